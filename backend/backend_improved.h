@@ -30,6 +30,21 @@ struct FFIList {
  */
 int init(uint8_t **whitelist_ptr, uintptr_t *whitelist_sizes_ptr, uintptr_t whitelist_size);
 
+/**
+ * Initializes the P2P network from a JSON or TOML config
+ *
+ * Every field is optional, missing ones fall back to the defaults used by init()
+ *
+ * @param config Config document (JSON if it starts with '{', TOML otherwise)
+ * @param config_size Size of the config
+ * @param whitelist_ptr Array of string pointers containing peer IDs
+ * @param whitelist_sizes_ptr Array of string sizes
+ * @param whitelist_size Number of strings in the array
+ * @return FFIList of error messages, empty if successful
+ */
+FFIList init_with_config(const uint8_t *config, uintptr_t config_size,
+                         uint8_t **whitelist_ptr, uintptr_t *whitelist_sizes_ptr, uintptr_t whitelist_size);
+
 /**
  * Starts the gossip event loop to process network events in the background
  */
@@ -43,21 +58,44 @@ void start_gossip_loop();
 FFIList collect_events();
 
 /**
- * Sends a ping to a specific peer
- * 
+ * Requests the round trip time to a specific peer
+ *
+ * The result comes back as a PingResult event with the next measurement
+ * (within network.ping_interval_secs, rtt_ms is null on timeout), together with
+ * the min/avg/jitter/loss stats also found in get_peer_details()
+ *
  * @param target Byte representation of the peer ID to ping
  * @param target_size Size of the peer ID
- * @return 1 if ping sent successfully, 0 on error
+ * @return 1 if the ping was requested, 0 on error or if we aren't connected to the peer
  */
 int ping(const uint8_t *target, uintptr_t target_size);
 
 /**
  * Gets a list of connected peers
- * 
+ *
+ * Only the ids, so they can be passed to ping() or new_wolf() as they are.
+ * RTT stats (last, min, avg, jitter, loss) are in the rtt field of get_peer_details().
+ *
  * @return FFIList containing peer ID strings
  */
 FFIList get_peers();
 
+/**
+ * Gets details about every peer we are or recently were connected to
+ *
+ * Disconnected peers are forgotten after three days, the least recently seen first
+ * if there are more than 256 of them.
+ *
+ * Each JSON object has: peer_id, role ("wolf"/"sheep"), connected, addresses, transports,
+ * connections (address, transport, link, direction), first_seen/last_seen (unix ms),
+ * rtt (last_ms, min_ms, avg_ms, jitter_ms, replies, timeouts, loss), agent_version,
+ * protocol_version, schema_version (null until identified), rooms and location
+ * (the last LocationShare beacon, null if the peer doesn't share its location)
+ *
+ * @return FFIList containing one JSON object per peer
+ */
+FFIList get_peer_details();
+
 /**
  * Sends a message to the network
  * 
@@ -70,6 +108,177 @@ FFIList get_peers();
 int broadcast_message(uint8_t *message, uintptr_t message_size, 
                      const uint8_t *tag, uintptr_t tag_size);
 
+/**
+ * Sends a message to the network, with optional fields
+ *
+ * Takes JSON like {"message": "Bridge is out", "tag": "high",
+ * "location": {"lat": 52.09, "lon": 5.12, "accuracy_m": 15, "altitude_m": 3, "source": "gps"},
+ * "expires_at": 1760000000000, "supersedes": "<id of an earlier message>", "reply_to": "<id of the parent>"}.
+ * Only message is required. source is one of "gps", "network", "manual", "unknown".
+ *
+ * expires_at is unix time in milliseconds, expired messages are dropped from queues
+ * and caches everywhere. A message can only supersede one by the same author, unless
+ * it's sent by a wolf. Superseded messages are reported with a Superseded event
+ * (msg_id, superseded_by) so the UI can grey them out.
+ *
+ * @param json Message as JSON
+ * @param json_size Size of the JSON
+ * @return FFIList with the new message's id, empty on error
+ */
+FFIList broadcast_message_ex(const uint8_t *json, uintptr_t json_size);
+
+/**
+ * Answers a message
+ *
+ * The reply is a normal broadcast with reply_to set to the parent's id (Message events
+ * carry it too). Anyone can reply, not just wolves. A wolf's reply keeps the parent's
+ * tag, everyone else's is Normal.
+ *
+ * @param msg_id Id of the message to answer
+ * @param msg_id_size Size of the id
+ * @param text Reply text
+ * @param text_size Size of the text
+ * @return FFIList with the reply's id, empty on error
+ */
+FFIList reply(const uint8_t *msg_id, uintptr_t msg_id_size, const uint8_t *text, uintptr_t text_size);
+
+/**
+ * Gets the whole thread a message is part of, from the first message on, oldest first
+ *
+ * Each JSON object has author, message (with id, reply_to, ...) and superseded_by.
+ * Only messages still in the cache (the last 1000, not expired or retracted) are included.
+ *
+ * @param msg_id Id of any message in the thread
+ * @param msg_id_size Size of the id
+ * @return FFIList containing one JSON object per message
+ */
+FFIList get_thread(const uint8_t *msg_id, uintptr_t msg_id_size);
+
+/**
+ * Gets messages from the backend's message store, oldest first
+ *
+ * Every broadcast, alert, voice message and file manifest we send or receive is
+ * stored in <storage_path>/messages.db, so this is the place to load history from
+ * (e.g. after a restart). Expired and retracted messages are deleted, retracted ones
+ * aren't taken back from peers that still have them.
+ * Messages missed while offline are fetched from peers on connect (history.sync_on_connect
+ * in the config), they come in as Message events followed by a HistorySynced event
+ * (one per page, a lot of missed messages are fetched in several).
+ * Each JSON object has id, room, author, tag, timestamp, outgoing, superseded_by
+ * and message (the InteractionMessage, as in Message events).
+ *
+ * @param room Room name, empty for all rooms
+ * @param room_size Size of the room name
+ * @param since Only messages with a timestamp after this (unix ms), 0 for all
+ * @param limit Maximum number of messages (at most 1000)
+ * @return FFIList containing one JSON object per message, empty on error
+ */
+FFIList get_messages(const uint8_t *room, uintptr_t room_size, uint64_t since, uint32_t limit);
+
+/**
+ * Full text search over the backend's message store, best matches first
+ *
+ * Looks through message text, CAP alert headlines/descriptions/areas and file names.
+ * Every word in the query has to match, as the start of a word ("ins" finds "insulin"),
+ * case and accents don't matter.
+ * Filters are a JSON object, every field is optional:
+ * {"room": "general", "author": "<peer id>", "tag": "critical", "from": <unix ms>,
+ *  "to": <unix ms>, "limit": 50}
+ * The tag is critical, high or normal in any case, anything else is an error.
+ * Each result has the same fields as get_messages() plus rank (lower is better) and
+ * snippet (the matching text, with matches between [ and ]).
+ *
+ * @param query Search words
+ * @param query_size Size of the query
+ * @param filters Filters as JSON, can be empty
+ * @param filters_size Size of the filters
+ * @return FFIList containing one JSON object per result, empty on error
+ */
+FFIList search_messages(const uint8_t *query, uintptr_t query_size, const uint8_t *filters, uintptr_t filters_size);
+
+/**
+ * Sends an alert that only shows up on nodes inside an area (wolves only)
+ *
+ * Same JSON as broadcast_message_ex() plus an area, either
+ * "area": {"circle": {"center": {"lat": 52.09, "lon": 5.12}, "radius_m": 500}} or
+ * "area": {"polygon": {"points": [{"lat": ..., "lon": ...}, ...]}} (3 to 256 points).
+ * Every node relays it, but it only becomes a Message event on nodes whose last
+ * update_location() is inside the area. Nodes without a location show it unless
+ * location.alerts_without_location is off in the config.
+ *
+ * @param json Alert as JSON
+ * @param json_size Size of the JSON
+ * @return FFIList with the alert's message id, empty on error
+ */
+FFIList broadcast_geo_alert(const uint8_t *json, uintptr_t json_size);
+
+/**
+ * Takes a message back everywhere in the mesh (wolves only)
+ *
+ * Every node drops the message from its queues and caches and emits a Retracted
+ * event (msg_id, reason, by) so the UI can remove it. Works for CAP alerts too,
+ * by their identifier.
+ *
+ * @param msg_id Id of the message to retract
+ * @param msg_id_size Size of the id
+ * @param reason Why, shown to users
+ * @param reason_size Size of the reason
+ * @return 1 if the retraction was sent, 0 on error
+ */
+int retract_message(const uint8_t *msg_id, uintptr_t msg_id_size, const uint8_t *reason, uintptr_t reason_size);
+
+/**
+ * Broadcasts a CAP 1.2 alert (wolves only)
+ *
+ * The XML is checked against the parts of the spec the backend keeps (identifier,
+ * sender, sent, status, msgType, scope, info blocks with urgency, severity, certainty,
+ * expiry and areas). Receivers get it as a Message event with a CapAlert inside,
+ * alerts that already expired are dropped.
+ *
+ * CAP severity maps onto message tags: Extreme = critical, Severe = high, anything else = normal
+ *
+ * @param xml CAP XML document
+ * @param xml_size Size of the XML
+ * @return 1 if the alert was sent, 0 on error
+ */
+int publish_cap_alert(const uint8_t *xml, uintptr_t xml_size);
+
+/**
+ * Exports an alert we sent or received as CAP 1.2 XML
+ *
+ * Works for CAP alerts (by their identifier) and geofenced alerts, whose identifier
+ * is "<peer id>.<timestamp>". Only the last 256 alerts are kept.
+ *
+ * @param identifier Alert identifier
+ * @param identifier_size Size of the identifier
+ * @return FFIList with the XML, empty if the alert is unknown
+ */
+FFIList export_cap_alert(const uint8_t *identifier, uintptr_t identifier_size);
+
+/**
+ * Updates our own position, same JSON as the location in broadcast_message_ex()
+ *
+ * Nothing is sent unless location sharing is enabled
+ *
+ * @param json Location as JSON
+ * @param json_size Size of the JSON
+ * @return 1 if the location is valid, 0 otherwise
+ */
+int update_location(const uint8_t *json, uintptr_t json_size);
+
+/**
+ * Turns the periodic LocationShare beacons on or off
+ *
+ * Coarse beacons only carry the center of a grid cell (location.grid_size_deg in the
+ * config, about a kilometer by default). Other peers' beacons show up as Message
+ * events and in the location field of get_peer_details().
+ *
+ * @param enabled 1 to share, 0 to stop
+ * @param precise 1 to share the exact position, 0 for the coarse one
+ * @return 1 on success, 0 on error
+ */
+int set_location_sharing(int enabled, int precise);
+
 /**
  * Promotes a peer to wolf status
  * 
@@ -79,6 +288,162 @@ int broadcast_message(uint8_t *message, uintptr_t message_size,
  */
 int new_wolf(const uint8_t *new_wolf_peer_id, uintptr_t new_wolf_peer_id_size);
 
+/**
+ * Dials a peer by multiaddr, e.g. "/ip4/10.0.0.2/udp/4001/quic-v1"
+ *
+ * The outcome is reported later as a DialSuccess or DialFailure event
+ *
+ * @param address Multiaddr string
+ * @param address_size Size of the multiaddr
+ * @return 1 if the dial was started, 0 on error
+ */
+int dial(const uint8_t *address, uintptr_t address_size);
+
+/**
+ * Shares a file (photo, PDF, ...) with everyone in the default room
+ *
+ * Only a manifest (name, size, mime, root hash) is broadcast, peers fetch the
+ * content in chunks with fetch_file(). The file is copied into the storage path.
+ *
+ * @param path Path of the file to share
+ * @param path_size Size of the path
+ * @param mime MIME type, e.g. "image/jpeg"
+ * @param mime_size Size of the MIME type
+ * @return FFIList with the file's root hash, empty on error
+ */
+FFIList share_file(const uint8_t *path, uintptr_t path_size, const uint8_t *mime, uintptr_t mime_size);
+
+/**
+ * Downloads a file announced by a FileManifest message
+ *
+ * Progress is reported through FileProgress events, followed by either a
+ * FileComplete event (with the path of the verified file) or a FileFailed event
+ *
+ * @param root_hash Root hash from the manifest
+ * @param root_hash_size Size of the root hash
+ * @return 1 if the download was started, 0 on error
+ */
+int fetch_file(const uint8_t *root_hash, uintptr_t root_hash_size);
+
+/**
+ * Sends a push-to-talk voice clip to the default room
+ *
+ * The clip is sent as is, encode it first (e.g. Opus, AAC or AMR). Small clips go inline,
+ * bigger ones through the chunked file transfer. Receivers get a VoiceMessage
+ * event with the path of the stored clip once it's complete.
+ *
+ * @param codec Codec name like "opus", 1 to 16 of a-z, 0-9 and -, anything else is refused
+ * @param codec_size Size of the codec name
+ * @param duration_ms Length of the clip in milliseconds
+ * @param data Encoded audio
+ * @param data_size Size of the encoded audio
+ * @return 1 on success, 0 on error
+ */
+int send_voice_message(const uint8_t *codec, uintptr_t codec_size, uint32_t duration_ms, const uint8_t *data, uintptr_t data_size);
+
+/**
+ * Gets diagnostics as a JSON object
+ *
+ * Has connected_peers and outbox, with critical/high/normal queues that each have
+ * depth (messages waiting), sent, shed (dropped under load) and retries (put back
+ * because there was nobody to send to yet).
+ *
+ * Outgoing messages are queued by tag: critical always goes out first and normal
+ * messages are dropped first when the network can't keep up (see outbox in the config).
+ *
+ * @return FFIList with one JSON object
+ */
+FFIList get_diagnostics();
+
+/**
+ * Adds an incident to the shared board (wolves only)
+ *
+ * The board is a list of incidents every wolf can edit, also while offline: edits
+ * are merged the same way everywhere once nodes reconnect (last edit of a field wins,
+ * an incident removed on one side and edited on the other stays removed unless it
+ * was added again). Everyone can read it, a BoardChanged event with the changed
+ * incident ids comes whenever it changes.
+ *
+ * @param json Fields as a JSON object of strings, e.g.
+ *             {"title": "Flooded basement", "assignee": "team 2"}.
+ *             status is open, assigned or resolved, open if left out.
+ * @param json_size Size of the JSON
+ * @return FFIList with the incident's id, empty on error
+ */
+FFIList board_add_incident(const uint8_t *json, uintptr_t json_size);
+
+/**
+ * Sets one field of an incident on the board (wolves only)
+ *
+ * @param incident Incident id
+ * @param incident_size Size of the id
+ * @param field Field name, e.g. "status"
+ * @param field_size Size of the field name
+ * @param value New value, for status one of open, assigned or resolved
+ * @param value_size Size of the value
+ * @return 1 on success, 0 on error
+ */
+int board_set_field(const uint8_t *incident, uintptr_t incident_size, const uint8_t *field, uintptr_t field_size, const uint8_t *value, uintptr_t value_size);
+
+/**
+ * Removes an incident from the board (wolves only)
+ *
+ * @param incident Incident id
+ * @param incident_size Size of the id
+ * @return 1 on success, 0 on error
+ */
+int board_remove_incident(const uint8_t *incident, uintptr_t incident_size);
+
+/**
+ * Gets the shared board, most recently changed incident first
+ *
+ * Each JSON object has id, updated_at (unix ms) and fields, e.g.
+ * "fields": {"status": {"value": "open", "author": "<peer id>", "timestamp": ...}}
+ *
+ * @return FFIList containing one JSON object per incident
+ */
+FFIList get_board();
+
+/**
+ * Starts a roll call, wolves only
+ *
+ * Everyone in the default room gets a RollCall message with the id and deadline
+ * and should answer with check_in(). Wolves get a CheckedIn event for every answer.
+ *
+ * @param duration_secs Time until the deadline, at most a day. Later check-ins are marked late.
+ * @return FFIList containing the roll call's id, empty on error
+ */
+FFIList start_roll_call(uint64_t duration_secs);
+
+/**
+ * Answers a roll call, signed with our key
+ *
+ * @param roll_call Pointer to the roll call id
+ * @param roll_call_size Length of the roll call id
+ * @param status Pointer to "ok", "need_help" or "injured"
+ * @param status_size Length of the status
+ * @param include_location Non-zero to send the last location passed to update_location (exact)
+ * @return 1 on success, 0 on error (e.g. a roll call we never heard of)
+ */
+int check_in(const uint8_t *roll_call, uintptr_t roll_call_size,
+             const uint8_t *status, uintptr_t status_size,
+             int32_t include_location);
+
+/**
+ * Gets the roster for a roll call, wolves only
+ *
+ * One JSON object with id, started_by, started_at, deadline (unix ms), open,
+ * responded (peer, status, location, checked_in_at, late) and unresponsive
+ * (peer, connected, last_seen, location, can_check_in), most recently seen first.
+ * Unresponsive is everyone we are or recently were connected to (see get_peer_details())
+ * that didn't answer.
+ *
+ * @param roll_call Pointer to the roll call id, empty for the latest roll call
+ * @param roll_call_size Length of the roll call id
+ * @return FFIList containing the roster, empty if there is no such roll call
+ */
+FFIList get_roster(const uint8_t *roll_call, uintptr_t roll_call_size);
+
 /**
  * Gets the local peer ID
  * 
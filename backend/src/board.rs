@@ -0,0 +1,485 @@
+use libp2p::{PeerId, identity::Keypair};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Display;
+
+use crate::gossip::{history::verify_signature, whitelist::Whitelist};
+
+// Shared situation board: a list of incidents with fields like title, status and assignee
+// that every wolf can edit, also while cut off from the rest of the mesh. Edits made on
+// both sides of a split have to end up the same everywhere once it heals, so the board is
+// a pair of CRDTs:
+// - which incidents exist is an OR-set: every add has a unique tag, a remove only removes
+//   the tags it had seen, so an add that happened concurrently survives
+// - every incident field is a last-writer-wins register, ties are broken by author so
+//   every node picks the same winner
+//
+// Edits go out as signed ops on the BOARD_ROOM topic. The board itself is nothing more than
+// the ops that still matter, so catching up after a reconnect is just fetching the ones we
+// don't have ("/truman/board/2.0.0", see gossip/replication.rs) and applying them, applying
+// twice changes nothing.
+// Every op carries its author's signature (see gossip/history.rs), so it doesn't matter who
+// passes it on, only wolves' ops are applied.
+
+pub const BOARD_ROOM: &str = "board";
+pub const BOARD_PROTOCOL: &str = "/truman/board/2.0.0";
+pub const STATUSES: [&str; 3] = ["open", "assigned", "resolved"];
+const MAX_ID_LEN: usize = 64;
+const MAX_FIELD_NAME_LEN: usize = 64;
+const MAX_VALUE_LEN: usize = 4096;
+
+#[derive(Debug)]
+pub enum BoardError {
+    Unauthorized,
+    BadSignature,
+    Invalid,
+    Signing,
+}
+
+impl Display for BoardError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BoardError::Unauthorized => write!(f, "Only wolves can edit the board"),
+            BoardError::BadSignature => write!(f, "Signature doesn't match the author"),
+            BoardError::Invalid => write!(f, "Invalid board edit"),
+            BoardError::Signing => write!(f, "Could not sign board edit"),
+        }
+    }
+}
+
+impl std::error::Error for BoardError {}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum BoardOp {
+    // `tag` is new for every add
+    Add { incident: String, tag: String },
+    // The add tags we had seen for the incident
+    Remove { incident: String, tags: Vec<String> },
+    Set { incident: String, field: String, value: String, timestamp: u64 },
+}
+
+impl BoardOp {
+    pub fn new_tag() -> String {
+        format!("{:016x}", rand::random::<u64>())
+    }
+    pub fn incident(&self) -> &str {
+        match self {
+            BoardOp::Add { incident, .. } | BoardOp::Remove { incident, .. } | BoardOp::Set { incident, .. } => incident,
+        }
+    }
+    pub fn is_valid(&self) -> bool {
+        let id_ok = |id: &str| !id.is_empty() && id.len() <= MAX_ID_LEN;
+        id_ok(self.incident())
+            && match self {
+                BoardOp::Add { tag, .. } => id_ok(tag),
+                BoardOp::Remove { tags, .. } => !tags.is_empty() && tags.iter().all(|tag| id_ok(tag)),
+                BoardOp::Set { field, value, .. } => {
+                    !field.is_empty()
+                        && field.len() <= MAX_FIELD_NAME_LEN
+                        && value.len() <= MAX_VALUE_LEN
+                        && (field != "status" || STATUSES.contains(&value.as_str()))
+                }
+            }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SignedOp {
+    pub author: PeerId,
+    pub op: BoardOp,
+    #[serde(with = "serde_bytes")]
+    pub signature: Vec<u8>,
+}
+
+fn signing_bytes(op: &BoardOp) -> Option<Vec<u8>> {
+    let mut bytes = b"truman-board".to_vec();
+    ciborium::into_writer(op, &mut bytes).ok()?;
+    Some(bytes)
+}
+
+impl SignedOp {
+    pub fn sign(keypair: &Keypair, op: BoardOp) -> Result<Self, BoardError> {
+        let bytes = signing_bytes(&op).ok_or(BoardError::Signing)?;
+        let signature = keypair.sign(&bytes).map_err(|_| BoardError::Signing)?;
+        Ok(Self { author: keypair.public().to_peer_id(), op, signature })
+    }
+    pub fn verify(&self) -> bool {
+        signing_bytes(&self.op).is_some_and(|bytes| verify_signature(&self.author, &bytes, &self.signature))
+    }
+    /// Short id of this exact op, ed25519 signatures are deterministic so the same op from the
+    /// same author always has the same one
+    pub fn digest(&self) -> String {
+        self.signature.iter().take(16).map(|b| format!("{:02x}", b)).collect()
+    }
+    /// Where the op is kept in the store, an op with the same key replaces it
+    pub fn key(&self) -> String {
+        match &self.op {
+            BoardOp::Add { tag, .. } => format!("add/{}", tag),
+            BoardOp::Remove { .. } => format!("remove/{}", self.digest()),
+            BoardOp::Set { incident, field, .. } => format!("set/{}/{}", incident, field),
+        }
+    }
+    /// Whether this Set wins over `other`, later first, then author and value so every node agrees
+    fn wins_over(&self, other: &SignedOp) -> bool {
+        match (&self.op, &other.op) {
+            (BoardOp::Set { value, timestamp, .. }, BoardOp::Set { value: other_value, timestamp: other_timestamp, .. }) => {
+                (timestamp, self.author.to_bytes(), value) > (other_timestamp, other.author.to_bytes(), other_value)
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FieldValue {
+    pub value: String,
+    pub author: PeerId,
+    pub timestamp: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Incident {
+    pub id: String,
+    pub fields: BTreeMap<String, FieldValue>,
+    // Newest field change
+    pub updated_at: u64,
+}
+
+#[derive(Default)]
+pub struct Board {
+    // By tag, only the ones that weren't removed
+    adds: HashMap<String, SignedOp>,
+    // By removed tag, kept so an add that shows up late stays removed
+    removed: HashMap<String, SignedOp>,
+    // By (incident, field), the winning Set
+    fields: HashMap<(String, String), SignedOp>,
+}
+
+impl Board {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ops that came out of our own store, those were checked when they came in
+    pub fn load(&mut self, ops: Vec<SignedOp>) {
+        for op in ops {
+            self.merge(op);
+        }
+    }
+
+    /// Checks and merges an op. Returns the incident if anything changed (and the op has to be kept).
+    pub fn apply(&mut self, op: SignedOp, whitelist: &Whitelist) -> Result<Option<String>, BoardError> {
+        if !whitelist.contains(&op.author) {
+            return Err(BoardError::Unauthorized);
+        }
+        if !op.op.is_valid() {
+            return Err(BoardError::Invalid);
+        }
+        if !op.verify() {
+            return Err(BoardError::BadSignature);
+        }
+        Ok(self.merge(op))
+    }
+
+    fn merge(&mut self, op: SignedOp) -> Option<String> {
+        let incident = op.op.incident().to_string();
+        let changed = match &op.op {
+            BoardOp::Add { tag, .. } => {
+                if self.removed.contains_key(tag) || self.adds.contains_key(tag) {
+                    false
+                } else {
+                    self.adds.insert(tag.clone(), op);
+                    true
+                }
+            }
+            BoardOp::Remove { tags, .. } => {
+                // A tombstone for an add we haven't seen yet counts as a change too, it has to be kept
+                let mut changed = false;
+                for tag in tags {
+                    self.adds.remove(tag);
+                    if !self.removed.contains_key(tag) {
+                        self.removed.insert(tag.clone(), op.clone());
+                        changed = true;
+                    }
+                }
+                changed
+            }
+            BoardOp::Set { field, .. } => {
+                let key = (incident.clone(), field.clone());
+                match self.fields.get(&key) {
+                    Some(current) if !op.wins_over(current) => false,
+                    _ => {
+                        self.fields.insert(key, op);
+                        true
+                    }
+                }
+            }
+        };
+        changed.then_some(incident)
+    }
+
+    pub fn contains(&self, incident: &str) -> bool {
+        self.adds.values().any(|add| add.op.incident() == incident)
+    }
+
+    /// The add tags a remove of the incident has to cover
+    pub fn tags(&self, incident: &str) -> Vec<String> {
+        self.adds
+            .iter()
+            .filter(|(_, add)| add.op.incident() == incident)
+            .map(|(tag, _)| tag.clone())
+            .collect()
+    }
+
+    /// Everything needed to rebuild the board, for anti-entropy
+    pub fn ops(&self) -> Vec<SignedOp> {
+        let mut seen_removes = HashSet::new();
+        let removes = self.removed.values().filter(|remove| seen_removes.insert(remove.signature.as_slice()));
+        self.adds.values().chain(removes).chain(self.fields.values()).cloned().collect()
+    }
+
+    /// One page of the ops the other side doesn't `have`, in digest order starting after
+    /// `after`. Also returns where the next page starts, None if this was the last one.
+    pub fn missing(&self, have: &HashSet<String>, after: Option<&str>, limit: usize) -> (Vec<SignedOp>, Option<String>) {
+        let mut missing: Vec<(String, SignedOp)> = self
+            .ops()
+            .into_iter()
+            .map(|op| (op.digest(), op))
+            .filter(|(digest, _)| !have.contains(digest) && after.is_none_or(|after| digest.as_str() > after))
+            .collect();
+        missing.sort_by(|a, b| a.0.cmp(&b.0));
+        let limit = limit.max(1);
+        let next = (missing.len() > limit).then(|| missing[limit - 1].0.clone());
+        missing.truncate(limit);
+        (missing.into_iter().map(|(_, op)| op).collect(), next)
+    }
+
+    /// Incidents on the board, most recently changed first
+    pub fn incidents(&self) -> Vec<Incident> {
+        let mut incidents: BTreeMap<&str, Incident> = BTreeMap::new();
+        for add in self.adds.values() {
+            let id = add.op.incident();
+            incidents.entry(id).or_insert_with(|| Incident { id: id.to_string(), fields: BTreeMap::new(), updated_at: 0 });
+        }
+        for ((id, field), set) in &self.fields {
+            let (Some(incident), BoardOp::Set { value, timestamp, .. }) = (incidents.get_mut(id.as_str()), &set.op) else {
+                continue;
+            };
+            incident.updated_at = incident.updated_at.max(*timestamp);
+            incident.fields.insert(
+                field.clone(),
+                FieldValue { value: value.clone(), author: set.author, timestamp: *timestamp },
+            );
+        }
+        let mut incidents: Vec<Incident> = incidents.into_values().collect();
+        incidents.sort_by_key(|incident| std::cmp::Reverse(incident.updated_at));
+        incidents
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wolves(count: usize) -> (Vec<Keypair>, Whitelist) {
+        let keys: Vec<Keypair> = (0..count).map(|_| Keypair::generate_ed25519()).collect();
+        let mut whitelist = Whitelist::default();
+        for key in &keys {
+            whitelist.add_peer(key.public().to_peer_id());
+        }
+        (keys, whitelist)
+    }
+
+    fn add(key: &Keypair, incident: &str, tag: &str) -> SignedOp {
+        SignedOp::sign(key, BoardOp::Add { incident: incident.to_string(), tag: tag.to_string() }).unwrap()
+    }
+
+    fn remove(key: &Keypair, incident: &str, tags: &[&str]) -> SignedOp {
+        let tags = tags.iter().map(|tag| tag.to_string()).collect();
+        SignedOp::sign(key, BoardOp::Remove { incident: incident.to_string(), tags }).unwrap()
+    }
+
+    fn set(key: &Keypair, incident: &str, field: &str, value: &str, timestamp: u64) -> SignedOp {
+        let op = BoardOp::Set { incident: incident.to_string(), field: field.to_string(), value: value.to_string(), timestamp };
+        SignedOp::sign(key, op).unwrap()
+    }
+
+    fn board_from(ops: &[SignedOp], whitelist: &Whitelist) -> Board {
+        let mut board = Board::new();
+        for op in ops {
+            board.apply(op.clone(), whitelist).unwrap();
+        }
+        board
+    }
+
+    // Incidents by id with their fields, Incident itself isn't comparable
+    type Snapshot = Vec<(String, Vec<(String, String, PeerId, u64)>)>;
+
+    fn snapshot(board: &Board) -> Snapshot {
+        let mut incidents: Vec<_> = board
+            .incidents()
+            .into_iter()
+            .map(|incident| {
+                let fields = incident
+                    .fields
+                    .into_iter()
+                    .map(|(field, value)| (field, value.value, value.author, value.timestamp))
+                    .collect();
+                (incident.id, fields)
+            })
+            .collect();
+        incidents.sort();
+        incidents
+    }
+
+    fn digests(board: &Board) -> Vec<String> {
+        let mut digests: Vec<String> = board.ops().iter().map(SignedOp::digest).collect();
+        digests.sort();
+        digests
+    }
+
+    fn history(keys: &[Keypair]) -> Vec<SignedOp> {
+        vec![
+            add(&keys[0], "fire", "a1"),
+            set(&keys[0], "fire", "title", "Fire at the mill", 10),
+            set(&keys[1], "fire", "status", "assigned", 20),
+            add(&keys[1], "flood", "b1"),
+            set(&keys[1], "flood", "title", "Flooded underpass", 15),
+            remove(&keys[0], "flood", &["b1"]),
+            set(&keys[2], "fire", "title", "Fire at the old mill", 30),
+            set(&keys[0], "fire", "status", "resolved", 20),
+            add(&keys[2], "fire", "c1"),
+        ]
+    }
+
+    #[test]
+    fn merge_is_commutative() {
+        let (keys, whitelist) = wolves(3);
+        let ops = history(&keys);
+        let expected = board_from(&ops, &whitelist);
+        assert_eq!(snapshot(&expected).len(), 1);
+
+        let reversed: Vec<SignedOp> = ops.iter().rev().cloned().collect();
+        let mut orders = vec![reversed];
+        for shift in 1..ops.len() {
+            let mut rotated = ops.clone();
+            rotated.rotate_left(shift);
+            orders.push(rotated);
+        }
+        for order in orders {
+            let board = board_from(&order, &whitelist);
+            assert_eq!(snapshot(&board), snapshot(&expected));
+            assert_eq!(digests(&board), digests(&expected));
+        }
+    }
+
+    #[test]
+    fn merge_is_idempotent() {
+        let (keys, whitelist) = wolves(3);
+        let ops = history(&keys);
+        let mut board = board_from(&ops, &whitelist);
+        let before = snapshot(&board);
+        for op in ops.iter().chain(board.ops().iter()) {
+            assert!(board.apply(op.clone(), &whitelist).unwrap().is_none());
+        }
+        assert_eq!(snapshot(&board), before);
+    }
+
+    #[test]
+    fn concurrent_add_and_remove_resolve_to_add() {
+        let (keys, whitelist) = wolves(2);
+        let first = add(&keys[0], "fire", "a1");
+        // One side removes the incident it knows, the other adds it again meanwhile
+        let removed = remove(&keys[1], "fire", &["a1"]);
+        let re_added = add(&keys[0], "fire", "a2");
+
+        for ops in [[first.clone(), removed.clone(), re_added.clone()], [re_added.clone(), removed.clone(), first.clone()]] {
+            let board = board_from(&ops, &whitelist);
+            assert!(board.contains("fire"));
+            assert_eq!(board.tags("fire"), vec!["a2".to_string()]);
+        }
+
+        // A remove that saw every add does remove it, also if the add shows up after it
+        let both = remove(&keys[1], "fire", &["a1", "a2"]);
+        let board = board_from(&[both, first, re_added], &whitelist);
+        assert!(!board.contains("fire"));
+    }
+
+    #[test]
+    fn lww_ties_break_the_same_everywhere() {
+        let (keys, whitelist) = wolves(2);
+        let created = add(&keys[0], "fire", "a1");
+        let mine = set(&keys[0], "fire", "title", "Mine", 10);
+        let theirs = set(&keys[1], "fire", "title", "Theirs", 10);
+        let one = board_from(&[created.clone(), mine.clone(), theirs.clone()], &whitelist);
+        let other = board_from(&[created.clone(), theirs.clone(), mine.clone()], &whitelist);
+        assert_eq!(snapshot(&one), snapshot(&other));
+        // Same time, so the author decides
+        let winner = if keys[0].public().to_peer_id().to_bytes() > keys[1].public().to_peer_id().to_bytes() { "Mine" } else { "Theirs" };
+        assert_eq!(snapshot(&one)[0].1[0].1, winner);
+
+        // Same time and author, the value decides
+        let a = set(&keys[0], "fire", "title", "A", 20);
+        let b = set(&keys[0], "fire", "title", "B", 20);
+        for ops in [[created.clone(), a.clone(), b.clone()], [created.clone(), b.clone(), a.clone()]] {
+            assert_eq!(snapshot(&board_from(&ops, &whitelist))[0].1[0].1, "B");
+        }
+
+        // And later always wins
+        let late = set(&keys[1], "fire", "title", "Late", 21);
+        for ops in [[created.clone(), late.clone(), b.clone()], [created, b, late]] {
+            assert_eq!(snapshot(&board_from(&ops, &whitelist))[0].1[0].1, "Late");
+        }
+    }
+
+    #[test]
+    fn bad_ops_are_rejected() {
+        let (keys, whitelist) = wolves(1);
+        let mut board = Board::new();
+
+        // Changed after signing
+        let mut tampered = set(&keys[0], "fire", "title", "Fire", 10);
+        if let BoardOp::Set { value, .. } = &mut tampered.op {
+            *value = "Nothing to see".to_string();
+        }
+        assert!(!tampered.verify());
+        assert!(matches!(board.apply(tampered, &whitelist), Err(BoardError::BadSignature)));
+
+        // Signed by someone else in the wolf's name
+        let sheep = Keypair::generate_ed25519();
+        let mut forged = add(&sheep, "fire", "a1");
+        forged.author = keys[0].public().to_peer_id();
+        assert!(matches!(board.apply(forged, &whitelist), Err(BoardError::BadSignature)));
+
+        // Properly signed, but not by a wolf
+        assert!(matches!(board.apply(add(&sheep, "fire", "a1"), &whitelist), Err(BoardError::Unauthorized)));
+
+        let invalid = set(&keys[0], "fire", "status", "on fire", 10);
+        assert!(matches!(board.apply(invalid, &whitelist), Err(BoardError::Invalid)));
+
+        assert!(board.ops().is_empty());
+    }
+
+    #[test]
+    fn missing_pages_cover_everything_once() {
+        let (keys, whitelist) = wolves(1);
+        let ops: Vec<SignedOp> = (0..10).map(|i| add(&keys[0], &format!("incident{}", i), &format!("tag{}", i))).collect();
+        let board = board_from(&ops, &whitelist);
+        let have: HashSet<String> = ops[..3].iter().map(SignedOp::digest).collect();
+
+        let mut fetched = Vec::new();
+        let mut after = None;
+        loop {
+            let (page, next) = board.missing(&have, after.as_deref(), 3);
+            assert!(page.len() <= 3);
+            fetched.extend(page.iter().map(SignedOp::digest));
+            match next {
+                Some(next) => after = Some(next),
+                None => break,
+            }
+        }
+        let mut expected: Vec<String> = ops[3..].iter().map(SignedOp::digest).collect();
+        expected.sort();
+        assert_eq!(fetched, expected);
+    }
+}
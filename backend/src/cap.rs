@@ -0,0 +1,657 @@
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt::{Display, Write};
+
+use crate::communication::{GeoAlert, Tag};
+use crate::geo::{Area, Point};
+
+// Common Alerting Protocol 1.2 (OASIS), what agencies already produce and consume.
+// Alerts are imported from CAP XML, sent as InteractionMessage::CapAlert (CBOR like
+// everything else) and can be turned back into CAP XML on the receiving side.
+// Only the parts of the spec people actually fill in are kept: no resources, parameters,
+// geocodes or event codes.
+// Spec: https://docs.oasis-open.org/emergency/cap/v1.2/CAP-v1.2-os.html
+
+pub const CAP_NAMESPACE: &str = "urn:oasis:names:tc:emergency:cap:1.2";
+// Received alerts kept around for exporting
+const MAX_STORED_ALERTS: usize = 256;
+
+#[derive(Debug)]
+pub enum CapError {
+    Xml(String),
+    NotCap,
+    Missing(&'static str),
+    Invalid { field: &'static str, value: String },
+}
+
+impl Display for CapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CapError::Xml(e) => write!(f, "Invalid XML: {}", e),
+            CapError::NotCap => write!(f, "Not a CAP 1.2 alert"),
+            CapError::Missing(field) => write!(f, "Missing {}", field),
+            CapError::Invalid { field, value } => write!(f, "Invalid {}: '{}'", field, value),
+        }
+    }
+}
+
+impl std::error::Error for CapError {}
+
+// CAP's code lists, with the exact spelling the spec uses
+macro_rules! cap_enum {
+    ($name:ident { $($variant:ident => $text:literal),* $(,)? }) => {
+        #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum $name {
+            $($variant),*
+        }
+
+        impl $name {
+            pub fn as_str(&self) -> &'static str {
+                match self {
+                    $($name::$variant => $text),*
+                }
+            }
+            fn parse(field: &'static str, value: &str) -> Result<Self, CapError> {
+                match value {
+                    $($text => Ok($name::$variant),)*
+                    _ => Err(CapError::Invalid { field, value: value.to_string() }),
+                }
+            }
+        }
+    };
+}
+
+cap_enum!(Status { Actual => "Actual", Exercise => "Exercise", System => "System", Test => "Test", Draft => "Draft" });
+cap_enum!(MsgType { Alert => "Alert", Update => "Update", Cancel => "Cancel", Ack => "Ack", Error => "Error" });
+cap_enum!(Scope { Public => "Public", Restricted => "Restricted", Private => "Private" });
+cap_enum!(Category {
+    Geo => "Geo", Met => "Met", Safety => "Safety", Security => "Security", Rescue => "Rescue",
+    Fire => "Fire", Health => "Health", Env => "Env", Transport => "Transport", Infra => "Infra",
+    Cbrne => "CBRNE", Other => "Other",
+});
+cap_enum!(Urgency { Immediate => "Immediate", Expected => "Expected", Future => "Future", Past => "Past", Unknown => "Unknown" });
+cap_enum!(Severity { Extreme => "Extreme", Severe => "Severe", Moderate => "Moderate", Minor => "Minor", Unknown => "Unknown" });
+cap_enum!(Certainty { Observed => "Observed", Likely => "Likely", Possible => "Possible", Unlikely => "Unlikely", Unknown => "Unknown" });
+
+impl From<Tag> for Severity {
+    fn from(tag: Tag) -> Self {
+        match tag {
+            Tag::Critical => Severity::Extreme,
+            Tag::High => Severity::Severe,
+            Tag::Normal => Severity::Moderate,
+        }
+    }
+}
+
+impl From<Severity> for Tag {
+    fn from(severity: Severity) -> Self {
+        match severity {
+            Severity::Extreme => Tag::Critical,
+            Severity::Severe => Tag::High,
+            Severity::Moderate | Severity::Minor | Severity::Unknown => Tag::Normal,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CapCircle {
+    pub center: Point,
+    // CAP uses kilometers here
+    pub radius_km: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CapArea {
+    pub area_desc: String,
+    // Closed rings, first point == last point
+    pub polygons: Vec<Vec<Point>>,
+    pub circles: Vec<CapCircle>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CapInfo {
+    pub language: String,
+    pub categories: Vec<Category>,
+    pub event: String,
+    pub urgency: Urgency,
+    pub severity: Severity,
+    pub certainty: Certainty,
+    // CAP date times, e.g. "2024-05-24T16:49:00-07:00"
+    pub effective: Option<String>,
+    pub expires: Option<String>,
+    pub sender_name: Option<String>,
+    pub headline: Option<String>,
+    pub description: Option<String>,
+    pub instruction: Option<String>,
+    pub areas: Vec<CapArea>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CapAlert {
+    pub identifier: String,
+    pub sender: String,
+    pub sent: String,
+    pub status: Status,
+    pub msg_type: MsgType,
+    pub scope: Scope,
+    pub restriction: Option<String>,
+    pub addresses: Option<String>,
+    pub note: Option<String>,
+    pub references: Option<String>,
+    pub info: Vec<CapInfo>,
+}
+
+impl CapAlert {
+    pub fn from_xml(xml: &str) -> Result<Self, CapError> {
+        let document = roxmltree::Document::parse(xml).map_err(|e| CapError::Xml(e.to_string()))?;
+        let root = document.root_element();
+        if root.tag_name().name() != "alert" || root.tag_name().namespace() != Some(CAP_NAMESPACE) {
+            return Err(CapError::NotCap);
+        }
+
+        let alert = Self {
+            identifier: required(root, "identifier")?,
+            sender: required(root, "sender")?,
+            sent: required(root, "sent")?,
+            status: Status::parse("status", &required(root, "status")?)?,
+            msg_type: MsgType::parse("msgType", &required(root, "msgType")?)?,
+            scope: Scope::parse("scope", &required(root, "scope")?)?,
+            restriction: optional(root, "restriction"),
+            addresses: optional(root, "addresses"),
+            note: optional(root, "note"),
+            references: optional(root, "references"),
+            info: children(root, "info").map(parse_info).collect::<Result<_, _>>()?,
+        };
+        alert.validate()?;
+        Ok(alert)
+    }
+
+    /// The things the schema insists on, also checked for alerts coming in over the network
+    pub fn validate(&self) -> Result<(), CapError> {
+        for (field, value) in [("identifier", &self.identifier), ("sender", &self.sender)] {
+            // No spaces, commas or restricted characters (&, <) allowed
+            if value.is_empty() || value.contains([' ', ',', '&', '<']) {
+                return Err(CapError::Invalid { field, value: value.clone() });
+            }
+        }
+        if parse_datetime(&self.sent).is_none() {
+            return Err(CapError::Invalid { field: "sent", value: self.sent.clone() });
+        }
+        if self.scope == Scope::Restricted && self.restriction.is_none() {
+            return Err(CapError::Missing("restriction"));
+        }
+        if self.scope == Scope::Private && self.addresses.is_none() {
+            return Err(CapError::Missing("addresses"));
+        }
+        for info in &self.info {
+            if info.categories.is_empty() {
+                return Err(CapError::Missing("category"));
+            }
+            for (field, value) in [("effective", &info.effective), ("expires", &info.expires)] {
+                if let Some(value) = value
+                    && parse_datetime(value).is_none()
+                {
+                    return Err(CapError::Invalid { field, value: value.clone() });
+                }
+            }
+            for area in &info.areas {
+                for polygon in &area.polygons {
+                    if polygon.len() < 4 || polygon.first() != polygon.last() {
+                        return Err(CapError::Invalid { field: "polygon", value: format!("{} points", polygon.len()) });
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Geofenced alerts are alerts too, so they can be handed to CAP consumers as well
+    pub fn from_geo_alert(alert: &GeoAlert, peer_id: &PeerId) -> Self {
+        let message = &alert.message;
+        let area = match &alert.area {
+            Area::Circle { center, radius_m } => CapArea {
+                area_desc: "Alert area".to_string(),
+                polygons: Vec::new(),
+                circles: vec![CapCircle { center: *center, radius_km: radius_m / 1000.0 }],
+            },
+            Area::Polygon { points } => {
+                let mut ring = points.clone();
+                if ring.first() != ring.last() {
+                    ring.push(ring[0]);
+                }
+                CapArea { area_desc: "Alert area".to_string(), polygons: vec![ring], circles: Vec::new() }
+            }
+        };
+        Self {
+            identifier: geo_alert_identifier(peer_id, message.timestamp),
+            sender: peer_id.to_string(),
+            sent: format_datetime(message.timestamp),
+            status: Status::Actual,
+            msg_type: MsgType::Alert,
+            scope: Scope::Public,
+            restriction: None,
+            addresses: None,
+            note: None,
+            references: None,
+            info: vec![CapInfo {
+                language: "en-US".to_string(),
+                categories: vec![Category::Other],
+                event: message.message.chars().take(64).collect(),
+                urgency: Urgency::Unknown,
+                severity: message.tags.into(),
+                certainty: Certainty::Unknown,
+                effective: None,
+                expires: None,
+                sender_name: None,
+                headline: None,
+                description: Some(message.message.clone()),
+                instruction: None,
+                areas: vec![area],
+            }],
+        }
+    }
+
+    /// The highest severity over all info blocks, for picking a Tag
+    pub fn severity(&self) -> Severity {
+        let rank = |s: &Severity| match s {
+            Severity::Extreme => 4,
+            Severity::Severe => 3,
+            Severity::Moderate => 2,
+            Severity::Minor => 1,
+            Severity::Unknown => 0,
+        };
+        self.info.iter().map(|i| i.severity).max_by_key(rank).unwrap_or(Severity::Unknown)
+    }
+
+    /// Expired once every info block with an expiry has passed it
+    pub fn is_expired(&self, now_ms: u64) -> bool {
+        let mut expiries = self.info.iter().map(|i| i.expires.as_deref().and_then(parse_datetime));
+        !self.info.is_empty() && expiries.all(|expires| expires.is_some_and(|e| e < now_ms))
+    }
+
+    pub fn to_xml(&self) -> String {
+        let mut xml = String::new();
+        xml.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        let _ = writeln!(xml, "<alert xmlns=\"{}\">", CAP_NAMESPACE);
+        element(&mut xml, 1, "identifier", &self.identifier);
+        element(&mut xml, 1, "sender", &self.sender);
+        element(&mut xml, 1, "sent", &self.sent);
+        element(&mut xml, 1, "status", self.status.as_str());
+        element(&mut xml, 1, "msgType", self.msg_type.as_str());
+        element(&mut xml, 1, "scope", self.scope.as_str());
+        optional_element(&mut xml, 1, "restriction", &self.restriction);
+        optional_element(&mut xml, 1, "addresses", &self.addresses);
+        optional_element(&mut xml, 1, "note", &self.note);
+        optional_element(&mut xml, 1, "references", &self.references);
+        for info in &self.info {
+            xml.push_str("  <info>\n");
+            element(&mut xml, 2, "language", &info.language);
+            for category in &info.categories {
+                element(&mut xml, 2, "category", category.as_str());
+            }
+            element(&mut xml, 2, "event", &info.event);
+            element(&mut xml, 2, "urgency", info.urgency.as_str());
+            element(&mut xml, 2, "severity", info.severity.as_str());
+            element(&mut xml, 2, "certainty", info.certainty.as_str());
+            optional_element(&mut xml, 2, "effective", &info.effective);
+            optional_element(&mut xml, 2, "expires", &info.expires);
+            optional_element(&mut xml, 2, "senderName", &info.sender_name);
+            optional_element(&mut xml, 2, "headline", &info.headline);
+            optional_element(&mut xml, 2, "description", &info.description);
+            optional_element(&mut xml, 2, "instruction", &info.instruction);
+            for area in &info.areas {
+                xml.push_str("    <area>\n");
+                element(&mut xml, 3, "areaDesc", &area.area_desc);
+                for polygon in &area.polygons {
+                    let points: Vec<String> = polygon.iter().map(|p| format!("{},{}", p.lat, p.lon)).collect();
+                    element(&mut xml, 3, "polygon", &points.join(" "));
+                }
+                for circle in &area.circles {
+                    let value = format!("{},{} {}", circle.center.lat, circle.center.lon, circle.radius_km);
+                    element(&mut xml, 3, "circle", &value);
+                }
+                xml.push_str("    </area>\n");
+            }
+            xml.push_str("  </info>\n");
+        }
+        xml.push_str("</alert>\n");
+        xml
+    }
+}
+
+/// Identifier geofenced alerts get when exported, "<peer id>.<timestamp>"
+pub fn geo_alert_identifier(peer_id: &PeerId, timestamp: u64) -> String {
+    format!("{}.{}", peer_id, timestamp)
+}
+
+/// The last few alerts we've seen or sent, by identifier
+#[derive(Default)]
+pub struct CapStore {
+    alerts: VecDeque<CapAlert>,
+}
+
+impl CapStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Seeing the same alert again just moves it to the back
+    pub fn insert(&mut self, alert: CapAlert) {
+        self.alerts.retain(|a| a.identifier != alert.identifier);
+        if self.alerts.len() >= MAX_STORED_ALERTS {
+            self.alerts.pop_front();
+        }
+        self.alerts.push_back(alert);
+    }
+    pub fn get(&self, identifier: &str) -> Option<&CapAlert> {
+        self.alerts.iter().find(|a| a.identifier == identifier)
+    }
+    pub fn remove(&mut self, identifier: &str) {
+        self.alerts.retain(|a| a.identifier != identifier);
+    }
+    pub fn prune(&mut self, now_ms: u64) {
+        self.alerts.retain(|a| !a.is_expired(now_ms));
+    }
+}
+
+fn parse_info(node: roxmltree::Node) -> Result<CapInfo, CapError> {
+    let categories = children(node, "category")
+        .map(|c| Category::parse("category", c.text().unwrap_or_default().trim()))
+        .collect::<Result<_, _>>()?;
+    Ok(CapInfo {
+        // en-US is what the spec says to assume
+        language: optional(node, "language").unwrap_or_else(|| "en-US".to_string()),
+        categories,
+        event: required(node, "event")?,
+        urgency: Urgency::parse("urgency", &required(node, "urgency")?)?,
+        severity: Severity::parse("severity", &required(node, "severity")?)?,
+        certainty: Certainty::parse("certainty", &required(node, "certainty")?)?,
+        effective: optional(node, "effective"),
+        expires: optional(node, "expires"),
+        sender_name: optional(node, "senderName"),
+        headline: optional(node, "headline"),
+        description: optional(node, "description"),
+        instruction: optional(node, "instruction"),
+        areas: children(node, "area").map(parse_area).collect::<Result<_, _>>()?,
+    })
+}
+
+fn parse_area(node: roxmltree::Node) -> Result<CapArea, CapError> {
+    let polygons = children(node, "polygon")
+        .map(|p| {
+            let text = p.text().unwrap_or_default();
+            text.split_whitespace().map(|pair| parse_point("polygon", pair)).collect()
+        })
+        .collect::<Result<_, _>>()?;
+    let circles = children(node, "circle")
+        .map(|c| {
+            let text = c.text().unwrap_or_default().trim();
+            let invalid = || CapError::Invalid { field: "circle", value: text.to_string() };
+            let (center, radius) = text.split_once(' ').ok_or_else(invalid)?;
+            Ok(CapCircle {
+                center: parse_point("circle", center)?,
+                radius_km: radius.trim().parse().map_err(|_| invalid())?,
+            })
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(CapArea {
+        area_desc: required(node, "areaDesc")?,
+        polygons,
+        circles,
+    })
+}
+
+fn parse_point(field: &'static str, pair: &str) -> Result<Point, CapError> {
+    let invalid = || CapError::Invalid { field, value: pair.to_string() };
+    let (lat, lon) = pair.split_once(',').ok_or_else(invalid)?;
+    let point = Point {
+        lat: lat.trim().parse().map_err(|_| invalid())?,
+        lon: lon.trim().parse().map_err(|_| invalid())?,
+    };
+    if !(-90.0..=90.0).contains(&point.lat) || !(-180.0..=180.0).contains(&point.lon) {
+        return Err(invalid());
+    }
+    Ok(point)
+}
+
+fn children<'a, 'input>(
+    node: roxmltree::Node<'a, 'input>,
+    name: &'static str,
+) -> impl Iterator<Item = roxmltree::Node<'a, 'input>> {
+    node.children().filter(move |c| c.is_element() && c.tag_name().name() == name)
+}
+
+fn optional(node: roxmltree::Node, name: &'static str) -> Option<String> {
+    let text = children(node, name).next()?.text()?.trim();
+    (!text.is_empty()).then(|| text.to_string())
+}
+
+fn required(node: roxmltree::Node, name: &'static str) -> Result<String, CapError> {
+    optional(node, name).ok_or(CapError::Missing(name))
+}
+
+fn element(xml: &mut String, depth: usize, name: &str, value: &str) {
+    let _ = writeln!(xml, "{}<{}>{}</{}>", "  ".repeat(depth), name, escape(value), name);
+}
+
+fn optional_element(xml: &mut String, depth: usize, name: &str, value: &Option<String>) {
+    if let Some(value) = value {
+        element(xml, depth, name, value);
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// CAP date time ("2024-05-24T16:49:00-07:00", always with an offset) to unix milliseconds
+pub fn parse_datetime(value: &str) -> Option<u64> {
+    // CAP's own examples don't have fractional seconds, but XML dateTime allows them and
+    // some feeds send them. Only milliseconds fit in the result.
+    let mut millis = 0;
+    let value = match value.get(19..20) {
+        Some(".") => {
+            let digits = value[20..].bytes().take_while(u8::is_ascii_digit).count();
+            if digits == 0 {
+                return None;
+            }
+            millis = format!("{:0<3}", &value[20..20 + digits.min(3)]).parse::<u64>().ok()?;
+            format!("{}{}", &value[..19], &value[20 + digits..])
+        }
+        _ => value.to_string(),
+    };
+    let bytes = value.as_bytes();
+    if bytes.len() != 25 || bytes[4] != b'-' || bytes[7] != b'-' || bytes[10] != b'T' || bytes[22] != b':' {
+        return None;
+    }
+    let number = |range: std::ops::Range<usize>| value.get(range)?.parse::<i64>().ok();
+    let (year, month, day) = (number(0..4)?, number(5..7)?, number(8..10)?);
+    let (hour, minute, second) = (number(11..13)?, number(14..16)?, number(17..19)?);
+    let offset = number(20..22)? * 3600 + number(23..25)? * 60;
+    let offset = match bytes[19] {
+        b'+' => offset,
+        b'-' => -offset,
+        _ => return None,
+    };
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    // Days since 1970-01-01, Howard Hinnant's days_from_civil
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let year_of_era = y - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+
+    let seconds = days * 86_400 + hour * 3600 + minute * 60 + second - offset;
+    u64::try_from(seconds).ok().map(|s| s * 1000 + millis)
+}
+
+/// Unix milliseconds to a CAP date time in UTC, which CAP wants written as "-00:00"
+pub fn format_datetime(unix_ms: u64) -> String {
+    let seconds = (unix_ms / 1000) as i64;
+    let (days, time) = (seconds.div_euclid(86_400), seconds.rem_euclid(86_400));
+
+    // civil_from_days, the inverse of the above
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}-00:00",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alert_xml(root: &str, body: &str) -> String {
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<{root}>
+  <identifier>KSTO1055887203</identifier>
+  <sender>KSTO@NWS.NOAA.GOV</sender>
+  <sent>2024-05-24T16:49:00-07:00</sent>
+  <status>Actual</status>
+  <msgType>Alert</msgType>
+  <scope>Public</scope>
+  {body}
+</alert>"#
+        )
+    }
+
+    const INFO: &str = r#"<info>
+    <category>Met</category>
+    <event>SEVERE THUNDERSTORM</event>
+    <urgency>Immediate</urgency>
+    <severity>Severe</severity>
+    <certainty>Observed</certainty>
+    <expires>2024-05-24T17:30:00-07:00</expires>
+    <area>
+      <areaDesc>EXTREME NORTH CENTRAL TUOLUMNE COUNTY</areaDesc>
+      <polygon>38.47,-120.14 38.34,-119.95 38.52,-119.74 38.62,-119.89 38.47,-120.14</polygon>
+    </area>
+  </info>"#;
+
+    #[test]
+    fn parse_datetime_applies_the_offset() {
+        let utc = parse_datetime("2024-05-24T23:49:00-00:00");
+        assert_eq!(utc, Some(1_716_594_540_000));
+        assert_eq!(parse_datetime("2024-05-24T23:49:00+00:00"), utc);
+        assert_eq!(parse_datetime("2024-05-24T16:49:00-07:00"), utc);
+        assert_eq!(parse_datetime("2024-05-25T05:19:00+05:30"), utc);
+        // Leap day, and the offset moving it across midnight
+        assert_eq!(parse_datetime("2024-02-29T12:00:00-00:00"), Some(1_709_208_000_000));
+        assert_eq!(parse_datetime("2024-03-01T01:00:00+13:00"), Some(1_709_208_000_000));
+    }
+
+    #[test]
+    fn parse_datetime_keeps_milliseconds() {
+        assert_eq!(parse_datetime("2024-05-24T16:49:00.5-07:00"), Some(1_716_594_540_500));
+        assert_eq!(parse_datetime("2024-05-24T16:49:00.123-07:00"), Some(1_716_594_540_123));
+        // Anything past milliseconds is dropped
+        assert_eq!(parse_datetime("2024-05-24T16:49:00.123999-07:00"), Some(1_716_594_540_123));
+        assert_eq!(parse_datetime("2024-05-24T16:49:00.-07:00"), None);
+    }
+
+    #[test]
+    fn parse_datetime_rejects_malformed_dates() {
+        for value in [
+            "",
+            "2024-05-24",
+            "2024-05-24T16:49:00",
+            // CAP doesn't allow Z
+            "2024-05-24T16:49:00Z",
+            "2024-05-24 16:49:00-07:00",
+            "2024-13-24T16:49:00-07:00",
+            "2024-05-32T16:49:00-07:00",
+            "2024-05-24T24:49:00-07:00",
+            "2024-05-24T16:60:00-07:00",
+            "2024-05-24T16:49:00*07:00",
+            "2024-05-24T16:49:00-0700",
+            "2O24-05-24T16:49:00-07:00",
+            // Before 1970
+            "1969-12-31T23:59:59-00:00",
+        ] {
+            assert_eq!(parse_datetime(value), None, "{}", value);
+        }
+    }
+
+    #[test]
+    fn format_datetime_round_trips() {
+        let unix_ms = 1_716_594_540_000;
+        assert_eq!(format_datetime(unix_ms), "2024-05-24T23:49:00-00:00");
+        assert_eq!(parse_datetime(&format_datetime(unix_ms)), Some(unix_ms));
+    }
+
+    #[test]
+    fn from_xml_parses_an_alert() {
+        let xml = alert_xml(&format!(r#"alert xmlns="{}""#, CAP_NAMESPACE), INFO);
+        let alert = CapAlert::from_xml(&xml).unwrap();
+        assert_eq!(alert.identifier, "KSTO1055887203");
+        assert_eq!(alert.msg_type, MsgType::Alert);
+        assert_eq!(alert.info.len(), 1);
+        assert_eq!(alert.info[0].categories, vec![Category::Met]);
+        assert_eq!(alert.info[0].language, "en-US");
+        assert_eq!(alert.info[0].areas[0].polygons[0].len(), 5);
+        assert_eq!(alert.severity(), Severity::Severe);
+        assert!(!alert.is_expired(parse_datetime("2024-05-24T17:00:00-07:00").unwrap()));
+        assert!(alert.is_expired(parse_datetime("2024-05-24T18:00:00-07:00").unwrap()));
+    }
+
+    #[test]
+    fn from_xml_accepts_an_alert_without_info() {
+        // Allowed by the spec, e.g. for Cancel and Ack
+        let xml = alert_xml(&format!(r#"alert xmlns="{}""#, CAP_NAMESPACE), "");
+        let alert = CapAlert::from_xml(&xml).unwrap();
+        assert!(alert.info.is_empty());
+        assert_eq!(alert.severity(), Severity::Unknown);
+        // Nothing says when it's over
+        assert!(!alert.is_expired(u64::MAX));
+    }
+
+    #[test]
+    fn from_xml_rejects_other_documents() {
+        // Right name, wrong namespace (CAP 1.1)
+        let xml = alert_xml(r#"alert xmlns="urn:oasis:names:tc:emergency:cap:1.1""#, INFO);
+        assert!(matches!(CapAlert::from_xml(&xml), Err(CapError::NotCap)));
+        // No namespace at all
+        let xml = alert_xml("alert", INFO);
+        assert!(matches!(CapAlert::from_xml(&xml), Err(CapError::NotCap)));
+        // Not an alert
+        let xml = format!(r#"<feed xmlns="{}"><alert/></feed>"#, CAP_NAMESPACE);
+        assert!(matches!(CapAlert::from_xml(&xml), Err(CapError::NotCap)));
+        assert!(matches!(CapAlert::from_xml("<alert"), Err(CapError::Xml(_))));
+    }
+
+    #[test]
+    fn from_xml_rejects_bad_fields() {
+        let namespace = format!(r#"alert xmlns="{}""#, CAP_NAMESPACE);
+        let xml = alert_xml(&namespace, INFO).replace("2024-05-24T16:49:00-07:00", "yesterday");
+        assert!(matches!(CapAlert::from_xml(&xml), Err(CapError::Invalid { field: "sent", .. })));
+        let xml = alert_xml(&namespace, INFO).replace("<category>Met</category>", "");
+        assert!(matches!(CapAlert::from_xml(&xml), Err(CapError::Missing("category"))));
+        let xml = alert_xml(&namespace, INFO).replace("<event>SEVERE THUNDERSTORM</event>", "");
+        assert!(matches!(CapAlert::from_xml(&xml), Err(CapError::Missing("event"))));
+    }
+}
@@ -1,7 +1,11 @@
-use crate::gossip::{message::MessageData, room::Room, whitelist::Whitelist};
+use crate::board::SignedOp;
+use crate::cap::CapAlert;
+use crate::geo::{Area, Location};
+use crate::rollcall::{RollCall, SignedCheckIn};
+use crate::gossip::{peers::now_ms, message::MessageData, room::Room, transfer::FileManifest, whitelist::Whitelist};
 use libp2p::PeerId;
 use serde::{Deserialize, Serialize};
-use serde_json::Error as SerdeError;
+use std::str::FromStr;
 
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -15,7 +19,7 @@ pub struct WolfVerify {
     pub old_wolf_private_key: String,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Tag {
     Critical,
     High,
@@ -32,11 +36,107 @@ fn from(tag: String) -> Self {
     }
 }
 
+// Strict, for places where a typo shouldn't quietly mean Normal
+impl FromStr for Tag {
+    type Err = String;
+
+    fn from_str(tag: &str) -> Result<Self, Self::Err> {
+        match tag.to_ascii_lowercase().as_str() {
+            "critical" => Ok(Tag::Critical),
+            "high" => Ok(Tag::High),
+            "normal" => Ok(Tag::Normal),
+            _ => Err(format!("Unknown tag {:?}, use critical, high or normal", tag)),
+        }
+    }
+}
+
+// Ids are random hex, anything much longer is someone messing around
+const MAX_MESSAGE_ID_LEN: usize = 64;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Message {
     pub message: String,
     pub tags: Tag,
     pub timestamp: u64,
+    // Where the report is about, older builds don't send it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<Location>,
+    // Empty for messages from older builds, those can't be superseded
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub id: String,
+    // Unix time in milliseconds, after which the message is dropped everywhere
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    // Id of an earlier message this one replaces, e.g. a changed shelter location.
+    // Only the author of that message or a wolf may do this.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub supersedes: Option<String>,
+    // Id of the message this answers, for grouping threads
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<String>,
+}
+
+impl Message {
+    pub fn new_id() -> String {
+        format!("{:016x}", rand::random::<u64>())
+    }
+    pub fn is_expired(&self, now_ms: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now_ms)
+    }
+    pub fn is_valid(&self) -> bool {
+        self.location.as_ref().is_none_or(|l| l.is_valid())
+            && self.id.len() <= MAX_MESSAGE_ID_LEN
+            && [&self.supersedes, &self.reply_to]
+                .iter()
+                .all(|id| id.as_ref().is_none_or(|id| !id.is_empty() && id.len() <= MAX_MESSAGE_ID_LEN))
+    }
+}
+
+// Periodic position beacon, only sent by peers that opted in
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LocationShare {
+    pub location: Location,
+    pub timestamp: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum VoiceClip {
+    Inline(#[serde(with = "serde_bytes")] Vec<u8>),
+    // Too big to go inline, fetched through the chunk protocol
+    File(FileManifest),
+}
+
+// Clips end up on disk with the codec as extension and in the mime type, so keep it to
+// something that can't be a path or break either of those. Which codecs exist is up to the host apps.
+pub const MAX_CODEC_LEN: usize = 16;
+
+pub fn is_valid_codec(codec: &str) -> bool {
+    (1..=MAX_CODEC_LEN).contains(&codec.len())
+        && codec.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VoiceMessage {
+    // What the host app encoded with, see is_valid_codec
+    pub codec: String,
+    pub duration_ms: u32,
+    pub timestamp: u64,
+    pub clip: VoiceClip,
+}
+
+// Takes a broadcast back everywhere, e.g. wrong coordinates. Wolves only, checked against
+// the gossipsub source (signed by the author), whoever relayed it doesn't matter.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Retract {
+    pub msg_id: String,
+    pub reason: String,
+}
+
+// Relayed by everyone, but only shown on nodes inside the area
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GeoAlert {
+    pub message: Message,
+    pub area: Area,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -48,34 +148,68 @@ pub enum InteractionMessage {
     NewWolf(NewWolf), // Public
     WolfVerify(WolfVerify), // Public
     Message(Message), // Public
+    FileManifest(FileManifest), // Public (wolves) & Private
+    VoiceMessage(VoiceMessage), // Public & Private
+    LocationShare(LocationShare), // Public & Private
+    GeoAlert(GeoAlert), // Public
+    CapAlert(CapAlert), // Public
+    Retract(Retract), // Public
+    BoardOps(Vec<SignedOp>), // Board
+    RollCall(RollCall), // Public
+    CheckIn(SignedCheckIn), // Public
     Other,
 }
 
 #[derive(Debug)]
 pub enum GetDataViaMessageError {
-    NotOurChannel,
     Unauthorized,
-    Serde(SerdeError),
-}
-impl From<serde_json::Error> for GetDataViaMessageError {
-    fn from(err: serde_json::Error) -> Self {
-        GetDataViaMessageError::Serde(err)
-    }
+    // Well formed but nonsense, e.g. a latitude of 500
+    Invalid,
 }
 
 impl InteractionMessage {
+    /// The broadcast inside, if there is one
+    pub fn message(&self) -> Option<&Message> {
+        match self {
+            Self::Message(message) => Some(message),
+            Self::GeoAlert(alert) => Some(&alert.message),
+            _ => None,
+        }
+    }
+    pub fn is_expired(&self, now_ms: u64) -> bool {
+        match self {
+            Self::CapAlert(alert) => alert.is_expired(now_ms),
+            // Check-ins still count after the deadline, the roll call itself doesn't
+            Self::RollCall(call) => call.deadline < now_ms,
+            _ => self.message().is_some_and(|message| message.is_expired(now_ms)),
+        }
+    }
+    /// Which outbox queue the message goes in, see gossip/outbox.rs
+    pub fn priority(&self) -> Tag {
+        match self {
+            Self::Message(message) => message.tags,
+            Self::GeoAlert(alert) => alert.message.tags,
+            Self::CapAlert(alert) => alert.severity().into(),
+            // Wolf changes decide who is allowed to send alerts, don't let chat hold them up
+            Self::NewWolf(_) | Self::WolfVerify(_) | Self::Retract(_) => Tag::High,
+            // Few and far between, and a shed edit only comes back with the next sync
+            Self::BoardOps(_) => Tag::High,
+            // Someone might be waiting for help
+            Self::RollCall(_) | Self::CheckIn(_) => Tag::High,
+            _ => Tag::Normal,
+        }
+    }
     pub fn from_msg(
         whitelist: &Whitelist,
         message_data: &MessageData,
+        message: Self,
     ) -> Result<Self, GetDataViaMessageError> {
-        match (
-            &message_data.room,
-            serde_json::from_str(&message_data.message)?,
-        ) {
+        // Decoding happens in wire.rs, this only checks who is allowed to send what where
+        match (&message_data.room, message) {
             (_, Self::Ping(x)) => Ok(Self::Ping(x)),
             (Room::DirectMessage(_), Self::Name) => Ok(Self::Name),
             (Room::PublicRoom(_), Self::NewWolf(new_wolf)) => {
-                if !whitelist.contains(&message_data.peer) {
+                if !whitelist.contains(&message_data.source) {
                     return Err(GetDataViaMessageError::Unauthorized);
                 }
 
@@ -83,20 +217,159 @@ pub fn from_msg(
             }
             (Room::PublicRoom(_), Self::WolfVerify(wolf_verify)) => {
                 // Why would an active wolf node send a wolf verify message?
-                if whitelist.contains(&message_data.peer) {
+                if whitelist.contains(&message_data.source) {
                     return Err(GetDataViaMessageError::Unauthorized);
                 }
 
                 Ok(Self::WolfVerify(wolf_verify))
             }
             (Room::PublicRoom(_), Self::Message(message)) => {
-                if !whitelist.contains(&message_data.peer) {
+                // Anyone can answer a message, but only wolves can raise the alarm
+                let is_reply = message.reply_to.is_some() && message.tags == Tag::Normal;
+                if !is_reply && !whitelist.contains(&message_data.source) {
                     return Err(GetDataViaMessageError::Unauthorized);
                 }
+                if !message.is_valid() {
+                    return Err(GetDataViaMessageError::Invalid);
+                }
 
                 Ok(Self::Message(message))
             }
+            (Room::PublicRoom(_), Self::FileManifest(manifest)) => {
+                if !whitelist.contains(&message_data.source) {
+                    return Err(GetDataViaMessageError::Unauthorized);
+                }
+
+                Ok(Self::FileManifest(manifest))
+            }
+            (Room::DirectMessage(_), Self::FileManifest(manifest)) => Ok(Self::FileManifest(manifest)),
+            // Anyone can talk, that's the whole point. How much of it we keep is limited in accept().
+            (_, Self::VoiceMessage(voice)) => {
+                if !is_valid_codec(&voice.codec) {
+                    return Err(GetDataViaMessageError::Invalid);
+                }
+
+                Ok(Self::VoiceMessage(voice))
+            }
+            (Room::PublicRoom(_), Self::GeoAlert(alert)) => {
+                if !whitelist.contains(&message_data.source) {
+                    return Err(GetDataViaMessageError::Unauthorized);
+                }
+                if !alert.area.is_valid() || !alert.message.is_valid() {
+                    return Err(GetDataViaMessageError::Invalid);
+                }
+
+                Ok(Self::GeoAlert(alert))
+            }
+            (Room::PublicRoom(_), Self::CapAlert(alert)) => {
+                if !whitelist.contains(&message_data.source) {
+                    return Err(GetDataViaMessageError::Unauthorized);
+                }
+                // Expired ones are dropped too, nobody needs last week's flood warning
+                if alert.validate().is_err() || alert.is_expired(now_ms()) {
+                    return Err(GetDataViaMessageError::Invalid);
+                }
+
+                Ok(Self::CapAlert(alert))
+            }
+            (Room::PublicRoom(_), Self::Retract(retract)) => {
+                if !whitelist.contains(&message_data.source) {
+                    return Err(GetDataViaMessageError::Unauthorized);
+                }
+                // Could be a CAP identifier, those are a lot longer than our ids
+                if retract.msg_id.is_empty() || retract.msg_id.len() > 256 {
+                    return Err(GetDataViaMessageError::Invalid);
+                }
+
+                Ok(Self::Retract(retract))
+            }
+            (Room::PublicRoom(_), Self::BoardOps(ops)) => {
+                // Every op carries its author's signature, who passed it on doesn't matter (see board.rs)
+                if ops.iter().any(|op| !whitelist.contains(&op.author) || !op.verify()) {
+                    return Err(GetDataViaMessageError::Unauthorized);
+                }
+                if ops.is_empty() || !ops.iter().all(|op| op.op.is_valid()) {
+                    return Err(GetDataViaMessageError::Invalid);
+                }
+
+                Ok(Self::BoardOps(ops))
+            }
+            (Room::PublicRoom(_), Self::RollCall(call)) => {
+                if !whitelist.contains(&message_data.source) {
+                    return Err(GetDataViaMessageError::Unauthorized);
+                }
+                if !call.is_valid(now_ms()) {
+                    return Err(GetDataViaMessageError::Invalid);
+                }
+
+                Ok(Self::RollCall(call))
+            }
+            (Room::PublicRoom(_), Self::CheckIn(check_in)) => {
+                // Anyone can answer, but only for themselves. Who passed it on doesn't matter, the signature says who wrote it.
+                if !check_in.verify() {
+                    return Err(GetDataViaMessageError::Unauthorized);
+                }
+                if !check_in.check_in.is_valid() {
+                    return Err(GetDataViaMessageError::Invalid);
+                }
+
+                Ok(Self::CheckIn(check_in))
+            }
+            (_, Self::LocationShare(share)) => {
+                if !share.location.is_valid() {
+                    return Err(GetDataViaMessageError::Invalid);
+                }
+
+                Ok(Self::LocationShare(share))
+            }
             (_, _) => Ok(Self::Other),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(tags: Tag, reply_to: Option<&str>) -> InteractionMessage {
+        InteractionMessage::Message(Message {
+            message: "Water at the school".to_string(),
+            tags,
+            timestamp: 1_716_594_540_000,
+            location: None,
+            id: Message::new_id(),
+            expires_at: None,
+            supersedes: None,
+            reply_to: reply_to.map(str::to_string),
+        })
+    }
+
+    fn received(
+        whitelist: &Whitelist,
+        source: PeerId,
+        message: InteractionMessage,
+    ) -> Result<InteractionMessage, GetDataViaMessageError> {
+        let data = MessageData { peer: PeerId::random(), source, room: Room::PublicRoom("public".to_string()) };
+        InteractionMessage::from_msg(whitelist, &data, message)
+    }
+
+    #[test]
+    fn anyone_can_reply_but_only_wolves_broadcast() {
+        let wolf = PeerId::random();
+        let sheep = PeerId::random();
+        let mut whitelist = Whitelist::default();
+        whitelist.add_peer(wolf);
+
+        assert!(received(&whitelist, wolf, message(Tag::Critical, None)).is_ok());
+        assert!(received(&whitelist, wolf, message(Tag::High, Some("0123456789abcdef"))).is_ok());
+        assert!(received(&whitelist, sheep, message(Tag::Normal, Some("0123456789abcdef"))).is_ok());
+
+        assert!(matches!(received(&whitelist, sheep, message(Tag::Normal, None)), Err(GetDataViaMessageError::Unauthorized)));
+        // A reply can't be used to sneak in an alert
+        assert!(matches!(
+            received(&whitelist, sheep, message(Tag::Critical, Some("0123456789abcdef"))),
+            Err(GetDataViaMessageError::Unauthorized)
+        ));
+        assert!(matches!(received(&whitelist, sheep, message(Tag::Normal, Some(""))), Err(GetDataViaMessageError::Invalid)));
+    }
+}
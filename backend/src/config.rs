@@ -0,0 +1,566 @@
+use libp2p::{Multiaddr, gossipsub, multiaddr::Protocol};
+use crate::geo::Precision;
+use crate::store::MAX_QUERY_LIMIT;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::Display,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+// Everything in here has a default, so the host app only has to send the bits it cares about.
+// e.g. `{ "network": { "tcp_port": 4001 } }` is a perfectly valid config.
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub network: NetworkConfig,
+    pub gossip: GossipConfig,
+    pub mdns: MdnsConfig,
+    pub features: FeatureToggles,
+    pub transfer: TransferConfig,
+    pub location: LocationConfig,
+    pub outbox: OutboxConfig,
+    pub history: HistoryConfig,
+    // Where the backend is allowed to keep its files
+    pub storage_path: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NetworkConfig {
+    pub listen_ip: String,
+    // 0 means "whatever the OS gives us"
+    pub tcp_port: u16,
+    pub quic_port: u16,
+    pub transports: TransportConfig,
+    pub bootstrap_peers: Vec<String>,
+    // Relay servers (with their /p2p/<peer id>) we ask for a reservation, so peers can reach us through them
+    pub relays: Vec<String>,
+    // Round trip times are measured continuously on every connection
+    pub ping_interval_secs: u64,
+    pub ping_timeout_secs: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TransportConfig {
+    pub tcp: bool,
+    pub quic: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GossipConfig {
+    pub default_room: String,
+    pub heartbeat_interval_ms: u64,
+    pub validation_mode: ValidationMode,
+    pub max_transmit_size: usize,
+    // Bodies bigger than this get compressed (if everyone in the room can handle it)
+    pub compression_threshold: usize,
+    // Anything that inflates to more than this is dropped, so a tiny message can't eat all our memory
+    pub max_decompressed_size: usize,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ValidationMode {
+    Strict,
+    Permissive,
+    Anonymous,
+    None,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MdnsConfig {
+    pub ttl_secs: u64,
+    pub query_interval_secs: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TransferConfig {
+    // Downloads are put together in memory, so keep this reasonable
+    pub max_file_size: u64,
+    // Chunk requests in flight per download
+    pub max_parallel_requests: usize,
+    // Voice clips up to this size go inline in the message instead of through the chunk protocol
+    pub inline_voice_max_size: usize,
+    // Received voice clips stored per voice_window_secs, per author and from everyone together
+    pub max_voice_clips_per_peer: usize,
+    pub max_voice_clips: usize,
+    pub voice_window_secs: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LocationConfig {
+    // Off unless the user opted in, can be changed at runtime
+    pub share: bool,
+    pub precision: Precision,
+    // Size of the grid cells coarse locations are snapped to, 0.01 is about a kilometer
+    pub grid_size_deg: f64,
+    pub beacon_interval_secs: u64,
+    // Whether geofenced alerts are shown while we don't know where we are
+    pub alerts_without_location: bool,
+}
+
+// Outgoing messages per priority, see gossip/outbox.rs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OutboxConfig {
+    // Messages per second
+    pub critical_per_sec: u32,
+    pub high_per_sec: u32,
+    pub normal_per_sec: u32,
+    // Critical and High are only dropped when their queue is this full, or after waiting this long
+    pub max_queue_depth: usize,
+    pub max_age_secs: u64,
+    // Normal is shed a lot sooner, by count and by age
+    pub normal_queue_depth: usize,
+    pub normal_max_age_secs: u64,
+}
+
+// Catching up on what we missed while offline, see gossip/history.rs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HistoryConfig {
+    pub sync_on_connect: bool,
+    // Per request, both the ones we send and the ones we answer
+    pub max_messages: u32,
+    // How far back we ask for a room we have nothing from yet
+    pub max_age_secs: u64,
+    // We ask from a bit before we last got something, our clock and theirs won't quite match
+    pub overlap_secs: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FeatureToggles {
+    pub mdns: bool,
+    pub kademlia: bool,
+    // Only for well connected nodes, relaying other people's traffic isn't free
+    pub relay_server: bool,
+    pub relay_client: bool,
+    pub hole_punching: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            network: NetworkConfig::default(),
+            gossip: GossipConfig::default(),
+            mdns: MdnsConfig::default(),
+            features: FeatureToggles::default(),
+            transfer: TransferConfig::default(),
+            location: LocationConfig::default(),
+            outbox: OutboxConfig::default(),
+            history: HistoryConfig::default(),
+            storage_path: "truman-data".to_string(),
+        }
+    }
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            listen_ip: "0.0.0.0".to_string(),
+            tcp_port: 0,
+            quic_port: 0,
+            transports: TransportConfig::default(),
+            bootstrap_peers: Vec::new(),
+            relays: Vec::new(),
+            ping_interval_secs: 15,
+            ping_timeout_secs: 20,
+        }
+    }
+}
+
+impl Default for TransportConfig {
+    fn default() -> Self {
+        Self {
+            tcp: true,
+            quic: true,
+        }
+    }
+}
+
+impl Default for GossipConfig {
+    fn default() -> Self {
+        Self {
+            default_room: "general".to_string(),
+            // This is set to aid debugging by not cluttering the log space
+            heartbeat_interval_ms: 10_000,
+            validation_mode: ValidationMode::Strict,
+            max_transmit_size: 65536,
+            compression_threshold: 1024,
+            max_decompressed_size: 1024 * 1024,
+        }
+    }
+}
+
+impl Default for MdnsConfig {
+    fn default() -> Self {
+        // Same as libp2p's mdns::Config::default()
+        Self {
+            ttl_secs: 6 * 60,
+            query_interval_secs: 5 * 60,
+        }
+    }
+}
+
+impl Default for TransferConfig {
+    fn default() -> Self {
+        Self {
+            max_file_size: 16 * 1024 * 1024,
+            max_parallel_requests: 8,
+            inline_voice_max_size: 16 * 1024,
+            max_voice_clips_per_peer: 20,
+            max_voice_clips: 200,
+            voice_window_secs: 10 * 60,
+        }
+    }
+}
+
+impl Default for LocationConfig {
+    fn default() -> Self {
+        Self {
+            share: false,
+            precision: Precision::Coarse,
+            grid_size_deg: 0.01,
+            beacon_interval_secs: 5 * 60,
+            // Rather one warning too many than a missed one
+            alerts_without_location: true,
+        }
+    }
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        Self {
+            critical_per_sec: 20,
+            high_per_sec: 10,
+            normal_per_sec: 5,
+            max_queue_depth: 1000,
+            // Nobody to send to for hours, by then it is old news that only confuses
+            max_age_secs: 6 * 60 * 60,
+            normal_queue_depth: 100,
+            normal_max_age_secs: 60,
+        }
+    }
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            sync_on_connect: true,
+            max_messages: 200,
+            max_age_secs: 24 * 60 * 60,
+            overlap_secs: 5 * 60,
+        }
+    }
+}
+
+impl Default for FeatureToggles {
+    fn default() -> Self {
+        Self {
+            mdns: true,
+            kademlia: true,
+            relay_server: false,
+            relay_client: true,
+            hole_punching: true,
+        }
+    }
+}
+
+impl From<ValidationMode> for gossipsub::ValidationMode {
+    fn from(mode: ValidationMode) -> Self {
+        match mode {
+            ValidationMode::Strict => gossipsub::ValidationMode::Strict,
+            ValidationMode::Permissive => gossipsub::ValidationMode::Permissive,
+            ValidationMode::Anonymous => gossipsub::ValidationMode::Anonymous,
+            ValidationMode::None => gossipsub::ValidationMode::None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Parse(String),
+    Invalid { field: &'static str, reason: String },
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Parse(e) => write!(f, "Could not parse config: {}", e),
+            ConfigError::Invalid { field, reason } => write!(f, "Invalid {}: {}", field, reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Parses a config from either JSON or TOML and validates it.
+    pub fn load(source: &str) -> Result<Self, Vec<ConfigError>> {
+        let source = source.trim();
+        let config: Config = if source.is_empty() {
+            Config::default()
+        } else if source.starts_with('{') {
+            serde_json::from_str(source).map_err(|e| vec![ConfigError::Parse(e.to_string())])?
+        } else {
+            toml::from_str(source).map_err(|e| vec![ConfigError::Parse(e.to_string())])?
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Collects every problem with the config instead of bailing on the first one,
+    /// so the host app can show them all at once.
+    pub fn validate(&self) -> Result<(), Vec<ConfigError>> {
+        let mut errors = Vec::new();
+        let mut invalid = |field: &'static str, reason: String| {
+            errors.push(ConfigError::Invalid { field, reason });
+        };
+
+        if self.network.listen_ip.parse::<IpAddr>().is_err() {
+            invalid("network.listen_ip", format!("'{}' is not an IP address", self.network.listen_ip));
+        }
+        if !self.network.transports.tcp && !self.network.transports.quic {
+            invalid("network.transports", "at least one transport has to be enabled".to_string());
+        }
+        for peer in &self.network.bootstrap_peers {
+            if peer.parse::<Multiaddr>().is_err() {
+                invalid("network.bootstrap_peers", format!("'{}' is not a multiaddr", peer));
+            }
+        }
+        for relay in &self.network.relays {
+            match relay.parse::<Multiaddr>() {
+                Ok(addr) if addr.iter().any(|p| matches!(p, Protocol::P2p(_))) => {}
+                Ok(_) => invalid("network.relays", format!("'{}' has to end with /p2p/<peer id>", relay)),
+                Err(_) => invalid("network.relays", format!("'{}' is not a multiaddr", relay)),
+            }
+        }
+        if !self.network.relays.is_empty() && !self.features.relay_client {
+            invalid("network.relays", "relays are configured but features.relay_client is off".to_string());
+        }
+        if self.features.hole_punching && !self.features.relay_client {
+            invalid("features.hole_punching", "hole punching needs features.relay_client".to_string());
+        }
+        if self.network.ping_interval_secs == 0 || self.network.ping_timeout_secs == 0 {
+            invalid("network.ping_interval_secs", "ping interval and timeout must be greater than 0".to_string());
+        }
+        if self.gossip.default_room.is_empty() {
+            invalid("gossip.default_room", "room name can't be empty".to_string());
+        }
+        if self.gossip.heartbeat_interval_ms == 0 {
+            invalid("gossip.heartbeat_interval_ms", "must be greater than 0".to_string());
+        }
+        if self.gossip.max_transmit_size < 1024 {
+            invalid("gossip.max_transmit_size", "must be at least 1024 bytes".to_string());
+        }
+        if self.gossip.max_decompressed_size < self.gossip.max_transmit_size {
+            invalid("gossip.max_decompressed_size", "must be at least gossip.max_transmit_size".to_string());
+        }
+        if self.features.mdns && (self.mdns.ttl_secs == 0 || self.mdns.query_interval_secs == 0) {
+            invalid("mdns", "ttl_secs and query_interval_secs must be greater than 0".to_string());
+        }
+        if self.transfer.max_file_size == 0 || self.transfer.max_parallel_requests == 0 {
+            invalid("transfer", "max_file_size and max_parallel_requests must be greater than 0".to_string());
+        }
+        if self.transfer.max_voice_clips_per_peer == 0
+            || self.transfer.max_voice_clips < self.transfer.max_voice_clips_per_peer
+            || self.transfer.voice_window_secs == 0
+        {
+            invalid(
+                "transfer",
+                "max_voice_clips_per_peer and voice_window_secs must be greater than 0, max_voice_clips at least max_voice_clips_per_peer".to_string(),
+            );
+        }
+        if self.transfer.inline_voice_max_size > self.gossip.max_transmit_size / 2 {
+            invalid("transfer.inline_voice_max_size", "must be at most half of gossip.max_transmit_size".to_string());
+        }
+        if !(self.location.grid_size_deg > 0.0 && self.location.grid_size_deg <= 1.0) {
+            invalid("location.grid_size_deg", "must be greater than 0 and at most 1".to_string());
+        }
+        if self.location.beacon_interval_secs == 0 {
+            invalid("location.beacon_interval_secs", "must be greater than 0".to_string());
+        }
+        let outbox = &self.outbox;
+        if outbox.critical_per_sec == 0 || outbox.high_per_sec == 0 || outbox.normal_per_sec == 0 {
+            invalid("outbox", "rates must be greater than 0".to_string());
+        }
+        if outbox.max_queue_depth == 0 || outbox.normal_queue_depth == 0 {
+            invalid("outbox", "queue depths must be greater than 0".to_string());
+        }
+        if outbox.max_age_secs == 0 || outbox.normal_max_age_secs == 0 {
+            invalid("outbox", "max_age_secs and normal_max_age_secs must be greater than 0".to_string());
+        }
+        if self.history.max_messages == 0 || self.history.max_messages as usize > MAX_QUERY_LIMIT {
+            invalid("history.max_messages", format!("must be between 1 and {}", MAX_QUERY_LIMIT));
+        }
+        if self.storage_path.is_empty() {
+            invalid("storage_path", "can't be empty".to_string());
+        }
+
+        if errors.is_empty() { Ok(()) } else { Err(errors) }
+    }
+
+    pub fn tcp_listen_addr(&self) -> Result<Multiaddr, libp2p::multiaddr::Error> {
+        format!("/{}/{}/tcp/{}", self.ip_protocol(), self.network.listen_ip, self.network.tcp_port).parse()
+    }
+
+    pub fn quic_listen_addr(&self) -> Result<Multiaddr, libp2p::multiaddr::Error> {
+        format!("/{}/{}/udp/{}/quic-v1", self.ip_protocol(), self.network.listen_ip, self.network.quic_port).parse()
+    }
+
+    pub fn relay_listen_addrs(&self) -> Vec<Multiaddr> {
+        self.network
+            .relays
+            .iter()
+            .filter_map(|relay| relay.parse::<Multiaddr>().ok())
+            .map(|relay| relay.with(Protocol::P2pCircuit))
+            .collect()
+    }
+
+    pub fn ping_config(&self) -> libp2p::ping::Config {
+        libp2p::ping::Config::new()
+            .with_interval(Duration::from_secs(self.network.ping_interval_secs))
+            .with_timeout(Duration::from_secs(self.network.ping_timeout_secs))
+    }
+
+    pub fn heartbeat_interval(&self) -> Duration {
+        Duration::from_millis(self.gossip.heartbeat_interval_ms)
+    }
+
+    pub fn mdns_config(&self) -> libp2p::mdns::Config {
+        libp2p::mdns::Config {
+            ttl: Duration::from_secs(self.mdns.ttl_secs),
+            query_interval: Duration::from_secs(self.mdns.query_interval_secs),
+            ..Default::default()
+        }
+    }
+
+    pub fn beacon_interval(&self) -> Duration {
+        Duration::from_secs(self.location.beacon_interval_secs)
+    }
+
+    /// Where shared and downloaded files live
+    pub fn files_dir(&self) -> PathBuf {
+        Path::new(&self.storage_path).join("files")
+    }
+
+    pub fn store_path(&self) -> PathBuf {
+        Path::new(&self.storage_path).join("messages.db")
+    }
+
+    fn ip_protocol(&self) -> &'static str {
+        match self.network.listen_ip.parse::<IpAddr>() {
+            Ok(IpAddr::V6(_)) => "ip6",
+            _ => "ip4",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn invalid_fields(errors: &[ConfigError]) -> Vec<&'static str> {
+        errors
+            .iter()
+            .map(|error| match error {
+                ConfigError::Invalid { field, .. } => *field,
+                ConfigError::Parse(e) => panic!("Expected a validation error, got {}", e),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn empty_config_is_the_default() {
+        let config = Config::load("  \n").unwrap();
+        assert_eq!(config.network.listen_ip, "0.0.0.0");
+        assert_eq!(config.gossip.default_room, "general");
+        assert_eq!(config.storage_path, "truman-data");
+        assert!(Config::default().validate().is_ok());
+    }
+
+    #[test]
+    fn json_and_toml_are_told_apart() {
+        let json = Config::load(r#"{ "network": { "tcp_port": 4001 }, "gossip": { "default_room": "ops" } }"#).unwrap();
+        let toml = Config::load("[network]\ntcp_port = 4001\n\n[gossip]\ndefault_room = \"ops\"\n").unwrap();
+        for config in [json, toml] {
+            assert_eq!(config.network.tcp_port, 4001);
+            assert_eq!(config.gossip.default_room, "ops");
+        }
+    }
+
+    #[test]
+    fn missing_fields_get_their_defaults() {
+        let config = Config::load(r#"{ "network": { "tcp_port": 4001 } }"#).unwrap();
+        let defaults = Config::default();
+        assert_eq!(config.network.quic_port, defaults.network.quic_port);
+        assert!(config.network.transports.tcp && config.network.transports.quic);
+        assert_eq!(config.network.ping_interval_secs, defaults.network.ping_interval_secs);
+        assert_eq!(config.gossip.max_transmit_size, defaults.gossip.max_transmit_size);
+        assert_eq!(config.outbox.normal_queue_depth, defaults.outbox.normal_queue_depth);
+        assert!(config.features.relay_client && !config.features.relay_server);
+
+        // Also within a section that is there
+        let config = Config::load("[transfer]\nmax_parallel_requests = 2\n").unwrap();
+        assert_eq!(config.transfer.max_parallel_requests, 2);
+        assert_eq!(config.transfer.max_file_size, defaults.transfer.max_file_size);
+    }
+
+    #[test]
+    fn parse_errors_are_reported() {
+        for source in [r#"{ "network": "#, "[network\ntcp_port = 1", r#"{ "network": { "tcp_port": "many" } }"#] {
+            let errors = Config::load(source).unwrap_err();
+            assert!(matches!(errors.as_slice(), [ConfigError::Parse(_)]), "{}", source);
+        }
+    }
+
+    #[test]
+    fn validation_collects_every_error() {
+        let source = r#"{
+            "network": {
+                "listen_ip": "not an ip",
+                "transports": { "tcp": false, "quic": false },
+                "bootstrap_peers": ["nope"],
+                "relays": ["/ip4/10.0.0.1/tcp/4001"]
+            },
+            "gossip": { "default_room": "", "heartbeat_interval_ms": 0 },
+            "storage_path": ""
+        }"#;
+        let errors = Config::load(source).unwrap_err();
+        assert_eq!(
+            invalid_fields(&errors),
+            vec![
+                "network.listen_ip",
+                "network.transports",
+                "network.bootstrap_peers",
+                "network.relays",
+                "gossip.default_room",
+                "gossip.heartbeat_interval_ms",
+                "storage_path",
+            ]
+        );
+    }
+
+    #[test]
+    fn validation_checks_settings_against_each_other() {
+        let mut config = Config::default();
+        config.features.relay_client = false;
+        config.network.relays = vec!["/ip4/10.0.0.1/tcp/4001/p2p/12D3KooWDpJ7As7BWAwRMfu1VU2WCqNjvq387JEYKDBj4kx6nXTN".to_string()];
+        config.gossip.max_decompressed_size = config.gossip.max_transmit_size - 1;
+        config.transfer.max_voice_clips = config.transfer.max_voice_clips_per_peer - 1;
+        let errors = config.validate().unwrap_err();
+        assert_eq!(
+            invalid_fields(&errors),
+            vec!["network.relays", "features.hole_punching", "gossip.max_decompressed_size", "transfer"]
+        );
+    }
+}
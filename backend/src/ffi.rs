@@ -46,7 +46,7 @@ pub fn to_vec(&self) -> Vec<String> {
         }
     }
 
-    pub fn from_vec(data: &Vec<String>) -> Self {
+    pub fn from_vec(data: &[String]) -> Self {
         if data.is_empty() {
             return Self::null();
         }
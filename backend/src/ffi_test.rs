@@ -13,7 +13,7 @@ fn print_ffi_list(list: FFIList, label: &str) {
             println!("  [{}]: {}", i, s);
         }
     }
-    println!("");
+    println!();
 }
 
 fn main() {
@@ -0,0 +1,303 @@
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+// Locations attached to messages and the periodic LocationShare beacons.
+// Nobody shares their position unless they opted in, and by default beacons only carry
+// the center of a grid cell (about a kilometer wide) instead of the exact position.
+
+// Roughly, good enough for accuracy estimates
+const METERS_PER_DEGREE: f64 = 111_320.0;
+const EARTH_RADIUS_M: f64 = 6_371_000.0;
+// Alerts go over gossip, nobody needs a coastline's worth of points
+pub const MAX_POLYGON_POINTS: usize = 256;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LocationSource {
+    Gps,
+    Network,
+    // Typed in or picked on a map
+    Manual,
+    #[default]
+    Unknown,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Location {
+    pub lat: f64,
+    pub lon: f64,
+    // Radius in meters
+    #[serde(default)]
+    pub accuracy_m: Option<f64>,
+    #[serde(default)]
+    pub altitude_m: Option<f64>,
+    #[serde(default)]
+    pub source: LocationSource,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Precision {
+    Exact,
+    // Snapped to the center of a grid cell
+    Coarse,
+}
+
+impl Location {
+    pub fn is_valid(&self) -> bool {
+        (-90.0..=90.0).contains(&self.lat)
+            && (-180.0..=180.0).contains(&self.lon)
+            && self.accuracy_m.is_none_or(|a| a.is_finite() && a >= 0.0)
+            && self.altitude_m.is_none_or(f64::is_finite)
+    }
+
+    /// Center of the grid cell the location is in, with the accuracy widened to match
+    pub fn coarsen(&self, grid_size_deg: f64) -> Self {
+        let snap = |value: f64| ((value / grid_size_deg).floor() + 0.5) * grid_size_deg;
+        let cell_radius = grid_size_deg * METERS_PER_DEGREE / 2.0;
+        Self {
+            lat: snap(self.lat).clamp(-90.0, 90.0),
+            lon: snap(self.lon).clamp(-180.0, 180.0),
+            accuracy_m: Some(self.accuracy_m.unwrap_or(0.0).max(cell_radius)),
+            // Would give away which floor someone is on
+            altitude_m: None,
+            source: self.source,
+        }
+    }
+
+    pub fn with_precision(&self, precision: Precision, grid_size_deg: f64) -> Self {
+        match precision {
+            Precision::Exact => self.clone(),
+            Precision::Coarse => self.coarsen(grid_size_deg),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Point {
+    pub lat: f64,
+    pub lon: f64,
+}
+
+impl Point {
+    fn is_valid(&self) -> bool {
+        (-90.0..=90.0).contains(&self.lat) && (-180.0..=180.0).contains(&self.lon)
+    }
+
+    /// Haversine, in meters
+    fn distance_to(&self, other: &Point) -> f64 {
+        let (lat1, lat2) = (self.lat.to_radians(), other.lat.to_radians());
+        let d_lat = lat2 - lat1;
+        let d_lon = (other.lon - self.lon).to_radians();
+        let a = (d_lat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (d_lon / 2.0).sin().powi(2);
+        2.0 * EARTH_RADIUS_M * a.sqrt().asin()
+    }
+
+    /// On the straight line between a and b, give or take rounding
+    fn is_on_edge(&self, a: &Point, b: &Point) -> bool {
+        let cross = (b.lon - a.lon) * (self.lat - a.lat) - (b.lat - a.lat) * (self.lon - a.lon);
+        cross.abs() <= 1e-12
+            && self.lat >= a.lat.min(b.lat)
+            && self.lat <= a.lat.max(b.lat)
+            && self.lon >= a.lon.min(b.lon)
+            && self.lon <= a.lon.max(b.lon)
+    }
+}
+
+/// Region a geofenced alert is meant for
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Area {
+    Circle { center: Point, radius_m: f64 },
+    // Doesn't have to be closed, the last point connects back to the first.
+    // Treated as flat, which is fine for anything smaller than a country.
+    Polygon { points: Vec<Point> },
+}
+
+impl Area {
+    pub fn is_valid(&self) -> bool {
+        match self {
+            Area::Circle { center, radius_m } => center.is_valid() && radius_m.is_finite() && *radius_m > 0.0,
+            Area::Polygon { points } => {
+                (3..=MAX_POLYGON_POINTS).contains(&points.len()) && points.iter().all(Point::is_valid)
+            }
+        }
+    }
+
+    pub fn contains(&self, location: &Location) -> bool {
+        let here = Point { lat: location.lat, lon: location.lon };
+        match self {
+            Area::Circle { center, radius_m } => center.distance_to(&here) <= *radius_m,
+            Area::Polygon { points } => {
+                // Ray casting, count how many edges a line going east from here crosses
+                let Some(mut previous) = points.last().copied() else {
+                    return false;
+                };
+                let mut inside = false;
+                for point in points {
+                    // Ray casting can go either way right on the border, better to warn one too many
+                    if here.is_on_edge(&previous, point) {
+                        return true;
+                    }
+                    if (point.lat > here.lat) != (previous.lat > here.lat) {
+                        let crossing_lon =
+                            point.lon + (here.lat - point.lat) / (previous.lat - point.lat) * (previous.lon - point.lon);
+                        if here.lon < crossing_lon {
+                            inside = !inside;
+                        }
+                    }
+                    previous = *point;
+                }
+                inside
+            }
+        }
+    }
+}
+
+/// Our own position as told by the host app, and whether we send it out
+pub struct LocationSharing {
+    pub current: Option<Location>,
+    pub enabled: bool,
+    pub precision: Precision,
+    last_beacon: Option<Instant>,
+}
+
+impl LocationSharing {
+    pub fn new(enabled: bool, precision: Precision) -> Self {
+        Self {
+            current: None,
+            enabled,
+            precision,
+            last_beacon: None,
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool, precision: Precision) {
+        self.enabled = enabled;
+        self.precision = precision;
+        // Send the next beacon right away
+        self.last_beacon = None;
+    }
+
+    /// The location to beacon if sharing is on and the interval has passed
+    pub fn beacon_due(&mut self, interval: Duration, grid_size_deg: f64) -> Option<Location> {
+        if !self.enabled || self.last_beacon.is_some_and(|last| last.elapsed() < interval) {
+            return None;
+        }
+        let location = self.current.as_ref()?.with_precision(self.precision, grid_size_deg);
+        self.last_beacon = Some(Instant::now());
+        Some(location)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(lat: f64, lon: f64) -> Location {
+        Location { lat, lon, accuracy_m: None, altitude_m: None, source: LocationSource::Gps }
+    }
+
+    fn polygon(points: &[(f64, f64)]) -> Area {
+        Area::Polygon { points: points.iter().map(|&(lat, lon)| Point { lat, lon }).collect() }
+    }
+
+    #[test]
+    fn polygon_inside_and_outside() {
+        let square = polygon(&[(0.0, 0.0), (0.0, 1.0), (1.0, 1.0), (1.0, 0.0)]);
+        assert!(square.contains(&at(0.5, 0.5)));
+        assert!(!square.contains(&at(1.5, 0.5)));
+        assert!(!square.contains(&at(0.5, -0.5)));
+        assert!(!square.contains(&at(-0.5, -0.5)));
+    }
+
+    #[test]
+    fn concave_polygon_leaves_out_the_notch() {
+        // A U shape, open to the north between lon 1 and 2
+        let u = polygon(&[(0.0, 0.0), (0.0, 3.0), (3.0, 3.0), (3.0, 2.0), (1.0, 2.0), (1.0, 1.0), (3.0, 1.0), (3.0, 0.0)]);
+        assert!(u.contains(&at(2.0, 0.5)));
+        assert!(u.contains(&at(2.0, 2.5)));
+        assert!(u.contains(&at(0.5, 1.5)));
+        assert!(!u.contains(&at(2.0, 1.5)));
+    }
+
+    #[test]
+    fn polygon_border_counts_as_inside() {
+        let square = polygon(&[(0.0, 0.0), (0.0, 1.0), (1.0, 1.0), (1.0, 0.0)]);
+        // Edges, including the closing one from the last point back to the first
+        assert!(square.contains(&at(0.0, 0.5)));
+        assert!(square.contains(&at(1.0, 0.5)));
+        assert!(square.contains(&at(0.5, 0.0)));
+        assert!(square.contains(&at(0.5, 1.0)));
+        // Vertices
+        for (lat, lon) in [(0.0, 0.0), (0.0, 1.0), (1.0, 1.0), (1.0, 0.0)] {
+            assert!(square.contains(&at(lat, lon)));
+        }
+        // In line with an edge but past its end
+        assert!(!square.contains(&at(0.0, 1.5)));
+    }
+
+    #[test]
+    fn circle_boundary() {
+        let center = Point { lat: 52.0, lon: 5.0 };
+        let edge = Point { lat: 52.01, lon: 5.0 };
+        let radius_m = center.distance_to(&edge);
+        let circle = Area::Circle { center, radius_m };
+        assert!(circle.contains(&at(52.01, 5.0)));
+        assert!(circle.contains(&at(52.005, 5.0)));
+        assert!(!circle.contains(&at(52.0101, 5.0)));
+        // A hundredth of a degree is a bit over a kilometer
+        assert!((radius_m - 1112.0).abs() < 5.0);
+    }
+
+    #[test]
+    fn area_limits() {
+        let center = Point { lat: 52.0, lon: 5.0 };
+        assert!(Area::Circle { center, radius_m: 500.0 }.is_valid());
+        assert!(!Area::Circle { center, radius_m: 0.0 }.is_valid());
+        assert!(!Area::Circle { center, radius_m: -1.0 }.is_valid());
+        assert!(!Area::Circle { center, radius_m: f64::INFINITY }.is_valid());
+        assert!(!Area::Circle { center, radius_m: f64::NAN }.is_valid());
+        assert!(!Area::Circle { center: Point { lat: 91.0, lon: 5.0 }, radius_m: 500.0 }.is_valid());
+
+        assert!(!polygon(&[(0.0, 0.0), (1.0, 1.0)]).is_valid());
+        assert!(polygon(&[(0.0, 0.0), (1.0, 1.0), (1.0, 0.0)]).is_valid());
+        assert!(!polygon(&[(0.0, 0.0), (1.0, 181.0), (1.0, 0.0)]).is_valid());
+        let ring = |n: usize| -> Vec<(f64, f64)> {
+            (0..n).map(|i| {
+                let angle = i as f64 / n as f64 * std::f64::consts::TAU;
+                (angle.sin(), angle.cos())
+            }).collect()
+        };
+        assert!(polygon(&ring(MAX_POLYGON_POINTS)).is_valid());
+        assert!(!polygon(&ring(MAX_POLYGON_POINTS + 1)).is_valid());
+    }
+
+    #[test]
+    fn coarsen_snaps_to_the_cell_center() {
+        let location = Location { accuracy_m: Some(5.0), altitude_m: Some(12.0), ..at(52.3712, 4.8991) };
+        let coarse = location.coarsen(0.01);
+        assert!((coarse.lat - 52.375).abs() < 1e-9);
+        assert!((coarse.lon - 4.895).abs() < 1e-9);
+        assert_eq!(coarse.altitude_m, None);
+        assert_eq!(coarse.source, LocationSource::Gps);
+        // Half a cell, a worse accuracy is kept as is
+        assert!((coarse.accuracy_m.unwrap() - 0.005 * METERS_PER_DEGREE).abs() < 1e-6);
+        let vague = Location { accuracy_m: Some(5000.0), ..location.clone() };
+        assert_eq!(vague.coarsen(0.01).accuracy_m, Some(5000.0));
+
+        // Everyone in the same cell ends up at the same place
+        assert_eq!(at(52.3701, 4.8909).coarsen(0.01).lat, at(52.3799, 4.8999).coarsen(0.01).lat);
+        // South and west of zero floor the other way
+        assert!((at(-0.001, -0.001).coarsen(0.01).lat + 0.005).abs() < 1e-9);
+        // Never off the map
+        assert!(at(90.0, 180.0).coarsen(1.0).is_valid());
+    }
+
+    #[test]
+    fn with_precision_only_coarsens_when_asked() {
+        let location = Location { altitude_m: Some(12.0), ..at(52.3712, 4.8991) };
+        assert_eq!(location.with_precision(Precision::Exact, 0.01), location);
+        assert_eq!(location.with_precision(Precision::Coarse, 0.01), location.coarsen(0.01));
+    }
+}
@@ -0,0 +1,332 @@
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use crate::communication::Message;
+use crate::store::StoredMessage;
+
+// The last messages we sent or received, by id. Needed to tell whether whoever supersedes
+// a message is allowed to (only its author or a wolf), and dropped once they expire.
+// Retracted ids are remembered too, so the message is dropped if it only shows up later.
+// Replies point at their parent through reply_to, threads are put back together from that.
+// The newest messages are loaded from the store on startup, so this all still works after a restart.
+
+pub const MAX_CACHED_MESSAGES: usize = 1000;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedMessage {
+    pub author: PeerId,
+    pub message: Message,
+    pub superseded_by: Option<String>,
+}
+
+impl CachedMessage {
+    /// None for stored messages that aren't broadcasts
+    pub fn from_stored(stored: StoredMessage) -> Option<Self> {
+        Some(Self {
+            author: stored.author.parse().ok()?,
+            message: stored.message.message()?.clone(),
+            superseded_by: stored.superseded_by,
+        })
+    }
+}
+
+/// A message that has to be marked as outdated, `by` is the id of the one replacing it
+#[derive(Debug, Clone)]
+pub struct Supersede {
+    pub msg_id: String,
+    pub by: String,
+}
+
+#[derive(Default)]
+pub struct MessageCache {
+    messages: HashMap<String, CachedMessage>,
+    // Oldest first, for evicting
+    order: VecDeque<String>,
+    // Superseded by a wolf before we saw the message itself, gossip doesn't keep order
+    early: HashMap<String, String>,
+    early_order: VecDeque<String>,
+    retracted: HashSet<String>,
+    retracted_order: VecDeque<String>,
+}
+
+impl MessageCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the messages that are outdated now. Messages from before ids existed aren't cached.
+    pub fn insert(&mut self, author: PeerId, message: Message, is_wolf: bool) -> Vec<Supersede> {
+        let mut superseded = Vec::new();
+        if message.id.is_empty() || self.messages.contains_key(&message.id) {
+            return superseded;
+        }
+
+        if let Some(target) = &message.supersedes {
+            match self.messages.get_mut(target) {
+                Some(cached) if cached.author == author || is_wolf => {
+                    cached.superseded_by = Some(message.id.clone());
+                    superseded.push(Supersede { msg_id: target.clone(), by: message.id.clone() });
+                }
+                Some(_) => {}
+                // Can't check the author of something we haven't seen, so only wolves
+                None if is_wolf => {
+                    if self.early.insert(target.clone(), message.id.clone()).is_none() {
+                        self.early_order.push_back(target.clone());
+                    }
+                    while self.early_order.len() > MAX_CACHED_MESSAGES {
+                        if let Some(oldest) = self.early_order.pop_front() {
+                            self.early.remove(&oldest);
+                        }
+                    }
+                }
+                None => {}
+            }
+        }
+
+        let superseded_by = self.early.remove(&message.id);
+        if let Some(by) = &superseded_by {
+            self.early_order.retain(|id| *id != message.id);
+            superseded.push(Supersede { msg_id: message.id.clone(), by: by.clone() });
+        }
+
+        self.push(CachedMessage { author, message, superseded_by });
+        superseded
+    }
+
+    /// Messages out of our own store, oldest first. Those were checked when they came in.
+    pub fn load(&mut self, messages: Vec<CachedMessage>) {
+        for cached in messages {
+            if !cached.message.id.is_empty() && !self.messages.contains_key(&cached.message.id) {
+                self.push(cached);
+            }
+        }
+    }
+
+    fn push(&mut self, cached: CachedMessage) {
+        while self.messages.len() >= MAX_CACHED_MESSAGES {
+            let Some(oldest) = self.order.pop_front() else {
+                break;
+            };
+            self.messages.remove(&oldest);
+        }
+        self.order.push_back(cached.message.id.clone());
+        self.messages.insert(cached.message.id.clone(), cached);
+    }
+
+    pub fn get(&self, msg_id: &str) -> Option<&CachedMessage> {
+        self.messages.get(msg_id)
+    }
+
+    /// The whole thread the message is part of, from the first message on, oldest first.
+    /// Parents we never saw (or that expired) cut the thread short.
+    pub fn thread(&self, msg_id: &str) -> Vec<CachedMessage> {
+        let Some(mut root) = self.messages.get(msg_id) else {
+            return Vec::new();
+        };
+        let mut seen = HashSet::from([msg_id]);
+        while let Some(parent) = root.message.reply_to.as_deref().and_then(|id| self.messages.get(id)) {
+            // Someone could make a loop on purpose
+            if !seen.insert(&parent.message.id) {
+                break;
+            }
+            root = parent;
+        }
+
+        let mut thread_ids = HashSet::from([root.message.id.as_str()]);
+        let mut thread = vec![root.clone()];
+        loop {
+            let replies: Vec<&CachedMessage> = self
+                .messages
+                .values()
+                .filter(|cached| !thread_ids.contains(cached.message.id.as_str()))
+                .filter(|cached| cached.message.reply_to.as_deref().is_some_and(|id| thread_ids.contains(id)))
+                .collect();
+            if replies.is_empty() {
+                break;
+            }
+            for reply in replies {
+                thread_ids.insert(&reply.message.id);
+                thread.push(reply.clone());
+            }
+        }
+        thread.sort_by_key(|cached| cached.message.timestamp);
+        thread
+    }
+
+    /// Forgets the message and makes sure it isn't accepted again
+    pub fn retract(&mut self, msg_id: &str) {
+        self.remove(msg_id);
+        if self.retracted.insert(msg_id.to_string()) {
+            self.retracted_order.push_back(msg_id.to_string());
+        }
+        if self.retracted_order.len() > MAX_CACHED_MESSAGES
+            && let Some(oldest) = self.retracted_order.pop_front()
+        {
+            self.retracted.remove(&oldest);
+        }
+    }
+
+    pub fn is_retracted(&self, msg_id: &str) -> bool {
+        self.retracted.contains(msg_id)
+    }
+
+    /// Drops expired messages, returns their ids
+    pub fn prune(&mut self, now_ms: u64) -> Vec<String> {
+        let expired: Vec<String> = self
+            .messages
+            .iter()
+            .filter(|(_, cached)| cached.message.is_expired(now_ms))
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in &expired {
+            self.messages.remove(id);
+        }
+        if !expired.is_empty() {
+            self.order.retain(|id| self.messages.contains_key(id));
+        }
+        expired
+    }
+
+    /// Every way out of the cache goes through here or prune(), so `order` never holds ids that are gone
+    fn remove(&mut self, msg_id: &str) {
+        if self.messages.remove(msg_id).is_some() {
+            self.order.retain(|id| id != msg_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::communication::Tag;
+
+    fn message(id: &str, supersedes: Option<&str>) -> Message {
+        Message {
+            message: format!("message {}", id),
+            tags: Tag::Normal,
+            timestamp: 1_716_594_540_000,
+            location: None,
+            id: id.to_string(),
+            expires_at: None,
+            supersedes: supersedes.map(str::to_string),
+            reply_to: None,
+        }
+    }
+
+    fn superseded(supersedes: &[Supersede]) -> Vec<(&str, &str)> {
+        supersedes.iter().map(|s| (s.msg_id.as_str(), s.by.as_str())).collect()
+    }
+
+    #[test]
+    fn author_can_supersede_their_own_message() {
+        let mut cache = MessageCache::new();
+        let author = PeerId::random();
+        assert!(cache.insert(author, message("a", None), false).is_empty());
+        let supersedes = cache.insert(author, message("b", Some("a")), false);
+        assert_eq!(superseded(&supersedes), [("a", "b")]);
+        assert_eq!(cache.get("a").unwrap().superseded_by.as_deref(), Some("b"));
+    }
+
+    #[test]
+    fn only_a_wolf_can_supersede_someone_else() {
+        let mut cache = MessageCache::new();
+        cache.insert(PeerId::random(), message("a", None), false);
+
+        assert!(cache.insert(PeerId::random(), message("b", Some("a")), false).is_empty());
+        assert_eq!(cache.get("a").unwrap().superseded_by, None);
+        // The message itself is still kept, it just doesn't replace anything
+        assert!(cache.get("b").is_some());
+
+        let supersedes = cache.insert(PeerId::random(), message("c", Some("a")), true);
+        assert_eq!(superseded(&supersedes), [("a", "c")]);
+    }
+
+    #[test]
+    fn wolf_can_supersede_before_the_message_arrives() {
+        let mut cache = MessageCache::new();
+        assert!(cache.insert(PeerId::random(), message("b", Some("a")), true).is_empty());
+        // Nobody else can, there's no author to check against yet
+        assert!(cache.insert(PeerId::random(), message("d", Some("c")), false).is_empty());
+
+        let supersedes = cache.insert(PeerId::random(), message("a", None), false);
+        assert_eq!(superseded(&supersedes), [("a", "b")]);
+        assert_eq!(cache.get("a").unwrap().superseded_by.as_deref(), Some("b"));
+        assert!(cache.insert(PeerId::random(), message("c", None), false).is_empty());
+        assert_eq!(cache.get("c").unwrap().superseded_by, None);
+    }
+
+    #[test]
+    fn duplicates_and_messages_without_id_are_ignored() {
+        let mut cache = MessageCache::new();
+        let author = PeerId::random();
+        cache.insert(author, message("a", None), false);
+        cache.insert(author, message("b", Some("a")), false);
+        // Seeing b again doesn't supersede anything twice
+        assert!(cache.insert(author, message("b", Some("a")), false).is_empty());
+        cache.insert(author, message("", None), false);
+        assert!(cache.get("").is_none());
+    }
+
+    #[test]
+    fn oldest_messages_are_evicted_first() {
+        let mut cache = MessageCache::new();
+        let author = PeerId::random();
+        for i in 0..MAX_CACHED_MESSAGES {
+            cache.insert(author, message(&i.to_string(), None), false);
+        }
+        assert!(cache.get("0").is_some());
+        cache.insert(author, message("new", None), false);
+        assert!(cache.get("0").is_none());
+        assert!(cache.get("1").is_some());
+        assert!(cache.get("new").is_some());
+
+        // Retracted messages don't take up a spot, the next eviction takes the next oldest
+        cache.retract("5");
+        cache.insert(author, message("newer", None), false);
+        assert!(cache.get("1").is_some());
+        cache.insert(author, message("newest", None), false);
+        assert!(cache.get("1").is_none());
+        assert!(cache.get("2").is_some());
+    }
+
+    #[test]
+    fn expired_messages_are_pruned() {
+        let mut cache = MessageCache::new();
+        let author = PeerId::random();
+        let mut expiring = message("expiring", None);
+        expiring.expires_at = Some(2_000);
+        cache.insert(author, expiring, false);
+        cache.insert(author, message("lasting", None), false);
+
+        assert!(cache.prune(1_999).is_empty());
+        assert_eq!(cache.prune(2_000), ["expiring"]);
+        assert!(cache.get("expiring").is_none());
+        assert!(cache.get("lasting").is_some());
+        assert!(!cache.order.contains(&"expiring".to_string()));
+    }
+
+    #[test]
+    fn loaded_messages_can_be_superseded_after_a_restart() {
+        let mut cache = MessageCache::new();
+        let author = PeerId::random();
+        cache.load(vec![
+            CachedMessage { author, message: message("a", None), superseded_by: None },
+            CachedMessage { author, message: message("", None), superseded_by: None },
+        ]);
+        assert!(cache.get("").is_none());
+        let supersedes = cache.insert(author, message("b", Some("a")), false);
+        assert_eq!(superseded(&supersedes), [("a", "b")]);
+    }
+
+    #[test]
+    fn retracted_messages_are_forgotten_and_stay_out() {
+        let mut cache = MessageCache::new();
+        cache.insert(PeerId::random(), message("a", None), false);
+        cache.retract("a");
+        cache.retract("b");
+        assert!(cache.get("a").is_none());
+        assert!(cache.is_retracted("a") && cache.is_retracted("b"));
+        assert!(!cache.is_retracted("c"));
+    }
+}
@@ -0,0 +1,18 @@
+use libp2p::PeerId;
+
+use super::GossipEvent;
+use crate::geo::Location;
+use crate::rollcall::{CheckInStatus, RollCall, RollCallError, SignedCheckIn};
+
+// Roll calls and check-ins both go to the default room, the roster itself is in rollcall.rs:
+// - everyone remembers the roll calls they hear about, so they can answer them
+// - only wolves keep the check-ins, everyone else just relays them
+
+pub trait CheckIns {
+    /// Wolves only, returns the new roll call's id
+    fn start_roll_call(&mut self, duration_ms: u64) -> Result<String, RollCallError>;
+    fn check_in(&mut self, roll_call: &str, status: CheckInStatus, location: Option<Location>) -> Result<(), RollCallError>;
+    fn roll_call_received(&mut self, by: PeerId, call: RollCall);
+    /// Returns a CheckedIn event if it's new and we keep a roster
+    fn check_in_received(&mut self, from: PeerId, check_in: SignedCheckIn) -> Option<GossipEvent>;
+}
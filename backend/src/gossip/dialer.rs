@@ -0,0 +1,117 @@
+use libp2p::{Multiaddr, PeerId, swarm::ConnectionId};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(2);
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+struct BootstrapPeer {
+    address: Multiaddr,
+    // Only known once we managed to connect to it (or if the address ends with /p2p/...)
+    peer_id: Option<PeerId>,
+    backoff: Duration,
+    // None while a dial is in flight or while we are connected
+    next_attempt: Option<Instant>,
+}
+
+pub struct PendingDial {
+    pub address: Multiaddr,
+    bootstrap_index: Option<usize>,
+}
+
+/// Keeps track of the dials we made ourselves (manual or bootstrap),
+/// so we can tell the host app how they went and redial bootstrap peers with backoff.
+pub struct Dialer {
+    bootstrap: Vec<BootstrapPeer>,
+    pending: HashMap<ConnectionId, PendingDial>,
+}
+
+impl Dialer {
+    pub fn new(bootstrap_peers: &[String]) -> Self {
+        let now = Instant::now();
+        let bootstrap = bootstrap_peers
+            .iter()
+            // Config validation already complained about the broken ones
+            .filter_map(|address| address.parse::<Multiaddr>().ok())
+            .map(|address| BootstrapPeer {
+                peer_id: address.iter().find_map(|p| match p {
+                    libp2p::multiaddr::Protocol::P2p(peer_id) => Some(peer_id),
+                    _ => None,
+                }),
+                address,
+                backoff: INITIAL_BACKOFF,
+                next_attempt: Some(now),
+            })
+            .collect();
+        Self {
+            bootstrap,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Bootstrap addresses whose backoff ran out, marked as in flight.
+    /// Peers we are already connected to (e.g. found via mDNS) are skipped.
+    pub fn due_bootstrap_dials(&mut self, is_connected: impl Fn(&PeerId) -> bool) -> Vec<(usize, Multiaddr)> {
+        let now = Instant::now();
+        let mut due = Vec::new();
+        for (index, peer) in self.bootstrap.iter_mut().enumerate() {
+            if peer.next_attempt.is_none_or(|at| at > now) {
+                continue;
+            }
+            peer.next_attempt = None;
+            if peer.peer_id.as_ref().is_some_and(&is_connected) {
+                continue;
+            }
+            due.push((index, peer.address.clone()));
+        }
+        due
+    }
+
+    pub fn dial_started(&mut self, connection_id: ConnectionId, address: Multiaddr, bootstrap_index: Option<usize>) {
+        self.pending.insert(connection_id, PendingDial { address, bootstrap_index });
+    }
+
+    /// Call when dialing failed before it even got to the swarm
+    pub fn dial_not_started(&mut self, bootstrap_index: Option<usize>) {
+        if let Some(index) = bootstrap_index {
+            self.back_off(index);
+        }
+    }
+
+    pub fn dial_succeeded(&mut self, connection_id: &ConnectionId, peer_id: PeerId) -> Option<PendingDial> {
+        let pending = self.pending.remove(connection_id)?;
+        if let Some(peer) = pending.bootstrap_index.and_then(|i| self.bootstrap.get_mut(i)) {
+            peer.peer_id = Some(peer_id);
+            peer.backoff = INITIAL_BACKOFF;
+            peer.next_attempt = None;
+        }
+        Some(pending)
+    }
+
+    pub fn dial_failed(&mut self, connection_id: &ConnectionId) -> Option<PendingDial> {
+        let pending = self.pending.remove(connection_id)?;
+        if let Some(index) = pending.bootstrap_index {
+            self.back_off(index);
+        }
+        Some(pending)
+    }
+
+    /// Call once we have no connection left to `peer_id`, bootstrap peers get redialed
+    pub fn peer_disconnected(&mut self, peer_id: &PeerId) {
+        for peer in self.bootstrap.iter_mut() {
+            if peer.peer_id.as_ref() == Some(peer_id) && peer.next_attempt.is_none() {
+                peer.next_attempt = Some(Instant::now() + peer.backoff);
+            }
+        }
+    }
+
+    fn back_off(&mut self, index: usize) {
+        let Some(peer) = self.bootstrap.get_mut(index) else {
+            return;
+        };
+        peer.next_attempt = Some(Instant::now() + peer.backoff);
+        peer.backoff = (peer.backoff * 2).min(MAX_BACKOFF);
+    }
+}
@@ -0,0 +1,36 @@
+use libp2p::{PeerId, kad};
+
+// mDNS only sees the local subnet, Kademlia is how we find everyone else:
+// - peer routing, so a DM (or ping) can dial a recipient we aren't directly linked with
+// - provider records for rooms, so we can find other members of a room
+// - provider records for files, so chunks can be fetched from anyone that has them
+
+/// What a Kademlia query was started for, so we know what to do with the result
+#[derive(Debug, Clone)]
+pub enum RoutingQuery {
+    Peer(PeerId),
+    RoomProviders(String),
+    FileProviders(String),
+}
+
+pub fn room_key(room_name: &str) -> kad::RecordKey {
+    kad::RecordKey::new(&format!("truman/room/{}", room_name))
+}
+
+pub fn file_key(root_hash: &str) -> kad::RecordKey {
+    kad::RecordKey::new(&format!("truman/file/{}", root_hash))
+}
+
+pub trait Discovery {
+    /// Looks the peer up in the DHT and dials it once found, no-op if we're already connected
+    fn find_peer(&mut self, peer_id: PeerId);
+    /// Announces that we are a member of the room
+    fn provide_room(&mut self, room_name: &str);
+    /// Looks for other members of the room and dials them
+    fn find_room_providers(&mut self, room_name: &str);
+    /// Announces that we have the whole file
+    fn provide_file(&mut self, root_hash: &str);
+    /// Looks for peers that have the file, they are added to the download
+    fn find_file_providers(&mut self, root_hash: &str);
+    fn routing_result(&mut self, id: kad::QueryId, result: kad::QueryResult);
+}
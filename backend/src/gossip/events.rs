@@ -1,11 +1,28 @@
 use crate::gossip::MyBehaviourEvent;
 
 use super::GossipEvent;
-use libp2p::{gossipsub::Message, swarm::SwarmEvent, Multiaddr, PeerId};
+use super::history::{HistoryRequest, HistoryResponse};
+use super::message::MessageData;
+use super::replication::{BoardSyncRequest, BoardSyncResponse};
+use super::transfer::{ChunkRequest, ChunkResponse};
+use crate::communication::InteractionMessage;
+use libp2p::{core::ConnectedPoint, gossipsub::Message, identify, ping, request_response, swarm::{ConnectionError, ConnectionId, SwarmEvent}, Multiaddr, PeerId};
 
 pub trait EventHandler {
-    fn new_connections(&mut self, list: Vec<(PeerId, Multiaddr)>) -> Option<GossipEvent>;
-    fn new_disconnections(&mut self, list: Vec<(PeerId, Multiaddr)>) -> Option<GossipEvent>;
+    fn discovered(&mut self, list: Vec<(PeerId, Multiaddr)>) -> Option<GossipEvent>;
+    fn expired(&mut self, list: Vec<(PeerId, Multiaddr)>) -> Option<GossipEvent>;
+    fn connection_established(&mut self, peer_id: PeerId, connection_id: ConnectionId, endpoint: ConnectedPoint) -> Option<GossipEvent>;
+    fn connection_closed(&mut self, peer_id: PeerId, connection_id: ConnectionId, cause: Option<ConnectionError>) -> Option<GossipEvent>;
+    fn dial_failed(&mut self, connection_id: ConnectionId, error: String) -> Option<GossipEvent>;
+    fn ping(&mut self, event: ping::Event) -> Option<GossipEvent>;
+    fn identify(&mut self, event: identify::Event) -> Option<GossipEvent>;
+    fn chunks(&mut self, event: request_response::Event<ChunkRequest, ChunkResponse>) -> Option<GossipEvent>;
+    fn history(&mut self, event: request_response::Event<HistoryRequest, HistoryResponse>) -> Option<GossipEvent>;
+    fn board_sync(&mut self, event: request_response::Event<BoardSyncRequest, BoardSyncResponse>) -> Option<GossipEvent>;
     fn message(&mut self, peer_id: PeerId, message: Message) -> Option<GossipEvent>;
+    /// Whether a message by peer_id in this room is any of our business
+    fn is_for_us(&self, peer_id: &PeerId, room: &str) -> bool;
+    /// Who may send what, expiry, retractions, geofences. Stores the message if it passes.
+    fn accept(&mut self, msg_data: MessageData, interaction: InteractionMessage, signature: Option<Vec<u8>>) -> Option<GossipEvent>;
     fn handle(&mut self, event: SwarmEvent<MyBehaviourEvent>) -> Option<GossipEvent>;
 }
@@ -0,0 +1,91 @@
+use libp2p::{PeerId, identity::{Keypair, PublicKey}};
+use serde::{Deserialize, Serialize};
+
+use super::GossipEvent;
+use crate::communication::InteractionMessage;
+use crate::wire::{self, WireError};
+
+// A phone that slept for an hour missed everything gossiped in the meantime, gossipsub
+// doesn't keep anything around. So once we know a peer speaks it, we ask for what it
+// has in our rooms since we last got something there ("/truman/history/1.0.0").
+// Times are when a message arrived, never the sender's timestamp, which could be anything.
+// Our own arrival time is only a rough start for the peer's clock, overlap_secs covers the
+// difference. A full page comes with the peer's arrival times, and we ask for the rest
+// from where it ended.
+//
+// The peer answering isn't necessarily the author, and gossipsub's own signature doesn't
+// survive being stored, so authors sign every message they send a second time:
+// - the signature covers the room and the uncompressed CBOR body (see signing_bytes()),
+//   and goes along in the envelope's EXT_SIGNATURE extension
+// - ed25519 peer ids contain the public key, so anyone can check it later
+// - only messages with a valid signature are stored with one, and only those are handed out
+// Whatever comes back goes through the same checks as a live message (whitelist, expiry,
+// retractions, geofences) before it's stored. Retracts are handed out too, so a peer that
+// missed one doesn't keep the message around.
+
+pub const HISTORY_PROTOCOL: &str = "/truman/history/1.0.0";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RoomSince {
+    pub room: String,
+    // Unix time in milliseconds by the answering peer's clock, only messages it got later are sent
+    pub since: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HistoryRequest {
+    pub rooms: Vec<RoomSince>,
+    pub limit: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HistoryEntry {
+    pub room: String,
+    pub author: PeerId,
+    pub message: InteractionMessage,
+    #[serde(with = "serde_bytes")]
+    pub signature: Vec<u8>,
+    // When the answering peer got it, by its clock. Not signed, only for asking for the next page.
+    // Builds from before it don't send one.
+    #[serde(default)]
+    pub received_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HistoryResponse {
+    pub entries: Vec<HistoryEntry>,
+}
+
+fn signing_bytes(room: &str, message: &InteractionMessage) -> Result<Vec<u8>, WireError> {
+    // Length first, so one room/body split can't be passed off as another
+    let mut bytes = (room.len() as u64).to_be_bytes().to_vec();
+    bytes.extend_from_slice(room.as_bytes());
+    bytes.extend(wire::to_cbor(message)?);
+    Ok(bytes)
+}
+
+pub fn sign(keypair: &Keypair, room: &str, message: &InteractionMessage) -> Option<Vec<u8>> {
+    keypair.sign(&signing_bytes(room, message).ok()?).ok()
+}
+
+pub fn verify(author: &PeerId, room: &str, message: &InteractionMessage, signature: &[u8]) -> bool {
+    signing_bytes(room, message).is_ok_and(|bytes| verify_signature(author, &bytes, signature))
+}
+
+/// Checks a signature made with the author's libp2p key
+pub fn verify_signature(author: &PeerId, bytes: &[u8], signature: &[u8]) -> bool {
+    // Ed25519 keys are small enough to be inlined in the peer id, other key types aren't
+    let multihash: &libp2p::multihash::Multihash<64> = author.as_ref();
+    if multihash.code() != 0 {
+        return false;
+    }
+    PublicKey::try_decode_protobuf(multihash.digest()).is_ok_and(|key| key.verify(bytes, signature))
+}
+
+pub trait History {
+    /// Asks the peer for whatever we missed in the rooms we're in
+    fn request_history(&mut self, peer_id: PeerId);
+    fn serve_history(&mut self, peer_id: PeerId, request: HistoryRequest) -> HistoryResponse;
+    /// Checks and stores what came back, every new message becomes a Message event
+    fn history_received(&mut self, peer_id: PeerId, response: HistoryResponse) -> Option<GossipEvent>;
+}
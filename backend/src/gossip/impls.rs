@@ -1,25 +1,44 @@
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
+use std::fs;
+use std::path::Path;
 
-use libp2p::Multiaddr;
+use libp2p::{Multiaddr, dcutr, identify, kad, ping, request_response};
 use libp2p::gossipsub::{Event, Message, TopicHash};
 use libp2p::mdns::Event::{Discovered, Expired};
-use libp2p::swarm::SwarmEvent;
+use libp2p::core::ConnectedPoint;
+use libp2p::swarm::{ConnectionError, ConnectionId, StreamUpgradeError, SwarmEvent};
 use libp2p::{PeerId, gossipsub::IdentTopic};
 
-use crate::communication::{GetDataViaMessageError, InteractionMessage};
+use crate::board::{BOARD_ROOM, BoardError, BoardOp, SignedOp};
+use crate::geo::Location;
+use crate::rollcall::{CheckIn, CheckInStatus, RollCall, RollCallError, SignedCheckIn};
+use crate::communication::{GetDataViaMessageError, InteractionMessage, VoiceClip, VoiceMessage, is_valid_codec};
 use crate::log;
+use crate::store;
+use crate::wire;
 
+use super::checkin::CheckIns;
+use super::discovery::{Discovery, RoutingQuery, file_key, room_key};
 use super::events::EventHandler;
+use super::history::{self, History, HistoryEntry, HistoryRequest, HistoryResponse, RoomSince};
+use super::replication::{BOARD_SYNC_PAGE, BoardSyncRequest, BoardSyncResponse, Replication};
 use super::message::MessageData;
+use super::peers::now_ms;
 // use super::nonce::Nonce;
 use super::room::{GossipRooms, Room};
+use super::transfer::{
+    ChunkRequest, ChunkResponse, Download, FileManifest, FileTransfer, SharedFile, TransferError, Transfers,
+};
+use super::voice::Voice;
+use super::version::{Feature, LEGACY_SCHEMA_VERSION, SCHEMA_VERSION, parse_protocol_version};
 use super::{Gossip, GossipEvent, MyBehaviourEvent};
 
 impl GossipRooms for Gossip {
     fn get_peer_from_room_name(&self, room_name: &str) -> Option<&PeerId> {
         self.peer_ids
             .iter()
-            .find(|id| id.to_string().contains(&room_name))
+            .find(|id| id.to_string().contains(room_name))
     }
     fn get_topic_from_name(&self, topic_self: &str) -> Option<IdentTopic> {
         // First check if we already have this topic
@@ -50,6 +69,11 @@ fn join_room(&mut self, topic_str: &str) -> Result<(), Box<dyn Error>> {
         self.topics.push((topic_str.to_string(), topic.clone()));
 
         self.swarm.behaviour_mut().gossipsub.subscribe(&topic)?;
+
+        // DM rooms are found through peer routing instead
+        if self.get_room_from_name(topic_str.to_string()).is_public() {
+            self.provide_room(topic_str);
+        }
         Ok(())
     }
     fn leave_room(&mut self, topic_str: &str) -> Result<(), Box<dyn Error>> {
@@ -67,85 +91,829 @@ fn get_room_from_hash(&self, topic: TopicHash) -> Room {
         
         // Instead of panicking, return a default room
         log!("Warning: Topic hash not found, using default room");
-        Room::PublicRoom("general".to_string())
+        Room::PublicRoom(self.default_room().to_string())
     }
     fn get_room_from_name(&self, topic: String) -> Room {
-        if topic.starts_with("public_") {
+        if topic.starts_with("public_") || topic == self.default_room() || topic == BOARD_ROOM {
             return Room::PublicRoom(topic);
         }
         Room::DirectMessage(topic)
     }
 }
 
-impl EventHandler for Gossip {
-    fn new_connections(&mut self, list: Vec<(PeerId, Multiaddr)>) -> Option<GossipEvent> {
-        if list.is_empty() {
+impl Discovery for Gossip {
+    fn find_peer(&mut self, peer_id: PeerId) {
+        if peer_id == self.peer_id() || self.peers.is_connected(&peer_id) {
+            return;
+        }
+        let Some(kademlia) = self.swarm.behaviour_mut().kademlia.as_mut() else {
+            log!("Kademlia is disabled, can't look up {}", peer_id);
+            return;
+        };
+        log!("Looking up {} in the DHT", peer_id);
+        let id = kademlia.get_closest_peers(peer_id);
+        self.routing_queries.insert(id, RoutingQuery::Peer(peer_id));
+    }
+    fn provide_room(&mut self, room_name: &str) {
+        let Some(kademlia) = self.swarm.behaviour_mut().kademlia.as_mut() else {
+            return;
+        };
+        if let Err(e) = kademlia.start_providing(room_key(room_name)) {
+            log!("Error announcing room {}: {:?}", room_name, e);
+        }
+    }
+    fn find_room_providers(&mut self, room_name: &str) {
+        let Some(kademlia) = self.swarm.behaviour_mut().kademlia.as_mut() else {
+            return;
+        };
+        let id = kademlia.get_providers(room_key(room_name));
+        self.routing_queries.insert(id, RoutingQuery::RoomProviders(room_name.to_string()));
+    }
+    fn provide_file(&mut self, root_hash: &str) {
+        let Some(kademlia) = self.swarm.behaviour_mut().kademlia.as_mut() else {
+            return;
+        };
+        if let Err(e) = kademlia.start_providing(file_key(root_hash)) {
+            log!("Error announcing file {}: {:?}", root_hash, e);
+        }
+    }
+    fn find_file_providers(&mut self, root_hash: &str) {
+        let Some(kademlia) = self.swarm.behaviour_mut().kademlia.as_mut() else {
+            return;
+        };
+        let id = kademlia.get_providers(file_key(root_hash));
+        self.routing_queries.insert(id, RoutingQuery::FileProviders(root_hash.to_string()));
+    }
+    fn routing_result(&mut self, id: kad::QueryId, result: kad::QueryResult) {
+        match result {
+            kad::QueryResult::Bootstrap(Ok(kad::BootstrapOk { num_remaining: 0, .. })) => {
+                log!("DHT bootstrap finished, looking for room members");
+                let rooms: Vec<String> = self
+                    .topics
+                    .iter()
+                    .map(|(name, _)| name.clone())
+                    .filter(|name| self.get_room_from_name(name.clone()).is_public())
+                    .collect();
+                for room in rooms {
+                    self.find_room_providers(&room);
+                }
+            }
+            kad::QueryResult::GetClosestPeers(Ok(ok)) => {
+                let Some(RoutingQuery::Peer(target)) = self.routing_queries.get(&id).cloned() else {
+                    return;
+                };
+                match ok.peers.into_iter().find(|peer| peer.peer_id == target) {
+                    Some(peer) => {
+                        log!("Found {} in the DHT, dialing", target);
+                        self.dial_peer(target, peer.addrs);
+                    }
+                    None => log!("Could not find {} in the DHT", target),
+                }
+            }
+            kad::QueryResult::GetProviders(Ok(kad::GetProvidersOk::FoundProviders { providers, .. })) => {
+                match self.routing_queries.get(&id).cloned() {
+                    Some(RoutingQuery::RoomProviders(room_name)) => {
+                        for provider in providers {
+                            if provider == self.peer_id() || self.peers.is_connected(&provider) {
+                                continue;
+                            }
+                            log!("Found {} member {}, dialing", room_name, provider);
+                            // Kademlia hands the swarm the addresses it knows for the provider
+                            self.dial_peer(provider, Vec::new());
+                        }
+                    }
+                    Some(RoutingQuery::FileProviders(root_hash)) => {
+                        let local_peer_id = self.peer_id();
+                        let Some(download) = self.transfers.downloads.get_mut(&root_hash) else {
+                            return;
+                        };
+                        for provider in providers {
+                            if provider != local_peer_id {
+                                log!("Found {} with file {}", provider, root_hash);
+                                download.add_provider(provider);
+                            }
+                        }
+                        self.request_chunks(&root_hash);
+                    }
+                    _ => {}
+                }
+            }
+            other => log!("DHT query {:?} progressed: {:?}", id, other),
+        }
+    }
+}
+
+impl FileTransfer for Gossip {
+    fn store_file(&mut self, name: String, mime: String, data: &[u8]) -> Result<FileManifest, TransferError> {
+        let manifest = FileManifest::build(name, mime, data);
+        let files_dir = self.config.files_dir();
+        fs::create_dir_all(&files_dir)?;
+        let path = Transfers::file_path(&files_dir, &manifest);
+        // Same root hash, same file
+        if !self.transfers.shared.contains_key(&manifest.root_hash) || !path.exists() {
+            fs::write(&path, data)?;
+        }
+        self.transfers.shared.insert(
+            manifest.root_hash.clone(),
+            SharedFile { manifest: manifest.clone(), path },
+        );
+        self.provide_file(&manifest.root_hash);
+        Ok(manifest)
+    }
+    fn share_file(&mut self, path: &Path, mime: String, room_name: &str) -> Result<FileManifest, Box<dyn Error>> {
+        let data = fs::read(path)?;
+        let max = self.config.transfer.max_file_size;
+        if data.len() as u64 > max {
+            return Err(TransferError::TooLarge { size: data.len() as u64, max }.into());
+        }
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_else(|| "file".to_string());
+        // Keep our own copy, the host app might move or delete the original
+        let manifest = self.store_file(name, mime, &data)?;
+
+        self.join_room(room_name)?;
+        let topic = self
+            .get_topic_from_name(room_name)
+            .ok_or("Failed to get topic from room name")?;
+        self.gossip(&InteractionMessage::FileManifest(manifest.clone()), topic)?;
+        log!("Shared {} as {}", manifest.name, manifest.root_hash);
+        Ok(manifest)
+    }
+    fn file_offered(&mut self, peer_id: PeerId, manifest: FileManifest) {
+        if !manifest.is_consistent() {
+            log!("Ignoring inconsistent manifest for {} from {}", manifest.name, peer_id);
+            return;
+        }
+        if let Some(download) = self.transfers.downloads.get_mut(&manifest.root_hash) {
+            download.add_provider(peer_id);
+        }
+        self.transfers.offers.insert(manifest.root_hash.clone(), (manifest, peer_id));
+    }
+    fn fetch_file(&mut self, root_hash: &str) -> Result<(), TransferError> {
+        if let Some(shared) = self.transfers.shared.get(root_hash) {
+            // Nothing to download, but the host app still wants to know where it is
+            let path = shared.path.display().to_string();
+            let event = GossipEvent::FileComplete {
+                root_hash: root_hash.to_string(),
+                name: shared.manifest.name.clone(),
+                mime: shared.manifest.mime.clone(),
+                path: path.clone(),
+            };
+            self.emit(event);
+            self.voice_ready(root_hash, path);
+            return Ok(());
+        }
+        if self.transfers.downloads.contains_key(root_hash) {
+            return Ok(());
+        }
+        let (manifest, announcer) = self
+            .transfers
+            .offers
+            .get(root_hash)
+            .cloned()
+            .ok_or_else(|| TransferError::UnknownFile(root_hash.to_string()))?;
+        let max = self.config.transfer.max_file_size;
+        if manifest.size > max {
+            return Err(TransferError::TooLarge { size: manifest.size, max });
+        }
+
+        self.transfers
+            .downloads
+            .insert(root_hash.to_string(), Download::new(manifest, vec![announcer]));
+        // An empty file has no chunks to wait for, so nothing else would ever finish it
+        if self.transfers.downloads.get(root_hash).is_some_and(Download::is_complete) {
+            if let Some(event) = self.download_progressed(root_hash) {
+                self.emit(event);
+            }
+            return Ok(());
+        }
+        // The announcer is one source, anyone else who has it speeds things up
+        self.find_file_providers(root_hash);
+        self.request_chunks(root_hash);
+        Ok(())
+    }
+    fn request_chunks(&mut self, root_hash: &str) {
+        let max_in_flight = self.config.transfer.max_parallel_requests;
+        let Some(download) = self.transfers.downloads.get_mut(root_hash) else {
+            return;
+        };
+        for (index, peer_id) in download.next_requests(max_in_flight) {
+            let request = ChunkRequest { root_hash: root_hash.to_string(), index: index as u32 };
+            let request_id = self.swarm.behaviour_mut().chunks.send_request(&peer_id, request);
+            download.request_sent(request_id, index, peer_id);
+            self.transfers.requests.insert(request_id, root_hash.to_string());
+        }
+    }
+    fn download_progressed(&mut self, root_hash: &str) -> Option<GossipEvent> {
+        if self.transfers.downloads.get(root_hash)?.is_complete() {
+            let download = self.transfers.downloads.remove(root_hash)?;
+            return Some(match self.save_download(download) {
+                Ok(event) => event,
+                Err(e) => {
+                    self.transfers.voice.remove(root_hash);
+                    GossipEvent::FileFailed { root_hash: root_hash.to_string(), reason: e.to_string() }
+                }
+            });
+        }
+
+        self.request_chunks(root_hash);
+        let download = self.transfers.downloads.get(root_hash)?;
+        if download.is_stuck() {
+            self.transfers.downloads.remove(root_hash);
+            self.transfers.voice.remove(root_hash);
+            return Some(GossipEvent::FileFailed {
+                root_hash: root_hash.to_string(),
+                reason: "No peer could send the missing chunks".to_string(),
+            });
+        }
+        Some(GossipEvent::FileProgress {
+            root_hash: root_hash.to_string(),
+            received_chunks: download.received,
+            total_chunks: download.manifest.chunk_count(),
+        })
+    }
+    fn save_download(&mut self, download: Download) -> Result<GossipEvent, TransferError> {
+        let data = download.assemble()?;
+        let manifest = download.manifest;
+        let files_dir = self.config.files_dir();
+        fs::create_dir_all(&files_dir)?;
+        let path = Transfers::file_path(&files_dir, &manifest);
+        fs::write(&path, data)?;
+        log!("Downloaded {} to {}", manifest.name, path.display());
+
+        // We're a source for it now too
+        self.provide_file(&manifest.root_hash);
+        let event = GossipEvent::FileComplete {
+            root_hash: manifest.root_hash.clone(),
+            name: manifest.name.clone(),
+            mime: manifest.mime.clone(),
+            path: path.display().to_string(),
+        };
+        let root_hash = manifest.root_hash.clone();
+        let path_str = path.display().to_string();
+        self.transfers.shared.insert(root_hash.clone(), SharedFile { manifest, path });
+        self.voice_ready(&root_hash, path_str);
+        Ok(event)
+    }
+}
+
+impl Voice for Gossip {
+    fn send_voice_message(
+        &mut self,
+        room_name: &str,
+        codec: String,
+        duration_ms: u32,
+        clip: &[u8],
+    ) -> Result<(), Box<dyn Error>> {
+        if !is_valid_codec(&codec) {
+            return Err(format!("Bad codec name {:?}, use up to 16 of a-z, 0-9 and -", codec).into());
+        }
+        let timestamp = now_ms();
+        let clip = if clip.len() <= self.config.transfer.inline_voice_max_size {
+            VoiceClip::Inline(clip.to_vec())
+        } else {
+            let max = self.config.transfer.max_file_size;
+            if clip.len() as u64 > max {
+                return Err(TransferError::TooLarge { size: clip.len() as u64, max }.into());
+            }
+            VoiceClip::File(self.store_file(format!("voice.{}", codec), format!("audio/{}", codec), clip)?)
+        };
+        let message = InteractionMessage::VoiceMessage(VoiceMessage { codec, duration_ms, timestamp, clip });
+
+        self.join_room(room_name)?;
+        let topic = self
+            .get_topic_from_name(room_name)
+            .ok_or("Failed to get topic from room name")?;
+        self.gossip(&message, topic)?;
+        Ok(())
+    }
+    fn voice_received(&mut self, data: MessageData, voice: VoiceMessage) -> Result<(), TransferError> {
+        match &voice.clip {
+            VoiceClip::Inline(clip) => {
+                // Files are named after their root hash, so a replayed clip is the same file
+                let name = format!("voice.{}", voice.codec);
+                let manifest = self.store_file(name, format!("audio/{}", voice.codec), clip)?;
+                self.transfers.voice.insert(manifest.root_hash.clone(), (data, voice.clone()));
+                self.fetch_file(&manifest.root_hash)
+            }
+            VoiceClip::File(manifest) => {
+                let root_hash = manifest.root_hash.clone();
+                self.file_offered(data.source, manifest.clone());
+                self.transfers.voice.insert(root_hash.clone(), (data, voice));
+                // Nobody wants to press "download" on a voice message
+                self.fetch_file(&root_hash).inspect_err(|_| {
+                    self.transfers.voice.remove(&root_hash);
+                })
+            }
+        }
+    }
+    fn voice_ready(&mut self, root_hash: &str, path: String) {
+        let Some((data, voice)) = self.transfers.voice.remove(root_hash) else {
+            return;
+        };
+        self.emit(GossipEvent::VoiceMessage {
+            peer: data.source,
+            room: data.room,
+            codec: voice.codec,
+            duration_ms: voice.duration_ms,
+            timestamp: voice.timestamp,
+            path,
+        });
+    }
+}
+
+impl History for Gossip {
+    fn request_history(&mut self, peer_id: PeerId) {
+        let Some(store) = &self.store else {
+            // Nowhere to put it
+            return;
+        };
+        let now = now_ms();
+        let oldest = now.saturating_sub(self.config.history.max_age_secs * 1000);
+        let overlap = self.config.history.overlap_secs * 1000;
+        let rooms = self
+            .topics
+            .iter()
+            .map(|(room, _)| {
+                let last = store.last_received(room).ok().flatten().map(|last| last.min(now).saturating_sub(overlap));
+                RoomSince { room: room.clone(), since: last.unwrap_or(oldest).max(oldest) }
+            })
+            .collect();
+        let request = HistoryRequest { rooms, limit: self.config.history.max_messages };
+        log!("Asking {} for missed messages", peer_id);
+        self.swarm.behaviour_mut().history.send_request(&peer_id, request);
+    }
+    fn serve_history(&mut self, peer_id: PeerId, request: HistoryRequest) -> HistoryResponse {
+        let mut entries = Vec::new();
+        let Some(store) = &self.store else {
+            return HistoryResponse { entries };
+        };
+        let mut limit = request.limit.min(self.config.history.max_messages) as usize;
+        for RoomSince { room, since } in request.rooms {
+            // Only rooms we're in ourselves
+            if limit == 0 || !self.topics.iter().any(|(name, _)| *name == room) {
+                continue;
+            }
+            match store.signed_messages(&room, since, limit) {
+                Ok(messages) => {
+                    limit -= messages.len();
+                    entries.extend(messages.into_iter().filter_map(|(stored, signature, received_at)| {
+                        Some(HistoryEntry {
+                            room: stored.room,
+                            author: stored.author.parse().ok()?,
+                            message: stored.message,
+                            signature,
+                            received_at,
+                        })
+                    }));
+                }
+                Err(e) => log!("Error reading history of {}: {}", room, e),
+            }
+        }
+        log!("Sending {} messages to {}", entries.len(), peer_id);
+        HistoryResponse { entries }
+    }
+    fn history_received(&mut self, peer_id: PeerId, response: HistoryResponse) -> Option<GossipEvent> {
+        let limit = self.config.history.max_messages;
+        // A full page means there's probably more, the rest is asked for from where each room ended
+        let mut next_page: HashMap<String, u64> = HashMap::new();
+        if response.entries.len() >= limit as usize {
+            for entry in response.entries.iter().filter(|entry| entry.received_at > 0) {
+                let since = next_page.entry(entry.room.clone()).or_default();
+                *since = (*since).max(entry.received_at);
+            }
+        }
+        let mut received = 0;
+        for entry in response.entries.into_iter().take(limit as usize) {
+            if !self.topics.iter().any(|(name, _)| *name == entry.room) || !self.is_for_us(&entry.author, &entry.room) {
+                continue;
+            }
+            // Whoever answered could have made it up, only the author's signature counts
+            if !history::verify(&entry.author, &entry.room, &entry.message, &entry.signature) {
+                log!("Bad signature on a message by {} from {}'s history", entry.author, peer_id);
+                continue;
+            }
+            let Some(id) = store::stored_id(&entry.message) else {
+                continue;
+            };
+            if self.store.as_ref().is_some_and(|store| store.contains(&id).unwrap_or(false)) || self.is_retracted(&id) {
+                continue;
+            }
+            let msg_data = MessageData { peer: peer_id, source: entry.author, room: self.get_room_from_name(entry.room) };
+            // Same checks as a live message, new ones go through the gossip loop like any other
+            if let Some(event) = self.accept(msg_data, entry.message, Some(entry.signature)) {
+                received += 1;
+                self.emit(event);
+            }
+        }
+        log!("Got {} missed messages from {}", received, peer_id);
+        next_page.retain(|room, _| self.topics.iter().any(|(name, _)| name == room));
+        if !next_page.is_empty() {
+            let rooms = next_page.into_iter().map(|(room, since)| RoomSince { room, since }).collect();
+            self.swarm.behaviour_mut().history.send_request(&peer_id, HistoryRequest { rooms, limit });
+        }
+        Some(GossipEvent::HistorySynced { peer: peer_id, messages: received })
+    }
+}
+
+impl Replication for Gossip {
+    fn edit_board(&mut self, ops: Vec<BoardOp>) -> Result<(), BoardError> {
+        if !self.whitelist.contains(&self.peer_id()) {
+            return Err(BoardError::Unauthorized);
+        }
+        let ops = ops
+            .into_iter()
+            .map(|op| SignedOp::sign(&self.keypair, op))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut changed = Vec::new();
+        for op in &ops {
+            if let Some(incident) = self.board.apply(op.clone(), &self.whitelist)? {
+                self.save_board_op(op);
+                changed.push(incident);
+            }
+        }
+        // Nobody to send it to is fine, the next sync takes care of it
+        match self.get_topic_from_name(BOARD_ROOM) {
+            Some(topic) => {
+                if let Err(e) = self.gossip(&InteractionMessage::BoardOps(ops), topic) {
+                    log!("Error sending board edit: {:?}", e);
+                }
+            }
+            None => log!("Not in the {} room, board edit stays local for now", BOARD_ROOM),
+        }
+        changed.dedup();
+        if !changed.is_empty() {
+            self.emit(GossipEvent::BoardChanged { incidents: changed });
+        }
+        Ok(())
+    }
+    fn board_ops_received(&mut self, from: PeerId, ops: Vec<SignedOp>) -> Vec<String> {
+        let mut changed = Vec::new();
+        for op in ops {
+            match self.board.apply(op.clone(), &self.whitelist) {
+                Ok(Some(incident)) => {
+                    self.save_board_op(&op);
+                    changed.push(incident);
+                }
+                Ok(None) => {}
+                Err(e) => log!("Ignoring board op by {} from {}: {}", op.author, from, e),
+            }
+        }
+        changed.sort();
+        changed.dedup();
+        changed
+    }
+    fn request_board_sync(&mut self, peer_id: PeerId, after: Option<String>) {
+        // Recomputed for every page, whatever came with the last one is left out then
+        let have: Vec<String> = self.board.ops().iter().map(SignedOp::digest).collect();
+        log!("Asking {} for board ops, we have {}", peer_id, have.len());
+        self.board_synced.insert(peer_id, after.clone());
+        self.swarm.behaviour_mut().board_sync.send_request(&peer_id, BoardSyncRequest { have, after });
+    }
+}
+
+impl CheckIns for Gossip {
+    fn start_roll_call(&mut self, duration_ms: u64) -> Result<String, RollCallError> {
+        if !self.whitelist.contains(&self.peer_id()) {
+            return Err(RollCallError::Unauthorized);
+        }
+        let now = now_ms();
+        let call = RollCall { id: RollCall::new_id(), deadline: now + duration_ms };
+        if !call.is_valid(now) {
+            return Err(RollCallError::Invalid);
+        }
+        let topic = self
+            .get_topic_from_name(self.default_room())
+            .ok_or_else(|| RollCallError::Send(format!("Not in the {} room", self.default_room())))?;
+        self.gossip(&InteractionMessage::RollCall(call.clone()), topic)
+            .map_err(|e| RollCallError::Send(e.to_string()))?;
+        let id = call.id.clone();
+        self.roll_calls.started(call, self.peer_id(), now);
+        Ok(id)
+    }
+    fn check_in(&mut self, roll_call: &str, status: CheckInStatus, location: Option<Location>) -> Result<(), RollCallError> {
+        if !self.roll_calls.contains(roll_call) {
+            return Err(RollCallError::UnknownRollCall);
+        }
+        let check_in = CheckIn { roll_call: roll_call.to_string(), status, location, timestamp: now_ms() };
+        let check_in = SignedCheckIn::sign(&self.keypair, check_in)?;
+        let topic = self
+            .get_topic_from_name(self.default_room())
+            .ok_or_else(|| RollCallError::Send(format!("Not in the {} room", self.default_room())))?;
+        self.gossip(&InteractionMessage::CheckIn(check_in.clone()), topic)
+            .map_err(|e| RollCallError::Send(e.to_string()))?;
+        // A wolf answering its own roll call shows up on its own roster too
+        if let Some(event) = self.check_in_received(self.peer_id(), check_in) {
+            self.emit(event);
+        }
+        Ok(())
+    }
+    fn roll_call_received(&mut self, by: PeerId, call: RollCall) {
+        let id = call.id.clone();
+        if self.roll_calls.started(call, by, now_ms()) {
+            log!("Roll call {} started by {}", id, by);
+        }
+    }
+    fn check_in_received(&mut self, from: PeerId, check_in: SignedCheckIn) -> Option<GossipEvent> {
+        if !self.whitelist.contains(&self.peer_id()) {
             return None;
         }
-        
-        let mut peers = Vec::with_capacity(list.len());
-        
-        // Process each new peer connection
-        for (peer_id, _multiaddr) in list {
-            log!("Adding peer: {}", peer_id);
-            
-            // Avoid re-adding existing peers
-            if self.peer_ids.contains(&peer_id) {
-                log!("Peer already known: {}", peer_id);
+        let (author, roll_call, status) = (check_in.author, check_in.check_in.roll_call.clone(), check_in.check_in.status);
+        match self.roll_calls.checked_in(check_in) {
+            Ok(true) => Some(GossipEvent::CheckedIn { roll_call, peer: author, status }),
+            Ok(false) => None,
+            Err(e) => {
+                log!("Ignoring check-in by {} from {}: {}", author, from, e);
+                None
+            }
+        }
+    }
+}
+
+impl EventHandler for Gossip {
+    fn discovered(&mut self, list: Vec<(PeerId, Multiaddr)>) -> Option<GossipEvent> {
+        let mut new_peers = Vec::new();
+        for (peer_id, multiaddr) in list {
+            // Everyone mDNS finds is a good entry point into the DHT
+            if let Some(kademlia) = self.swarm.behaviour_mut().kademlia.as_mut() {
+                kademlia.add_address(&peer_id, multiaddr.clone());
+            }
+            if self.peers.is_connected(&peer_id) {
                 continue;
             }
-            
-            // Add to gossipsub
-            self.swarm
-                .behaviour_mut()
-                .gossipsub
-                .add_explicit_peer(&peer_id);
-                
-            // Store for our notification
-            peers.push(peer_id.clone());
-            
-            // Add to our tracking set
-            self.peer_ids.insert(peer_id);
+            if self.peers.discovered(peer_id, multiaddr) && !new_peers.contains(&peer_id) {
+                new_peers.push(peer_id);
+            }
         }
-        
-        if peers.is_empty() {
+
+        if new_peers.is_empty() {
             return None;
         }
-        
-        return Some(GossipEvent::NewConnection(peers));
+
+        if let Some(kademlia) = self.swarm.behaviour_mut().kademlia.as_mut()
+            && let Err(e) = kademlia.bootstrap()
+        {
+            log!("Could not bootstrap the DHT: {:?}", e);
+        }
+
+        for peer_id in new_peers {
+            let addresses = self.peers.candidate_addresses(&peer_id);
+            log!("Discovered {} at {:?}, dialing", peer_id, addresses);
+            self.dial_peer(peer_id, addresses.clone());
+            self.emit(GossipEvent::Discovered { peer: peer_id, addresses });
+        }
+        None
+    }
+    fn expired(&mut self, list: Vec<(PeerId, Multiaddr)>) -> Option<GossipEvent> {
+        // Only means mDNS stopped hearing from them, an open connection is still fine
+        for (peer_id, multiaddr) in list {
+            log!("mDNS record for {} at {} expired", peer_id, multiaddr);
+            self.peers.expired(&peer_id, &multiaddr);
+        }
+        None
+    }
+    fn connection_established(&mut self, peer_id: PeerId, connection_id: ConnectionId, endpoint: ConnectedPoint) -> Option<GossipEvent> {
+        let address = endpoint.get_remote_address().clone();
+        log!("Connection {} established with {} via {}", connection_id, peer_id, address);
+        let first_connection = !self.peers.is_connected(&peer_id);
+        let connection = self.peers.connection_established(peer_id, connection_id, &endpoint);
+        if first_connection {
+            self.peer_ids.insert(peer_id);
+            self.emit(GossipEvent::Connected { peer: peer_id, connection });
+        }
+
+        // Only dials we made ourselves are reported
+        let pending = self.dialer.dial_succeeded(&connection_id, peer_id)?;
+        log!("Dial to {} succeeded", pending.address);
+        Some(GossipEvent::DialSuccess { peer: peer_id, address })
     }
-    fn new_disconnections(&mut self, list: Vec<(PeerId, Multiaddr)>) -> Option<GossipEvent> {
-        if list.is_empty() {
+    fn connection_closed(&mut self, peer_id: PeerId, connection_id: ConnectionId, cause: Option<ConnectionError>) -> Option<GossipEvent> {
+        log!("Connection {} with {} closed: {:?}", connection_id, peer_id, cause);
+        self.peers.connection_closed(&peer_id, &connection_id);
+        if self.peers.is_connected(&peer_id) {
+            // Still got another connection, e.g. the relayed one after hole punching
             return None;
         }
-        
-        let mut peers = Vec::with_capacity(list.len());
-        
-        // Process each disconnected peer
-        for (peer_id, _multiaddr) in list {
-            log!("Removing peer: {}", peer_id);
-            
-            // Remove from gossipsub
-            self.swarm
-                .behaviour_mut()
-                .gossipsub
-                .remove_explicit_peer(&peer_id);
-            
-            // Store for our notification
-            peers.push(peer_id.clone());
-            
-            // Remove from our tracking set
-            self.peer_ids.remove(&peer_id);
+        self.peer_ids.remove(&peer_id);
+        // A measurement after reconnecting doesn't answer a request from before
+        self.ping_requests.remove(&peer_id);
+        self.history_synced.remove(&peer_id);
+        self.board_synced.remove(&peer_id);
+        self.dialer.peer_disconnected(&peer_id);
+        Some(GossipEvent::Disconnected { peer: peer_id, reason: cause.as_ref().into() })
+    }
+    fn dial_failed(&mut self, connection_id: ConnectionId, error: String) -> Option<GossipEvent> {
+        let pending = self.dialer.dial_failed(&connection_id)?;
+        log!("Dial to {} failed: {}", pending.address, error);
+        Some(GossipEvent::DialFailure { address: pending.address, error })
+    }
+    fn ping(&mut self, event: ping::Event) -> Option<GossipEvent> {
+        let info = self.peers.get_mut(&event.peer)?;
+        let rtt_ms = match event.result {
+            Ok(rtt) => {
+                info.rtt.record(rtt);
+                info.last_seen = now_ms();
+                info.rtt.last_ms
+            }
+            Err(ping::Failure::Timeout) => {
+                info.rtt.record_timeout();
+                None
+            }
+            Err(e) => {
+                // Unsupported/other errors say nothing about the link quality
+                log!("Ping to {} failed: {}", event.peer, e);
+                return None;
+            }
+        };
+        let stats = info.rtt.clone();
+
+        if !self.ping_requests.remove(&event.peer) {
+            return None;
         }
-        
-        if peers.is_empty() {
+        Some(GossipEvent::PingResult { peer: event.peer, rtt_ms, stats })
+    }
+    fn identify(&mut self, event: identify::Event) -> Option<GossipEvent> {
+        let (peer_id, remote) = match event {
+            identify::Event::Received { peer_id, info, .. } => {
+                log!("Identified {} ({}, {})", peer_id, info.agent_version, info.protocol_version);
+                let remote = parse_protocol_version(&info.protocol_version);
+                // Random libp2p nodes have no business in our DHT
+                if remote.is_some()
+                    && let Some(kademlia) = self.swarm.behaviour_mut().kademlia.as_mut()
+                {
+                    for address in info.listen_addrs.iter() {
+                        kademlia.add_address(&peer_id, address.clone());
+                    }
+                }
+                let protocol_version = info.protocol_version.clone();
+                self.peers.identified(&peer_id, info, remote);
+                // Identify comes again every few minutes, one sync per connection is enough
+                if self.config.history.sync_on_connect
+                    && remote.is_some_and(|remote| Feature::History.supported_by(remote))
+                    && self.history_synced.insert(peer_id)
+                {
+                    self.request_history(peer_id);
+                }
+                if remote.is_some_and(|remote| Feature::Board.supported_by(remote)) && !self.board_synced.contains_key(&peer_id) {
+                    self.request_board_sync(peer_id, None);
+                }
+                (peer_id, (remote, protocol_version))
+            }
+            identify::Event::Error { peer_id, error: StreamUpgradeError::NegotiationFailed, .. } => {
+                // Builds from before identify was added
+                log!("{} doesn't speak identify, assuming schema {}", peer_id, LEGACY_SCHEMA_VERSION);
+                self.peers.identify_unsupported(&peer_id);
+                (peer_id, (Some(LEGACY_SCHEMA_VERSION), String::new()))
+            }
+            identify::Event::Error { peer_id, error, .. } => {
+                log!("Could not identify {}: {}", peer_id, error);
+                return None;
+            }
+            _ => return None,
+        };
+
+        let (remote, protocol_version) = remote;
+        if remote == Some(SCHEMA_VERSION) {
             return None;
         }
-        
-        return Some(GossipEvent::Disconnection(peers));
+        Some(GossipEvent::VersionMismatch { peer: peer_id, local: SCHEMA_VERSION, remote, protocol_version })
+    }
+    fn chunks(&mut self, event: request_response::Event<ChunkRequest, ChunkResponse>) -> Option<GossipEvent> {
+        match event {
+            request_response::Event::Message {
+                peer,
+                message: request_response::Message::Request { request, channel, .. },
+                ..
+            } => {
+                let index = request.index as usize;
+                // Half finished downloads can hand out the chunks they already have
+                let chunk = match self.transfers.shared.get(&request.root_hash) {
+                    Some(shared) => shared.read_chunk(index).ok(),
+                    None => self
+                        .transfers
+                        .downloads
+                        .get(&request.root_hash)
+                        .and_then(|download| download.chunk(index).cloned()),
+                };
+                let response = match chunk {
+                    Some(chunk) => ChunkResponse::Chunk(chunk),
+                    None => ChunkResponse::NotFound,
+                };
+                if self.swarm.behaviour_mut().chunks.send_response(channel, response).is_err() {
+                    log!("Could not send chunk {} of {} to {}", index, request.root_hash, peer);
+                }
+                None
+            }
+            request_response::Event::Message {
+                peer,
+                message: request_response::Message::Response { request_id, response },
+                ..
+            } => {
+                let root_hash = self.transfers.requests.remove(&request_id)?;
+                let download = self.transfers.downloads.get_mut(&root_hash)?;
+                match response {
+                    ChunkResponse::Chunk(chunk) => {
+                        if let Err(e) = download.chunk_received(&request_id, chunk) {
+                            log!("Bad chunk for {} from {}: {}", root_hash, peer, e);
+                        }
+                    }
+                    ChunkResponse::NotFound => download.request_failed(&request_id),
+                }
+                self.download_progressed(&root_hash)
+            }
+            request_response::Event::OutboundFailure { peer, request_id, error, .. } => {
+                log!("Chunk request to {} failed: {}", peer, error);
+                let root_hash = self.transfers.requests.remove(&request_id)?;
+                self.transfers.downloads.get_mut(&root_hash)?.request_failed(&request_id);
+                self.download_progressed(&root_hash)
+            }
+            request_response::Event::InboundFailure { peer, error, .. } => {
+                log!("Chunk request from {} failed: {}", peer, error);
+                None
+            }
+            request_response::Event::ResponseSent { .. } => None,
+        }
+    }
+    fn history(&mut self, event: request_response::Event<HistoryRequest, HistoryResponse>) -> Option<GossipEvent> {
+        match event {
+            request_response::Event::Message {
+                peer,
+                message: request_response::Message::Request { request, channel, .. },
+                ..
+            } => {
+                let response = self.serve_history(peer, request);
+                if self.swarm.behaviour_mut().history.send_response(channel, response).is_err() {
+                    log!("Could not send history to {}", peer);
+                }
+                None
+            }
+            request_response::Event::Message {
+                peer,
+                message: request_response::Message::Response { response, .. },
+                ..
+            } => self.history_received(peer, response),
+            request_response::Event::OutboundFailure { peer, error, .. } => {
+                log!("History request to {} failed: {}", peer, error);
+                // Try again on the next identify
+                self.history_synced.remove(&peer);
+                None
+            }
+            request_response::Event::InboundFailure { peer, error, .. } => {
+                log!("History request from {} failed: {}", peer, error);
+                None
+            }
+            request_response::Event::ResponseSent { .. } => None,
+        }
+    }
+    fn board_sync(&mut self, event: request_response::Event<BoardSyncRequest, BoardSyncResponse>) -> Option<GossipEvent> {
+        let changed = match event {
+            request_response::Event::Message {
+                peer,
+                message: request_response::Message::Request { request, channel, .. },
+                ..
+            } => {
+                // They fetch ours, we fetch theirs with our own request
+                let have: HashSet<String> = request.have.into_iter().collect();
+                let (ops, next) = self.board.missing(&have, request.after.as_deref(), BOARD_SYNC_PAGE);
+                if self.swarm.behaviour_mut().board_sync.send_response(channel, BoardSyncResponse { ops, next }).is_err() {
+                    log!("Could not send board ops to {}", peer);
+                }
+                return None;
+            }
+            request_response::Event::Message {
+                peer,
+                message: request_response::Message::Response { response, .. },
+                ..
+            } => {
+                let changed = self.board_ops_received(peer, response.ops);
+                match response.next {
+                    // Pages only go forward, so a peer can't keep us going in circles
+                    Some(next) if self.board_synced.get(&peer).is_some_and(|after| after.as_ref().is_none_or(|after| next > *after)) => {
+                        self.request_board_sync(peer, Some(next))
+                    }
+                    _ => log!("Board synced with {}", peer),
+                }
+                changed
+            }
+            request_response::Event::OutboundFailure { peer, error, .. } => {
+                log!("Board sync with {} failed: {}", peer, error);
+                // Try again on the next identify
+                self.board_synced.remove(&peer);
+                return None;
+            }
+            request_response::Event::InboundFailure { peer, error, .. } => {
+                log!("Board sync from {} failed: {}", peer, error);
+                return None;
+            }
+            request_response::Event::ResponseSent { .. } => return None,
+        };
+        (!changed.is_empty()).then_some(GossipEvent::BoardChanged { incidents: changed })
     }
     fn message(&mut self, peer_id: PeerId, message: Message) -> Option<GossipEvent> {
         log!("Received message from peer {} on topic {}", peer_id, message.topic);
+        self.peers.seen(&peer_id);
         
         // Safety check for unexpected messages
         if message.data.is_empty() {
@@ -153,12 +921,40 @@ fn message(&mut self, peer_id: PeerId, message: Message) -> Option<GossipEvent>
             return None;
         }
         
-        let is_public_room = message.topic.to_string() == "general";
-        let is_message_by_the_dm_op = peer_id.to_string().contains(&message.topic.to_string());
+        // Only missing if the sender doesn't sign, then it can only be the peer itself
+        let source = message.source.unwrap_or(peer_id);
+        if !self.is_for_us(&source, message.topic.as_str()) {
+            // probably someone asking the OP something, we don't care
+            return None;
+        }
+        let data = message.data;//Nonce::remove_nonce(&message.data);
+        let msg_data = MessageData {
+            peer: peer_id,
+            source,
+            room: self.get_room_from_hash(message.topic),
+        };
+        let interaction = match wire::decode(&data, self.config.gossip.max_decompressed_size) {
+            Ok(interaction) => interaction,
+            Err(e) => {
+                log!("Could not decode message from {}: {}", msg_data.peer, e);
+                return Some(GossipEvent::UndecodableMessage {
+                    peer: msg_data.peer,
+                    schema_version: self.peers.schema_version(&msg_data.peer),
+                    room: msg_data.room,
+                    error: e.to_string(),
+                });
+            }
+        };
+        let signature = wire::signature(&data);
+        self.accept(msg_data, interaction, signature)
+    }
+    fn is_for_us(&self, peer_id: &PeerId, room: &str) -> bool {
+        let is_public_room = room == self.default_room() || room == BOARD_ROOM;
+        let is_message_by_the_dm_op = peer_id.to_string().contains(room);
         let is_message_in_self_dm = self
             .peer_id()
             .to_string()
-            .contains(&message.topic.to_string());
+            .contains(room);
         
         // Messages to ignore
         // Private Room: Other DM's, other's messages
@@ -168,21 +964,51 @@ fn message(&mut self, peer_id: PeerId, message: Message) -> Option<GossipEvent>
         // FTF: Valid
         // FFF: Invalid
         // T__: Valid
-        if !is_public_room && !is_message_by_the_dm_op && !is_message_in_self_dm {
-            // probably someone asking the OP something, we don't care
-            return None;
-        }
-        let data = message.data;//Nonce::remove_nonce(&message.data);
-        let content = String::from_utf8_lossy(&data);
-        let msg_data = MessageData {
-            peer: peer_id,
-            message: content.to_string(),
-            room: self.get_room_from_hash(message.topic),
-        };
-        match InteractionMessage::from_msg(&self.whitelist, &msg_data) {
-            Ok(interaction) => Some(GossipEvent::Message((msg_data, interaction))),
+        is_public_room || is_message_by_the_dm_op || is_message_in_self_dm
+    }
+    fn accept(
+        &mut self,
+        msg_data: MessageData,
+        interaction: InteractionMessage,
+        signature: Option<Vec<u8>>,
+    ) -> Option<GossipEvent> {
+        match InteractionMessage::from_msg(&self.whitelist, &msg_data, interaction) {
+            // Gossipsub relays it no matter what, we just don't bother the user with it
+            Ok(InteractionMessage::GeoAlert(alert)) if !self.in_area(&alert.area) => {
+                log!("Geofenced alert from {} is not for our area", msg_data.peer);
+                None
+            }
+            // Before it gets anywhere near the disk
+            Ok(InteractionMessage::VoiceMessage(_))
+                if !self.transfers.voice_quota.allow(msg_data.source, &self.config.transfer, now_ms()) =>
+            {
+                log!("Too many voice clips from {}, dropping one", msg_data.source);
+                None
+            }
+            Ok(interaction) => {
+                if interaction.is_expired(now_ms()) {
+                    log!("Dropping expired message from {}", msg_data.peer);
+                    return None;
+                }
+                if store::stored_id(&interaction).is_some_and(|id| self.is_retracted(&id)) {
+                    log!("Dropping retracted message from {}", msg_data.peer);
+                    return None;
+                }
+                // Only a signature that checks out is kept, those messages are handed out to peers catching up
+                let room = msg_data.room.name();
+                let signature = signature.filter(|signature| history::verify(&msg_data.source, &room, &interaction, signature));
+                // Stored before caching, so superseding can mark it. broadcast() in lib.rs does the same for ours.
+                self.store_message(msg_data.source, &room, &interaction, false, signature.as_deref());
+                if let Some(message) = interaction.message() {
+                    self.cache_message(msg_data.source, message);
+                }
+                Some(GossipEvent::Message((msg_data, interaction)))
+            }
             Err(e) => {
-                if let GetDataViaMessageError::Unauthorized = e {
+                // Relayers pass on whatever they get, only the author is to blame
+                if let GetDataViaMessageError::Unauthorized = e
+                    && msg_data.source == msg_data.peer
+                {
                     // This peer is doing shit they shouldn't be able to do via the UI, so they are manipulating the system
                     let _ = self.swarm.disconnect_peer_id(msg_data.peer);
                 }
@@ -192,17 +1018,67 @@ fn message(&mut self, peer_id: PeerId, message: Message) -> Option<GossipEvent>
     }
     fn handle(&mut self, event: SwarmEvent<MyBehaviourEvent>) -> Option<GossipEvent> {
         match event {
-            SwarmEvent::Behaviour(MyBehaviourEvent::Mdns(Discovered(list))) => {
-                self.new_connections(list)
+            SwarmEvent::Behaviour(MyBehaviourEvent::Mdns(Discovered(list))) => self.discovered(list),
+            SwarmEvent::Behaviour(MyBehaviourEvent::Mdns(Expired(list))) => self.expired(list),
+            SwarmEvent::Behaviour(MyBehaviourEvent::Kademlia(kad::Event::OutboundQueryProgressed {
+                id,
+                result,
+                step,
+                ..
+            })) => {
+                self.routing_result(id, result);
+                if step.last {
+                    self.routing_queries.remove(&id);
+                }
+                None
             }
-            SwarmEvent::Behaviour(MyBehaviourEvent::Mdns(Expired(list))) => {
-                self.new_disconnections(list)
+            SwarmEvent::Behaviour(MyBehaviourEvent::Kademlia(kad::Event::RoutingUpdated { peer, .. })) => {
+                log!("DHT routing table updated with {}", peer);
+                None
+            }
+            SwarmEvent::Behaviour(MyBehaviourEvent::Identify(event)) => self.identify(event),
+            SwarmEvent::Behaviour(MyBehaviourEvent::RelayClient(event)) => {
+                log!("Relay client: {:?}", event);
+                None
+            }
+            SwarmEvent::Behaviour(MyBehaviourEvent::RelayServer(event)) => {
+                log!("Relay server: {:?}", event);
+                None
+            }
+            SwarmEvent::Behaviour(MyBehaviourEvent::Dcutr(dcutr::Event { remote_peer_id, result })) => {
+                match result {
+                    Ok(_) => log!("Hole punched through to {}, upgrading to a direct link", remote_peer_id),
+                    Err(e) => log!("Hole punching to {} failed, staying relayed: {}", remote_peer_id, e),
+                }
+                None
+            }
+            SwarmEvent::Behaviour(MyBehaviourEvent::Ping(event)) => self.ping(event),
+            SwarmEvent::Behaviour(MyBehaviourEvent::Chunks(event)) => self.chunks(event),
+            SwarmEvent::Behaviour(MyBehaviourEvent::History(event)) => self.history(event),
+            SwarmEvent::Behaviour(MyBehaviourEvent::BoardSync(event)) => self.board_sync(event),
+            SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(Event::Subscribed { peer_id, topic })) => {
+                // IdentTopic hashes are just the topic name
+                self.peers.subscribed(&peer_id, topic.into_string());
+                None
+            }
+            SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(Event::Unsubscribed { peer_id, topic })) => {
+                self.peers.unsubscribed(&peer_id, topic.as_str());
+                None
             }
             SwarmEvent::Behaviour(MyBehaviourEvent::Gossipsub(Event::Message {
                 propagation_source: peer_id,
                 message_id: _,
                 message,
             })) => self.message(peer_id, message),
+            SwarmEvent::ConnectionEstablished { peer_id, connection_id, endpoint, .. } => {
+                self.connection_established(peer_id, connection_id, endpoint)
+            }
+            SwarmEvent::ConnectionClosed { peer_id, connection_id, cause, .. } => {
+                self.connection_closed(peer_id, connection_id, cause)
+            }
+            SwarmEvent::OutgoingConnectionError { connection_id, error, .. } => {
+                self.dial_failed(connection_id, error.to_string())
+            }
             SwarmEvent::NewListenAddr { address, .. } => {
                 log!("Local node is listening on {address}");
                 None
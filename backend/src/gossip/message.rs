@@ -2,26 +2,31 @@
 
 use super::{
     GenerateRoomName, Gossip,
+    discovery::Discovery,
     room::{GossipRooms, Room},
 };
 use crate::{communication::InteractionMessage, log};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct MessageData {
+    // Who we got it from, not necessarily who wrote it
     pub peer: libp2p::PeerId,
-    pub message: String,
+    // Who wrote it, gossipsub signs messages so this can't be faked by whoever relays it
+    pub source: libp2p::PeerId,
     pub room: Room,
 }
 
 impl MessageData {
     pub fn reply_to_peer(&self, gossip: &mut Gossip, message: &InteractionMessage) -> Result<(), Box<dyn std::error::Error>> {
         // Check if we have the peer in our known peers
-        if !gossip.peer_ids.contains(&self.peer) {
-            log!("Warning: Trying to reply to unknown peer {}", self.peer);
+        if !gossip.peer_ids.contains(&self.source) {
+            log!("Warning: Trying to reply to unknown peer {}", self.source);
             // Continue anyway as the peer might be known at a lower level
         }
+        // Make sure we can actually reach them, even if they aren't on our subnet
+        gossip.find_peer(self.source);
         
-        let room_name = self.peer.generate_room_name();
+        let room_name = self.source.generate_room_name();
         gossip.join_room(&room_name)?;
         
         let room_name = match gossip.get_topic_from_name(&room_name) {
@@ -33,35 +38,6 @@ pub fn reply_to_peer(&self, gossip: &mut Gossip, message: &InteractionMessage) -
             Ok(_) => Ok(()),
             Err(e) => {
                 log!("Error sending message response: {:?}", e);
-                
-                // For InsufficientPeers, log but don't treat as fatal
-                if let super::GossipSendError::PublishError(libp2p::gossipsub::PublishError::InsufficientPeers) = e {
-                    log!("Not enough peers connected yet to send reply");
-                    return Ok(());
-                }
-                
-                Err(Box::new(e))
-            }
-        }
-    }
-    
-    pub fn reply_to_room(&self, gossip: &mut Gossip, message: &InteractionMessage) -> Result<(), Box<dyn std::error::Error>> {
-        let topic = match gossip.get_topic_from_name(&self.room.name()) {
-            Some(topic) => topic,
-            None => return Err("Failed to get topic from room name".into()),
-        };
-        
-        match gossip.gossip(message, topic) {
-            Ok(_) => Ok(()),
-            Err(e) => {
-                log!("Error sending message to room: {:?}", e);
-                
-                // For InsufficientPeers, log but don't treat as fatal
-                if let super::GossipSendError::PublishError(libp2p::gossipsub::PublishError::InsufficientPeers) = e {
-                    log!("Not enough peers connected yet to send to room");
-                    return Ok(());
-                }
-                
                 Err(Box::new(e))
             }
         }
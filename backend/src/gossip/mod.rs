@@ -1,44 +1,90 @@
 use libp2p::{
-    PeerId, gossipsub, mdns, noise,
-    swarm::{NetworkBehaviour, SwarmEvent},
+    Multiaddr, PeerId, StreamProtocol, dcutr, gossipsub, identify, identity, kad, mdns, noise, ping, relay, request_response,
+    swarm::{
+        DialError, NetworkBehaviour, SwarmEvent,
+        behaviour::toggle::Toggle,
+        dial_opts::{DialOpts, PeerCondition},
+    },
     tcp, yamux,
 };
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::{HashSet, hash_map::DefaultHasher},
+    collections::{HashMap, HashSet, hash_map::DefaultHasher},
     error::Error,
     fmt::Display,
     hash::{Hash, Hasher},
-    time::Duration,
 };
 // use tokio::io;
 use tracing_subscriber::EnvFilter;
 
-use crate::communication::InteractionMessage;
+use crate::board::{BOARD_PROTOCOL, Board, SignedOp};
+use crate::communication::{InteractionMessage, LocationShare, Message};
+use crate::cap::CapStore;
+use crate::geo::{Area, Location, LocationSharing};
+use crate::config::Config;
+use crate::rollcall::{CheckInStatus, RollCalls};
+use crate::log;
+use crate::store::{self, MessageStore};
+use crate::wire::{self, EncodeOptions, Encoding, WireError};
 
+pub mod dialer;
+pub mod discovery;
 pub mod events;
+pub mod history;
 pub mod impls;
+pub mod cache;
+pub mod checkin;
 pub mod message;
+pub mod outbox;
+pub mod peers;
+pub mod replication;
 // pub mod nonce;
 pub mod room;
+pub mod transfer;
+pub mod version;
+pub mod voice;
 pub mod whitelist;
 
+use dialer::Dialer;
+use discovery::RoutingQuery;
 use events::EventHandler;
 use message::MessageData;
-use room::GossipRooms;
+use cache::{CachedMessage, MAX_CACHED_MESSAGES, MessageCache};
+use outbox::{Outbox, OutboxStats, Queued};
+use peers::{ConnectionInfo, DisconnectReason, PeerBook, RttStats, now_ms};
+use room::{GossipRooms, Room};
+use history::{HistoryRequest, HistoryResponse};
+use replication::{BoardSyncRequest, BoardSyncResponse};
+use transfer::{ChunkRequest, ChunkResponse, Transfers};
+use version::Feature;
 use whitelist::Whitelist;
 
+pub const KADEMLIA_PROTOCOL: &str = "/truman/kad/1.0.0";
+
 #[derive(NetworkBehaviour)]
 pub struct MyBehaviour {
     gossipsub: gossipsub::Behaviour,
-    mdns: mdns::tokio::Behaviour,
+    mdns: Toggle<mdns::tokio::Behaviour>,
+    kademlia: Toggle<kad::Behaviour<kad::store::MemoryStore>>,
+    // Tells peers which addresses they see us on, which is what hole punching needs
+    identify: identify::Behaviour,
+    relay_server: Toggle<relay::Behaviour>,
+    relay_client: Toggle<relay::client::Behaviour>,
+    dcutr: Toggle<dcutr::Behaviour>,
+    ping: ping::Behaviour,
+    // File chunks, see transfer.rs
+    chunks: request_response::cbor::Behaviour<ChunkRequest, ChunkResponse>,
+    // Catching up on missed messages, see history.rs
+    history: request_response::cbor::Behaviour<HistoryRequest, HistoryResponse>,
+    // Fetching missed board ops on connect, see replication.rs
+    board_sync: request_response::cbor::Behaviour<BoardSyncRequest, BoardSyncResponse>,
 }
 
 
 #[derive(Debug)]
 pub enum GossipSendError {
     PublishError(gossipsub::PublishError),
-    SerdeError(serde_json::Error),
+    WireError(WireError),
     Other(String)
 }
 
@@ -46,7 +92,7 @@ impl std::fmt::Display for GossipSendError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             GossipSendError::PublishError(e) => write!(f, "PublishError: {:?}", e),
-            GossipSendError::SerdeError(e) => write!(f, "SerdeError: {}", e),
+            GossipSendError::WireError(e) => write!(f, "WireError: {}", e),
             GossipSendError::Other(s) => write!(f, "Other error: {}", s),
         }
     }
@@ -56,7 +102,7 @@ impl std::error::Error for GossipSendError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
             GossipSendError::PublishError(_) => None, // libp2p's errors don't implement Error
-            GossipSendError::SerdeError(e) => Some(e),
+            GossipSendError::WireError(e) => Some(e),
             GossipSendError::Other(_) => None,
         }
     }
@@ -68,9 +114,9 @@ fn from(err: gossipsub::PublishError) -> Self {
     }
 }
 
-impl From<serde_json::Error> for GossipSendError {
-    fn from(err: serde_json::Error) -> Self {
-        GossipSendError::SerdeError(err)
+impl From<WireError> for GossipSendError {
+    fn from(err: WireError) -> Self {
+        GossipSendError::WireError(err)
     }
 }
 
@@ -88,41 +134,139 @@ fn from(err: &str) -> Self {
 
 pub struct Gossip {
     pub swarm: libp2p::Swarm<MyBehaviour>,
+    // Same key the swarm uses, for signing messages (see history.rs)
+    pub keypair: identity::Keypair,
     pub topics: Vec<(String, gossipsub::IdentTopic)>,
+    // Peers we currently have at least one connection to
     pub peer_ids: HashSet<PeerId>,
     pub whitelist: Whitelist,
+    pub peers: PeerBook,
+    pub dialer: Dialer,
+    pub routing_queries: HashMap<kad::QueryId, RoutingQuery>,
+    // Peers the host app asked to ping, they get a PingResult event on the next measurement
+    pub ping_requests: HashSet<PeerId>,
+    pub transfers: Transfers,
+    pub location: LocationSharing,
+    pub cap_alerts: CapStore,
+    pub outbox: Outbox,
+    pub messages: MessageCache,
+    // None if the database couldn't be opened, we keep going without history then
+    pub store: Option<MessageStore>,
+    // Peers we already asked for history on this connection
+    pub history_synced: HashSet<PeerId>,
+    pub board: Board,
+    // Same for fetching board ops, with where the last page we asked for started
+    pub board_synced: HashMap<PeerId, Option<String>>,
+    pub roll_calls: RollCalls,
+    // Extra events produced while handling a swarm event, drained by the gossip loop
+    pub pending_events: Vec<GossipEvent>,
+    pub config: Config,
+}
+
+/// For the host app's debug screen
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Diagnostics {
+    pub connected_peers: usize,
+    pub outbox: OutboxStats,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
+// Most events are messages anyway, boxing them would only add an allocation each
+#[allow(clippy::large_enum_variant)]
 pub enum GossipEvent {
-    NewConnection(Vec<libp2p::PeerId>),
-    Disconnection(Vec<libp2p::PeerId>),
+    // Seen on the local network through mDNS, not necessarily connected
+    Discovered { peer: PeerId, addresses: Vec<Multiaddr> },
     Message((MessageData, InteractionMessage)),
+    DialSuccess { peer: PeerId, address: Multiaddr },
+    DialFailure { address: Multiaddr, error: String },
+    // Only for the first connection to a peer / after the last one closed
+    Connected { peer: PeerId, connection: ConnectionInfo },
+    Disconnected { peer: PeerId, reason: DisconnectReason },
+    // rtt_ms is None when the ping timed out
+    PingResult { peer: PeerId, rtt_ms: Option<f64>, stats: RttStats },
+    // remote is None if the peer isn't a TruMAN node at all
+    VersionMismatch { peer: PeerId, local: u32, remote: Option<u32>, protocol_version: String },
+    FileProgress { root_hash: String, received_chunks: usize, total_chunks: usize },
+    FileComplete { root_hash: String, name: String, mime: String, path: String },
+    FileFailed { root_hash: String, reason: String },
+    // The message is outdated, the UI should grey it out
+    Superseded { msg_id: String, superseded_by: String },
+    // A wolf took the message back, the UI should remove it
+    Retracted { msg_id: String, reason: String, by: PeerId },
+    // The clip is at path, whether it came inline or in chunks
+    VoiceMessage { peer: PeerId, room: Room, codec: String, duration_ms: u32, timestamp: u64, path: String },
+    // Most likely a message type from a newer build we don't know about
+    UndecodableMessage { peer: PeerId, room: Room, schema_version: Option<u32>, error: String },
+    // Done catching up with a peer, the new messages came as Message events before this
+    HistorySynced { peer: PeerId, messages: usize },
+    // Incidents that were added, removed or edited, get_board() has the new state
+    BoardChanged { incidents: Vec<String> },
+    // Wolves only, get_roster() has the whole picture
+    CheckedIn { roll_call: String, peer: PeerId, status: CheckInStatus },
 }
 impl Display for GossipEvent {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            GossipEvent::NewConnection(peers) => write!(f, "New connection: {:?}", peers),
-            GossipEvent::Disconnection(peers) => write!(f, "Disconnection: {:?}", peers),
+            GossipEvent::Discovered { peer, addresses } => write!(f, "Discovered {} at {:?}", peer, addresses),
             GossipEvent::Message(data) => {
                 write!(
                     f,
-                    "Message from {}({}): {} | {:?}",
-                    data.0.peer, data.0.room, data.0.message, data.1
+                    "Message from {}({}): {:?}",
+                    data.0.peer, data.0.room, data.1
                 )
             }
+            GossipEvent::DialSuccess { peer, address } => write!(f, "Dialed {} at {}", peer, address),
+            GossipEvent::DialFailure { address, error } => write!(f, "Failed to dial {}: {}", address, error),
+            GossipEvent::Connected { peer, connection } => {
+                write!(f, "Connected to {} ({:?}, {:?})", peer, connection.link, connection.transport)
+            }
+            GossipEvent::Disconnected { peer, reason } => write!(f, "Disconnected from {} ({:?})", peer, reason),
+            GossipEvent::PingResult { peer, rtt_ms, .. } => match rtt_ms {
+                Some(rtt_ms) => write!(f, "Ping to {}: {:.1}ms", peer, rtt_ms),
+                None => write!(f, "Ping to {} timed out", peer),
+            },
+            GossipEvent::VersionMismatch { peer, local, remote, protocol_version } => write!(
+                f,
+                "Version mismatch with {}: we speak {}, they speak {:?} ({})",
+                peer, local, remote, protocol_version
+            ),
+            GossipEvent::FileProgress { root_hash, received_chunks, total_chunks } => {
+                write!(f, "File {}: {}/{} chunks", root_hash, received_chunks, total_chunks)
+            }
+            GossipEvent::FileComplete { root_hash, path, .. } => write!(f, "File {} saved to {}", root_hash, path),
+            GossipEvent::FileFailed { root_hash, reason } => write!(f, "File {} failed: {}", root_hash, reason),
+            GossipEvent::Superseded { msg_id, superseded_by } => {
+                write!(f, "Message {} superseded by {}", msg_id, superseded_by)
+            }
+            GossipEvent::Retracted { msg_id, reason, by } => {
+                write!(f, "Message {} retracted by {}: {}", msg_id, by, reason)
+            }
+            GossipEvent::VoiceMessage { peer, room, duration_ms, path, .. } => {
+                write!(f, "Voice message from {}({}): {}ms at {}", peer, room, duration_ms, path)
+            }
+            GossipEvent::UndecodableMessage { peer, room, error, .. } => {
+                write!(f, "Could not decode message from {} in {}: {}", peer, room, error)
+            }
+            GossipEvent::HistorySynced { peer, messages } => {
+                write!(f, "Got {} missed messages from {}", messages, peer)
+            }
+            GossipEvent::BoardChanged { incidents } => write!(f, "Board changed: {:?}", incidents),
+            GossipEvent::CheckedIn { roll_call, peer, status } => {
+                write!(f, "{} checked in for roll call {}: {:?}", peer, roll_call, status)
+            }
         }
     }
 }
 
 impl Gossip {
-    pub fn new(whitelist: &Vec<String>) -> Result<Self, Box<dyn Error>> {
+    pub fn new(whitelist: &Vec<String>, config: Config) -> Result<Self, Box<dyn Error>> {
         let _ = tracing_subscriber::fmt()
             .with_env_filter(EnvFilter::from_default_env())
             .try_init();
 
 
-        let swarm = libp2p::SwarmBuilder::with_new_identity()
+        let keypair = identity::Keypair::generate_ed25519();
+        let swarm = libp2p::SwarmBuilder::with_existing_identity(keypair.clone())
             .with_tokio()
             .with_tcp(
                 tcp::Config::default(),
@@ -130,7 +274,8 @@ pub fn new(whitelist: &Vec<String>) -> Result<Self, Box<dyn Error>> {
                 yamux::Config::default,
             )?
             .with_quic()
-            .with_behaviour(|key| {
+            .with_relay_client(noise::Config::new, yamux::Config::default)?
+            .with_behaviour(|key, relay_client| {
                 // To content-address message, we can take the hash of message and use it as an ID.
                 let message_id_fn = |message: &gossipsub::Message| {
                     let mut s = DefaultHasher::new();
@@ -140,9 +285,10 @@ pub fn new(whitelist: &Vec<String>) -> Result<Self, Box<dyn Error>> {
 
                 // Set a custom gossipsub configuration
                 let gossipsub_config = gossipsub::ConfigBuilder::default()
-                    .heartbeat_interval(Duration::from_secs(10)) // This is set to aid debugging by not cluttering the log space
-                    .validation_mode(gossipsub::ValidationMode::Strict) // This sets the kind of message validation. The default is Strict (enforce message
+                    .heartbeat_interval(config.heartbeat_interval())
+                    .validation_mode(config.gossip.validation_mode.into()) // This sets the kind of message validation. The default is Strict (enforce message
                     // signing)
+                    .max_transmit_size(config.gossip.max_transmit_size)
                     .message_id_fn(message_id_fn) // content-address messages. No two messages of the same content will be propagated.
                     .build()?;
 
@@ -152,22 +298,118 @@ pub fn new(whitelist: &Vec<String>) -> Result<Self, Box<dyn Error>> {
                     gossipsub_config,
                 )?;
 
-                let mdns = mdns::tokio::Behaviour::new(
-                    mdns::Config::default(),
-                    key.public().to_peer_id(),
-                )?;
-                Ok(MyBehaviour { gossipsub, mdns })
+                let mdns = if config.features.mdns {
+                    Some(mdns::tokio::Behaviour::new(
+                        config.mdns_config(),
+                        key.public().to_peer_id(),
+                    )?)
+                } else {
+                    None
+                };
+
+                let kademlia = if config.features.kademlia {
+                    let peer_id = key.public().to_peer_id();
+                    let mut kademlia = kad::Behaviour::with_config(
+                        peer_id,
+                        kad::store::MemoryStore::new(peer_id),
+                        kad::Config::new(StreamProtocol::new(KADEMLIA_PROTOCOL)),
+                    );
+                    // Nodes in a mesh rarely have a confirmed external address, but they should still answer queries
+                    kademlia.set_mode(Some(kad::Mode::Server));
+                    Some(kademlia)
+                } else {
+                    None
+                };
+
+                let identify = identify::Behaviour::new(
+                    identify::Config::new(version::protocol_version(), key.public())
+                        .with_agent_version(format!("truman-backend/{}", env!("CARGO_PKG_VERSION"))),
+                );
+
+                let relay_server = config
+                    .features
+                    .relay_server
+                    .then(|| relay::Behaviour::new(key.public().to_peer_id(), relay::Config::default()));
+                let relay_client = config.features.relay_client.then_some(relay_client);
+                let dcutr = config
+                    .features
+                    .hole_punching
+                    .then(|| dcutr::Behaviour::new(key.public().to_peer_id()));
+
+                Ok(MyBehaviour {
+                    gossipsub,
+                    mdns: mdns.into(),
+                    kademlia: kademlia.into(),
+                    identify,
+                    relay_server: relay_server.into(),
+                    relay_client: relay_client.into(),
+                    dcutr: dcutr.into(),
+                    ping: ping::Behaviour::new(config.ping_config()),
+                    chunks: request_response::cbor::Behaviour::new(
+                        [(StreamProtocol::new(transfer::CHUNK_PROTOCOL), request_response::ProtocolSupport::Full)],
+                        request_response::Config::default(),
+                    ),
+                    history: request_response::cbor::Behaviour::new(
+                        [(StreamProtocol::new(history::HISTORY_PROTOCOL), request_response::ProtocolSupport::Full)],
+                        request_response::Config::default(),
+                    ),
+                    board_sync: request_response::cbor::Behaviour::new(
+                        [(StreamProtocol::new(BOARD_PROTOCOL), request_response::ProtocolSupport::Full)],
+                        request_response::Config::default(),
+                    ),
+                })
             })?
             .build();
+        let store = match MessageStore::open(
+            &config.store_path(),
+            config.gossip.compression_threshold,
+            config.gossip.max_decompressed_size,
+        ) {
+            Ok(store) => Some(store),
+            Err(e) => {
+                log!("Error opening message store, history won't be kept: {}", e);
+                None
+            }
+        };
+        let mut board = Board::new();
+        let mut messages = MessageCache::new();
+        if let Some(store) = &store {
+            match store.board_ops() {
+                Ok(ops) => board.load(ops),
+                Err(e) => log!("Error loading the board: {}", e),
+            }
+            // Otherwise nothing from before the restart could be superseded or replied to
+            match store.recent_broadcasts(MAX_CACHED_MESSAGES, now_ms()) {
+                Ok(stored) => messages.load(stored.into_iter().filter_map(CachedMessage::from_stored).collect()),
+                Err(e) => log!("Error loading recent messages: {}", e),
+            }
+        }
         Ok(Self {
             swarm,
+            keypair,
             topics: Vec::new(),
             peer_ids: HashSet::new(),
             whitelist: whitelist.into(),
+            peers: PeerBook::new(),
+            dialer: Dialer::new(&config.network.bootstrap_peers),
+            routing_queries: HashMap::new(),
+            ping_requests: HashSet::new(),
+            transfers: Transfers::new(),
+            location: LocationSharing::new(config.location.share, config.location.precision),
+            cap_alerts: CapStore::new(),
+            outbox: Outbox::new(&config.outbox),
+            messages,
+            store,
+            history_synced: HashSet::new(),
+            board,
+            board_synced: HashMap::new(),
+            roll_calls: RollCalls::new(),
+            pending_events: Vec::new(),
+            config,
         })
     }
     pub fn peer_id(&self) -> PeerId {
-        self.swarm.local_peer_id().clone()
+        *self.swarm.local_peer_id()
     }
     pub fn open_ears(&mut self) -> Result<(), Box<dyn Error>> {
         // Before opening ears, we join a room with the name of our peer id, so that if someone wants to relay a message
@@ -180,20 +422,271 @@ pub fn open_ears(&mut self) -> Result<(), Box<dyn Error>> {
         let last_five_id_char = self.peer_id().generate_room_name();
         self.join_room(&last_five_id_char)?;
 
-        // Listen on all interfaces and whatever port the OS assigns
-        // self.swarm.listen_on("/ip4/0.0.0.0/udp/0/quic-v1".parse()?)?;
-        self.swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
+        // By default: all interfaces and whatever port the OS assigns
+        // QUIC is preferred (faster handshakes on lossy links), TCP is the fallback for when UDP isn't available
+        let transports = self.config.network.transports.clone();
+        let mut listening = false;
+        if transports.quic {
+            match self.config.quic_listen_addr().map(|addr| self.swarm.listen_on(addr)) {
+                Ok(Ok(_)) => listening = true,
+                Ok(Err(e)) => log!("Could not listen on QUIC, falling back to TCP: {:?}", e),
+                Err(e) => log!("Invalid QUIC listen address, falling back to TCP: {:?}", e),
+            }
+        }
+        if transports.tcp {
+            match self.config.tcp_listen_addr().map(|addr| self.swarm.listen_on(addr)) {
+                Ok(Ok(_)) => listening = true,
+                Ok(Err(e)) => log!("Could not listen on TCP: {:?}", e),
+                Err(e) => log!("Invalid TCP listen address: {:?}", e),
+            }
+        }
+        if !listening {
+            return Err("Could not listen on any of the enabled transports".into());
+        }
+
+        // Peers that can't reach us directly (NAT, isolated AP clients) can still come in through a relay
+        for relay_addr in self.config.relay_listen_addrs() {
+            if let Err(e) = self.swarm.listen_on(relay_addr.clone()) {
+                log!("Could not listen through relay {}: {:?}", relay_addr, e);
+            }
+        }
         Ok(())
     }
-    pub fn gossip(
+    pub fn emit(&mut self, event: GossipEvent) {
+        self.pending_events.push(event);
+    }
+    /// RTTs are measured all the time, this just asks for the next measurement to be reported.
+    /// The last one is in get_peer_details() already, reporting it again would pass it off as new.
+    /// Returns false if we aren't connected, there won't be a measurement then.
+    pub fn request_ping(&mut self, peer_id: PeerId) -> bool {
+        if !self.peers.is_connected(&peer_id) {
+            return false;
+        }
+        self.ping_requests.insert(peer_id);
+        true
+    }
+    /// Dials an address by hand, the outcome shows up as a DialSuccess/DialFailure event
+    pub fn dial(&mut self, address: Multiaddr) -> Result<(), DialError> {
+        self.dial_address(address, None)
+    }
+    fn dial_address(&mut self, address: Multiaddr, bootstrap_index: Option<usize>) -> Result<(), DialError> {
+        let opts = DialOpts::from(address.clone());
+        let connection_id = opts.connection_id();
+        match self.swarm.dial(opts) {
+            Ok(_) => {
+                self.dialer.dial_started(connection_id, address, bootstrap_index);
+                Ok(())
+            }
+            Err(e) => {
+                self.dialer.dial_not_started(bootstrap_index);
+                Err(e)
+            }
+        }
+    }
+    /// Dials a peer we found through the DHT, unless we're already connected (or dialing it)
+    pub fn dial_peer(&mut self, peer_id: PeerId, addresses: Vec<Multiaddr>) {
+        let opts = DialOpts::peer_id(peer_id)
+            .condition(PeerCondition::DisconnectedAndNotDialing)
+            .addresses(addresses)
+            .build();
+        if let Err(e) = self.swarm.dial(opts) {
+            log!("Error dialing {}: {:?}", peer_id, e);
+        }
+    }
+    /// Periodic housekeeping, called from the gossip loop
+    pub fn tick(&mut self) {
+        let due = self.dialer.due_bootstrap_dials(|peer_id| self.peers.is_connected(peer_id));
+        for (index, address) in due {
+            log!("Dialing bootstrap peer {}", address);
+            if let Err(e) = self.dial_address(address.clone(), Some(index)) {
+                log!("Error dialing bootstrap peer {}: {:?}", address, e);
+            }
+        }
+
+        if let Some(location) = self.location.beacon_due(self.config.beacon_interval(), self.config.location.grid_size_deg) {
+            self.send_location_beacon(location);
+        }
+
+        let now = now_ms();
+        let expired = self.messages.prune(now);
+        if !expired.is_empty() {
+            log!("{} messages expired", expired.len());
+        }
+        self.cap_alerts.prune(now);
+        self.roll_calls.prune(now);
+        if let Some(store) = &mut self.store {
+            match store.prune(now) {
+                Ok(0) => {}
+                Ok(pruned) => log!("Deleted {} expired messages from the store", pruned),
+                Err(e) => log!("Error pruning message store: {}", e),
+            }
+        }
+
+        // Whatever didn't fit in the budgets earlier, or had nobody to go to
+        self.flush_outbox();
+    }
+    /// Removes a message everywhere we have it, `by` is the wolf that retracted it
+    pub fn retract(&mut self, msg_id: String, reason: String, by: PeerId) {
+        self.messages.retract(&msg_id);
+        self.outbox.remove(&msg_id);
+        // CAP alerts are retracted by their identifier
+        self.cap_alerts.remove(&msg_id);
+        if let Some(store) = &mut self.store
+            && let Err(e) = store.retract(&msg_id)
+        {
+            log!("Error deleting retracted message {} from the store: {}", msg_id, e);
+        }
+        self.emit(GossipEvent::Retracted { msg_id, reason, by });
+    }
+    /// Whether we've seen a retraction for it, also from before a restart
+    pub fn is_retracted(&self, msg_id: &str) -> bool {
+        self.messages.is_retracted(msg_id)
+            || self.store.as_ref().is_some_and(|store| store.is_retracted(msg_id).unwrap_or(false))
+    }
+    /// Remembers a broadcast (ours or someone else's) and marks what it supersedes. Call it after
+    /// the message is stored, otherwise there is no row to mark yet.
+    pub fn cache_message(&mut self, author: PeerId, message: &Message) {
+        let is_wolf = self.whitelist.contains(&author);
+        for supersede in self.messages.insert(author, message.clone(), is_wolf) {
+            if let Some(store) = &mut self.store
+                && let Err(e) = store.superseded(&supersede.msg_id, &supersede.by)
+            {
+                log!("Error marking {} as superseded in the store: {}", supersede.msg_id, e);
+            }
+            self.emit(GossipEvent::Superseded { msg_id: supersede.msg_id, superseded_by: supersede.by });
+        }
+    }
+    /// Writes a message (ours or someone else's) to the store, if it's a kind we keep
+    pub fn store_message(
+        &mut self,
+        author: PeerId,
+        room: &str,
+        message: &InteractionMessage,
+        outgoing: bool,
+        signature: Option<&[u8]>,
+    ) {
+        if let Some(store) = &mut self.store
+            && let Err(e) = store.insert(&author, room, message, outgoing, signature)
+        {
+            log!("Error storing message: {}", e);
+        }
+    }
+    fn save_board_op(&mut self, op: &SignedOp) {
+        if let Some(store) = &mut self.store
+            && let Err(e) = store.save_board_op(op)
+        {
+            log!("Error storing board op: {}", e);
+        }
+    }
+    /// Whether a geofenced alert is meant for us, based on the last location the host app gave us
+    pub fn in_area(&self, area: &Area) -> bool {
+        match &self.location.current {
+            Some(location) => area.contains(location),
+            None => self.config.location.alerts_without_location,
+        }
+    }
+    fn send_location_beacon(&mut self, location: Location) {
+        let Some(topic) = self.get_topic_from_name(self.default_room()) else {
+            return;
+        };
+        let message = InteractionMessage::LocationShare(LocationShare { location, timestamp: now_ms() });
+        // Normal priority, so a beacon nobody could receive is shed long before the next one
+        if let Err(e) = self.gossip(&message, topic) {
+            log!("Error sending location beacon: {:?}", e);
+        }
+    }
+    /// Queues the message in the outbox, it goes out as soon as its priority's budget allows
+    /// (usually right away)
+    pub fn gossip(&mut self, message: &InteractionMessage, topic: gossipsub::IdentTopic) -> Result<(), GossipSendError> {
+        let room_name = topic.to_string();
+        if let Some(feature) = Feature::needed_for(message) {
+            let too_old = self.peers.too_old_for(&room_name, feature);
+            if let Some(peer_id) = too_old.first() {
+                // A direct message is only for them, no point sending what they can't read
+                if !self.get_room_from_name(room_name.clone()).is_public() {
+                    return Err(format!("{} is on a build that can't read {:?} messages", peer_id, feature).into());
+                }
+                // Everyone else in the room still needs it, see version.rs
+                log!("{} peers in {} can't read {:?} messages and will drop this one", too_old.len(), room_name, feature);
+            }
+        }
+        // Only what ends up in the store is worth signing
+        let signature = store::stored_id(message).and_then(|_| history::sign(&self.keypair, &room_name, message));
+        // Stored right away so it shows up even if it takes a while to go out, and deleted
+        // again if it never does
+        self.store_message(self.peer_id(), &room_name, message, true, signature.as_deref());
+        let shed = self.outbox.push(message.clone(), topic, signature);
+        if !shed.is_empty() {
+            log!("Outbox full, shed {} {:?} messages", shed.len(), message.priority());
+            self.forget_unsent(&shed);
+        }
+        self.flush_outbox();
+        Ok(())
+    }
+    /// Takes messages the outbox gave up on out of the store
+    fn forget_unsent(&mut self, shed: &[Queued]) {
+        let Some(store) = &mut self.store else {
+            return;
+        };
+        for id in shed.iter().filter_map(|queued| store::stored_id(&queued.message)) {
+            if let Err(e) = store.delete(&id) {
+                log!("Error deleting unsent message {} from the store: {}", id, e);
+            }
+        }
+    }
+    /// Sends as much as the budgets allow, highest priority first
+    pub fn flush_outbox(&mut self) {
+        let shed = self.outbox.shed_stale(now_ms());
+        if !shed.is_empty() {
+            log!("Shed {} stale messages from the outbox", shed.len());
+            self.forget_unsent(&shed);
+        }
+        let mut blocked = HashSet::new();
+        while let Some((lane, queued)) = self.outbox.next(&blocked) {
+            match self.publish(&queued.message, queued.topic.clone(), queued.signature.as_deref()) {
+                Ok(_) => self.outbox.sent(lane),
+                Err(GossipSendError::PublishError(gossipsub::PublishError::InsufficientPeers)) => {
+                    // Try again next tick, the rest of that room waits behind it
+                    blocked.insert(queued.topic.to_string());
+                    self.outbox.retry(lane, queued);
+                }
+                Err(e) => {
+                    log!("Dropping queued message: {:?}", e);
+                    self.outbox.dropped(lane);
+                    self.forget_unsent(std::slice::from_ref(&queued));
+                }
+            }
+        }
+    }
+    fn publish(
         &mut self,
         message: &InteractionMessage,
         topic: gossipsub::IdentTopic,
+        signature: Option<&[u8]>,
     ) -> Result<gossipsub::MessageId, GossipSendError> {
-        let message_str = serde_json::to_string(message)?;
-        let data = message_str.as_bytes();
+        // Always the envelope, plain JSON has no room for the signature and is only a debug
+        // fallback (the json-wire feature, see wire.rs). Builds from before the envelope can't
+        // read it, compression is still left out while someone in the room can't inflate.
+        let room_name = topic.to_string();
+        let options = EncodeOptions {
+            encoding: Encoding::Binary,
+            compression_threshold: self
+                .peers
+                .room_supports(&room_name, Feature::Compression)
+                .then_some(self.config.gossip.compression_threshold),
+        };
+        let data = wire::encode_signed(message, options, signature)?;
         Ok(self.swarm.behaviour_mut().gossipsub.publish(topic, data)?)
     }
+    pub fn diagnostics(&self) -> Diagnostics {
+        Diagnostics {
+            connected_peers: self.peer_ids.len(),
+            outbox: self.outbox.stats(),
+        }
+    }
+    pub fn default_room(&self) -> &str {
+        &self.config.gossip.default_room
+    }
     pub fn handle_event(&mut self, event: SwarmEvent<MyBehaviourEvent>) -> Option<GossipEvent> {
         EventHandler::handle(self, event)
     }
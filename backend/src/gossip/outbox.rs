@@ -0,0 +1,364 @@
+use libp2p::gossipsub::IdentTopic;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashSet, VecDeque},
+    time::{Duration, Instant},
+};
+
+use crate::communication::{InteractionMessage, Tag};
+use crate::config::OutboxConfig;
+
+// Everything we publish goes through here instead of straight to gossipsub, so a pile of
+// chat messages can't hold up an evacuation order:
+// - one queue per Tag, Critical is always sent before High, High before Normal
+// - each queue has its own token bucket (messages per second), so no priority can flood the mesh
+// - messages that can't go out yet (InsufficientPeers) stay at the front of their queue, and
+//   the rest of that room waits with them until the next flush. Other rooms carry on.
+// - under load Normal traffic is shed: oldest first when the queue is full, and anything
+//   that waited too long, stale chat isn't worth sending anymore
+// - Critical and High wait a lot longer (max_age_secs), but not forever either
+// - expired messages are dropped whatever their priority
+// Whatever is shed is handed back, it was stored when it was queued and shouldn't look sent.
+
+pub struct Queued {
+    pub message: InteractionMessage,
+    pub topic: IdentTopic,
+    // See history.rs
+    pub signature: Option<Vec<u8>>,
+    queued_at: Instant,
+}
+
+struct TokenBucket {
+    tokens: f64,
+    capacity: f64,
+    per_sec: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(per_sec: u32) -> Self {
+        let per_sec = per_sec as f64;
+        Self {
+            tokens: per_sec,
+            // A second's worth of burst
+            capacity: per_sec,
+            per_sec,
+            last_refill: Instant::now(),
+        }
+    }
+    fn take(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_sec).min(self.capacity);
+        self.last_refill = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+    fn give_back(&mut self) {
+        self.tokens = (self.tokens + 1.0).min(self.capacity);
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct QueueStats {
+    pub depth: usize,
+    pub sent: u64,
+    // Dropped because the queue was full, too old or couldn't be encoded
+    pub shed: u64,
+    // Put back because there was nobody to send to yet
+    pub retries: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct OutboxStats {
+    pub critical: QueueStats,
+    pub high: QueueStats,
+    pub normal: QueueStats,
+}
+
+struct Lane {
+    queue: VecDeque<Queued>,
+    bucket: TokenBucket,
+    max_depth: usize,
+    max_age: Duration,
+    stats: QueueStats,
+}
+
+impl Lane {
+    fn new(per_sec: u32, max_depth: usize, max_age: Duration) -> Self {
+        Self {
+            queue: VecDeque::new(),
+            bucket: TokenBucket::new(per_sec),
+            max_depth,
+            max_age,
+            stats: QueueStats::default(),
+        }
+    }
+    fn stats(&self) -> QueueStats {
+        QueueStats { depth: self.queue.len(), ..self.stats.clone() }
+    }
+}
+
+pub struct Outbox {
+    // Indexed by Outbox::lane()
+    lanes: [Lane; 3],
+}
+
+impl Outbox {
+    pub fn new(config: &OutboxConfig) -> Self {
+        Self {
+            lanes: [
+                Lane::new(config.critical_per_sec, config.max_queue_depth, Duration::from_secs(config.max_age_secs)),
+                Lane::new(config.high_per_sec, config.max_queue_depth, Duration::from_secs(config.max_age_secs)),
+                Lane::new(
+                    config.normal_per_sec,
+                    config.normal_queue_depth,
+                    Duration::from_secs(config.normal_max_age_secs),
+                ),
+            ],
+        }
+    }
+
+    fn lane(priority: Tag) -> usize {
+        match priority {
+            Tag::Critical => 0,
+            Tag::High => 1,
+            Tag::Normal => 2,
+        }
+    }
+
+    /// Returns the messages that had to be dropped to make room
+    pub fn push(&mut self, message: InteractionMessage, topic: IdentTopic, signature: Option<Vec<u8>>) -> Vec<Queued> {
+        let lane = &mut self.lanes[Self::lane(message.priority())];
+        let excess = (lane.queue.len() + 1).saturating_sub(lane.max_depth);
+        let shed: Vec<Queued> = lane.queue.drain(..excess.min(lane.queue.len())).collect();
+        lane.stats.shed += shed.len() as u64;
+        lane.queue.push_back(Queued { message, topic, signature, queued_at: Instant::now() });
+        shed
+    }
+
+    /// Drops expired messages and ones that waited longer than their lane allows, and returns them
+    pub fn shed_stale(&mut self, now_ms: u64) -> Vec<Queued> {
+        let mut shed = Vec::new();
+        for lane in &mut self.lanes {
+            let max_age = lane.max_age;
+            let (keep, dropped): (VecDeque<Queued>, VecDeque<Queued>) = lane.queue.drain(..).partition(|queued| {
+                !queued.message.is_expired(now_ms) && queued.queued_at.elapsed() <= max_age
+            });
+            lane.queue = keep;
+            lane.stats.shed += dropped.len() as u64;
+            shed.extend(dropped);
+        }
+        shed
+    }
+
+    /// Next message to send, highest priority first, as far as the budgets allow.
+    /// Messages for topics in `blocked` are skipped (nobody to send to this round), so they
+    /// keep their place in the queue.
+    pub fn next(&mut self, blocked: &HashSet<String>) -> Option<(usize, Queued)> {
+        for (index, lane) in self.lanes.iter_mut().enumerate() {
+            let Some(position) = lane.queue.iter().position(|queued| !blocked.contains(&queued.topic.to_string())) else {
+                continue;
+            };
+            if !lane.bucket.take() {
+                continue;
+            }
+            return lane.queue.remove(position).map(|queued| (index, queued));
+        }
+        None
+    }
+
+    /// Takes a retracted message out of the queues before it goes out
+    pub fn remove(&mut self, msg_id: &str) {
+        for lane in &mut self.lanes {
+            lane.queue.retain(|queued| queued.message.message().is_none_or(|m| m.id != msg_id));
+        }
+    }
+
+    pub fn sent(&mut self, lane: usize) {
+        self.lanes[lane].stats.sent += 1;
+    }
+
+    pub fn dropped(&mut self, lane: usize) {
+        self.lanes[lane].stats.shed += 1;
+    }
+
+    /// Puts the message back at the front, it's still the next one to go out for its topic
+    pub fn retry(&mut self, lane: usize, queued: Queued) {
+        let lane = &mut self.lanes[lane];
+        lane.bucket.give_back();
+        lane.stats.retries += 1;
+        lane.queue.push_front(queued);
+    }
+
+    pub fn stats(&self) -> OutboxStats {
+        OutboxStats {
+            critical: self.lanes[0].stats(),
+            high: self.lanes[1].stats(),
+            normal: self.lanes[2].stats(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::communication::Message;
+
+    fn config() -> OutboxConfig {
+        OutboxConfig {
+            critical_per_sec: 2,
+            high_per_sec: 2,
+            normal_per_sec: 2,
+            max_queue_depth: 10,
+            max_age_secs: 600,
+            normal_queue_depth: 3,
+            normal_max_age_secs: 60,
+        }
+    }
+
+    fn message(text: &str, tags: Tag) -> InteractionMessage {
+        InteractionMessage::Message(Message {
+            message: text.to_string(),
+            tags,
+            timestamp: 1_716_594_540_000,
+            location: None,
+            id: text.to_string(),
+            expires_at: None,
+            supersedes: None,
+            reply_to: None,
+        })
+    }
+
+    fn push(outbox: &mut Outbox, text: &str, tags: Tag, room: &str) -> Vec<Queued> {
+        outbox.push(message(text, tags), IdentTopic::new(room), None)
+    }
+
+    fn text_of(queued: &Queued) -> &str {
+        &queued.message.message().unwrap().message
+    }
+
+    /// Everything next() hands out right now, by text
+    fn drain(outbox: &mut Outbox, blocked: &HashSet<String>) -> Vec<String> {
+        let mut sent = Vec::new();
+        while let Some((lane, queued)) = outbox.next(blocked) {
+            outbox.sent(lane);
+            sent.push(text_of(&queued).to_string());
+        }
+        sent
+    }
+
+    #[test]
+    fn token_bucket_allows_a_second_worth_then_refills() {
+        let mut bucket = TokenBucket::new(3);
+        assert!(bucket.take() && bucket.take() && bucket.take());
+        assert!(!bucket.take());
+
+        bucket.give_back();
+        assert!(bucket.take());
+        assert!(!bucket.take());
+
+        // Half a second later there's one and a half tokens, but never more than capacity
+        bucket.last_refill -= Duration::from_millis(500);
+        assert!(bucket.take());
+        assert!(!bucket.take());
+        bucket.last_refill -= Duration::from_secs(10);
+        assert!(bucket.take() && bucket.take() && bucket.take());
+        assert!(!bucket.take());
+    }
+
+    #[test]
+    fn higher_priority_goes_first() {
+        let mut outbox = Outbox::new(&config());
+        push(&mut outbox, "normal", Tag::Normal, "room");
+        push(&mut outbox, "high", Tag::High, "room");
+        push(&mut outbox, "critical", Tag::Critical, "room");
+        push(&mut outbox, "critical 2", Tag::Critical, "room");
+        assert_eq!(drain(&mut outbox, &HashSet::new()), ["critical", "critical 2", "high", "normal"]);
+        assert_eq!(outbox.stats().critical.sent, 2);
+    }
+
+    #[test]
+    fn each_priority_has_its_own_budget() {
+        let mut outbox = Outbox::new(&config());
+        for i in 0..3 {
+            push(&mut outbox, &format!("critical {}", i), Tag::Critical, "room");
+            push(&mut outbox, &format!("normal {}", i), Tag::Normal, "room");
+        }
+        // Two per second each, a full Critical lane doesn't use up Normal's budget
+        assert_eq!(drain(&mut outbox, &HashSet::new()), ["critical 0", "critical 1", "normal 0", "normal 1"]);
+        let stats = outbox.stats();
+        assert_eq!((stats.critical.depth, stats.normal.depth), (1, 1));
+    }
+
+    #[test]
+    fn full_normal_queue_sheds_the_oldest() {
+        let mut outbox = Outbox::new(&config());
+        for i in 0..3 {
+            assert!(push(&mut outbox, &format!("normal {}", i), Tag::Normal, "room").is_empty());
+        }
+        let shed = push(&mut outbox, "normal 3", Tag::Normal, "room");
+        assert_eq!(shed.iter().map(text_of).collect::<Vec<_>>(), ["normal 0"]);
+        let stats = outbox.stats().normal;
+        assert_eq!((stats.depth, stats.shed), (3, 1));
+    }
+
+    #[test]
+    fn stale_messages_are_shed_per_lane() {
+        let mut outbox = Outbox::new(&config());
+        push(&mut outbox, "normal", Tag::Normal, "room");
+        push(&mut outbox, "critical", Tag::Critical, "room");
+        push(&mut outbox, "old critical", Tag::Critical, "room");
+        let mut expiring = message("expiring", Tag::Critical);
+        if let InteractionMessage::Message(message) = &mut expiring {
+            message.expires_at = Some(1_000);
+        }
+        outbox.push(expiring, IdentTopic::new("room"), None);
+
+        // A couple of minutes is too long for chat, not for an alert
+        let two_minutes_ago = Instant::now() - Duration::from_secs(120);
+        outbox.lanes[2].queue[0].queued_at = two_minutes_ago;
+        outbox.lanes[0].queue[0].queued_at = two_minutes_ago;
+        // Critical gives up eventually too
+        outbox.lanes[0].queue[1].queued_at = Instant::now() - Duration::from_secs(601);
+
+        let mut shed: Vec<_> = outbox.shed_stale(2_000).iter().map(|q| text_of(q).to_string()).collect();
+        shed.sort();
+        assert_eq!(shed, ["expiring", "normal", "old critical"]);
+        assert_eq!(drain(&mut outbox, &HashSet::new()), ["critical"]);
+    }
+
+    #[test]
+    fn retry_keeps_the_place_and_blocks_only_that_room() {
+        let mut outbox = Outbox::new(&config());
+        push(&mut outbox, "a 1", Tag::Critical, "a");
+        push(&mut outbox, "a 2", Tag::Critical, "a");
+        push(&mut outbox, "b 1", Tag::Critical, "b");
+
+        // Nobody in room a yet
+        let (lane, queued) = outbox.next(&HashSet::new()).unwrap();
+        assert_eq!(text_of(&queued), "a 1");
+        let blocked = HashSet::from(["a".to_string()]);
+        outbox.retry(lane, queued);
+        let stats = outbox.stats().critical;
+        assert_eq!((stats.retries, stats.depth), (1, 3));
+
+        // Room b still gets through, and the failed attempt didn't cost a token
+        assert_eq!(drain(&mut outbox, &blocked), ["b 1"]);
+        // Once room a has peers again it picks up where it left off
+        outbox.lanes[0].bucket.last_refill -= Duration::from_secs(1);
+        assert_eq!(drain(&mut outbox, &HashSet::new()), ["a 1", "a 2"]);
+    }
+
+    #[test]
+    fn removed_messages_never_go_out() {
+        let mut outbox = Outbox::new(&config());
+        push(&mut outbox, "keep", Tag::High, "room");
+        push(&mut outbox, "retracted", Tag::High, "room");
+        outbox.remove("retracted");
+        assert_eq!(drain(&mut outbox, &HashSet::new()), ["keep"]);
+    }
+}
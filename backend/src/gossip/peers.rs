@@ -0,0 +1,445 @@
+use libp2p::{
+    Multiaddr, PeerId,
+    core::ConnectedPoint,
+    multiaddr::Protocol,
+    swarm::{ConnectionError, ConnectionId},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeSet, HashMap},
+    time::{Duration, SystemTime},
+};
+
+use super::version::{Feature, LEGACY_SCHEMA_VERSION};
+use crate::communication::LocationShare;
+use super::whitelist::Whitelist;
+
+// Disconnected peers are forgotten after a while, the oldest first if there are too many
+const MAX_DISCONNECTED_PEERS: usize = 256;
+const DISCONNECTED_MAX_AGE_MS: u64 = 3 * 24 * 60 * 60 * 1000;
+
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis() as u64
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Transport {
+    Quic,
+    Tcp,
+    Other,
+}
+
+impl Transport {
+    pub fn from_multiaddr(address: &Multiaddr) -> Self {
+        let mut transport = Transport::Other;
+        for protocol in address.iter() {
+            match protocol {
+                // QUIC runs over UDP, so it wins over anything found before it
+                Protocol::QuicV1 => return Transport::Quic,
+                Protocol::Tcp(_) => transport = Transport::Tcp,
+                _ => {}
+            }
+        }
+        transport
+    }
+}
+
+/// Whether we talk to the peer straight away or through a relay (circuit relay v2)
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum Link {
+    Direct,
+    Relayed,
+}
+
+impl Link {
+    pub fn from_multiaddr(address: &Multiaddr) -> Self {
+        if address.iter().any(|p| matches!(p, Protocol::P2pCircuit)) {
+            Link::Relayed
+        } else {
+            Link::Direct
+        }
+    }
+}
+
+/// Who opened the connection
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    // We dialed them
+    Dialer,
+    // They dialed us
+    Listener,
+}
+
+impl From<&ConnectedPoint> for Direction {
+    fn from(endpoint: &ConnectedPoint) -> Self {
+        if endpoint.is_dialer() {
+            Direction::Dialer
+        } else {
+            Direction::Listener
+        }
+    }
+}
+
+/// Why we lost the last connection to a peer
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub enum DisconnectReason {
+    // Closed on purpose, by us or by them
+    Closed,
+    // Nothing was using the connection anymore
+    KeepAliveTimeout,
+    Error(String),
+}
+
+impl From<Option<&ConnectionError>> for DisconnectReason {
+    fn from(cause: Option<&ConnectionError>) -> Self {
+        match cause {
+            None => DisconnectReason::Closed,
+            Some(ConnectionError::KeepAliveTimeout) => DisconnectReason::KeepAliveTimeout,
+            Some(ConnectionError::IO(e)) => DisconnectReason::Error(e.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Wolf,
+    Sheep,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ConnectionInfo {
+    pub address: Multiaddr,
+    pub transport: Transport,
+    pub link: Link,
+    pub direction: Direction,
+}
+
+impl ConnectionInfo {
+    pub fn new(endpoint: &ConnectedPoint) -> Self {
+        let address = endpoint.get_remote_address().clone();
+        Self {
+            transport: Transport::from_multiaddr(&address),
+            link: Link::from_multiaddr(&address),
+            direction: endpoint.into(),
+            address,
+        }
+    }
+}
+
+/// Round trip times from the libp2p ping protocol, all in milliseconds
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct RttStats {
+    pub last_ms: Option<f64>,
+    pub min_ms: Option<f64>,
+    pub avg_ms: Option<f64>,
+    // Smoothed like RFC 3550 does it for RTP
+    pub jitter_ms: f64,
+    pub replies: u64,
+    pub timeouts: u64,
+    pub loss: f64,
+}
+
+impl RttStats {
+    pub fn record(&mut self, rtt: Duration) {
+        let rtt_ms = rtt.as_secs_f64() * 1000.0;
+        if let Some(last_ms) = self.last_ms {
+            self.jitter_ms += ((rtt_ms - last_ms).abs() - self.jitter_ms) / 16.0;
+        }
+        self.replies += 1;
+        let avg_ms = self.avg_ms.unwrap_or(0.0);
+        self.avg_ms = Some(avg_ms + (rtt_ms - avg_ms) / self.replies as f64);
+        self.min_ms = Some(self.min_ms.map_or(rtt_ms, |min_ms| min_ms.min(rtt_ms)));
+        self.last_ms = Some(rtt_ms);
+        self.update_loss();
+    }
+    pub fn record_timeout(&mut self) {
+        self.timeouts += 1;
+        self.update_loss();
+    }
+    fn update_loss(&mut self) {
+        self.loss = self.timeouts as f64 / (self.replies + self.timeouts) as f64;
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    pub connections: HashMap<ConnectionId, ConnectionInfo>,
+    pub rtt: RttStats,
+    // Unix time in milliseconds, like message timestamps
+    pub first_seen: u64,
+    pub last_seen: u64,
+    // What the peer told us about itself through identify
+    pub agent_version: Option<String>,
+    pub protocol_version: Option<String>,
+    pub listen_addrs: Vec<Multiaddr>,
+    // None until identify told us (or failed to), see version.rs
+    pub schema_version: Option<u32>,
+    // Gossipsub topics the peer is subscribed to
+    pub rooms: BTreeSet<String>,
+    // Last beacon, only if the peer shares its location
+    pub location: Option<LocationShare>,
+}
+
+impl PeerInfo {
+    pub fn new() -> Self {
+        let now = now_ms();
+        Self {
+            connections: HashMap::new(),
+            rtt: RttStats::default(),
+            first_seen: now,
+            last_seen: now,
+            agent_version: None,
+            protocol_version: None,
+            listen_addrs: Vec::new(),
+            schema_version: None,
+            rooms: BTreeSet::new(),
+            location: None,
+        }
+    }
+    pub fn transports(&self) -> Vec<Transport> {
+        let mut transports: Vec<Transport> = self.connections.values().map(|c| c.transport).collect();
+        transports.sort();
+        transports.dedup();
+        transports
+    }
+    pub fn is_connected(&self) -> bool {
+        !self.connections.is_empty()
+    }
+}
+
+/// What the host app gets to see about a peer
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PeerDetails {
+    pub peer_id: PeerId,
+    pub role: Role,
+    pub connected: bool,
+    // Where we are connected to the peer, plus where it says it listens
+    pub addresses: Vec<Multiaddr>,
+    pub transports: Vec<Transport>,
+    pub connections: Vec<ConnectionInfo>,
+    pub first_seen: u64,
+    pub last_seen: u64,
+    pub rtt: RttStats,
+    pub agent_version: Option<String>,
+    pub protocol_version: Option<String>,
+    pub schema_version: Option<u32>,
+    pub rooms: Vec<String>,
+    pub location: Option<LocationShare>,
+}
+
+/// Every peer we have a connection with.
+/// Disconnected peers are kept around for a while so the host app can still show when they
+/// were last seen, see MAX_DISCONNECTED_PEERS and DISCONNECTED_MAX_AGE_MS.
+#[derive(Debug, Default)]
+pub struct PeerBook {
+    pub peers: HashMap<PeerId, PeerInfo>,
+    // Found through mDNS but not connected (yet), a peer being discovered says nothing about
+    // whether we can actually talk to it
+    pub candidates: HashMap<PeerId, Vec<Multiaddr>>,
+}
+
+impl PeerBook {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn connection_established(&mut self, peer_id: PeerId, connection_id: ConnectionId, endpoint: &ConnectedPoint) -> ConnectionInfo {
+        let connection = ConnectionInfo::new(endpoint);
+        self.candidates.remove(&peer_id);
+        if !self.peers.contains_key(&peer_id) {
+            self.forget_disconnected(now_ms());
+        }
+        let info = self.peers.entry(peer_id).or_insert_with(PeerInfo::new);
+        info.connections.insert(connection_id, connection.clone());
+        info.last_seen = now_ms();
+        connection
+    }
+    pub fn connection_closed(&mut self, peer_id: &PeerId, connection_id: &ConnectionId) -> Option<ConnectionInfo> {
+        let info = self.peers.get_mut(peer_id)?;
+        info.last_seen = now_ms();
+        let connection = info.connections.remove(connection_id);
+        if !info.is_connected() {
+            // Nobody is going to tell us when they leave a room now
+            info.rooms.clear();
+        }
+        self.forget_disconnected(now_ms());
+        connection
+    }
+    /// Drops disconnected peers we haven't seen in ages, and the least recently seen ones
+    /// while there are more than MAX_DISCONNECTED_PEERS
+    fn forget_disconnected(&mut self, now_ms: u64) {
+        self.peers
+            .retain(|_, info| info.is_connected() || info.last_seen + DISCONNECTED_MAX_AGE_MS > now_ms);
+        let mut disconnected: Vec<(u64, PeerId)> = self
+            .peers
+            .iter()
+            .filter(|(_, info)| !info.is_connected())
+            .map(|(peer_id, info)| (info.last_seen, *peer_id))
+            .collect();
+        if disconnected.len() <= MAX_DISCONNECTED_PEERS {
+            return;
+        }
+        disconnected.sort();
+        for (_, peer_id) in &disconnected[..disconnected.len() - MAX_DISCONNECTED_PEERS] {
+            self.peers.remove(peer_id);
+        }
+    }
+    /// Returns true if the address wasn't known yet
+    pub fn discovered(&mut self, peer_id: PeerId, address: Multiaddr) -> bool {
+        let addresses = self.candidates.entry(peer_id).or_default();
+        if addresses.contains(&address) {
+            return false;
+        }
+        addresses.push(address);
+        true
+    }
+    pub fn expired(&mut self, peer_id: &PeerId, address: &Multiaddr) {
+        if let Some(addresses) = self.candidates.get_mut(peer_id) {
+            addresses.retain(|a| a != address);
+            if addresses.is_empty() {
+                self.candidates.remove(peer_id);
+            }
+        }
+    }
+    /// Addresses to dial a candidate on, QUIC first since it connects faster
+    pub fn candidate_addresses(&self, peer_id: &PeerId) -> Vec<Multiaddr> {
+        let mut addresses = self.candidates.get(peer_id).cloned().unwrap_or_default();
+        addresses.sort_by_key(Transport::from_multiaddr);
+        addresses
+    }
+    /// Call whenever we hear from the peer
+    pub fn seen(&mut self, peer_id: &PeerId) {
+        if let Some(info) = self.peers.get_mut(peer_id) {
+            info.last_seen = now_ms();
+        }
+    }
+    pub fn identified(&mut self, peer_id: &PeerId, identify: libp2p::identify::Info, schema_version: Option<u32>) {
+        if let Some(info) = self.peers.get_mut(peer_id) {
+            info.agent_version = Some(identify.agent_version);
+            info.protocol_version = Some(identify.protocol_version);
+            info.listen_addrs = identify.listen_addrs;
+            info.schema_version = schema_version;
+            info.last_seen = now_ms();
+        }
+    }
+    /// For peers that don't speak identify at all, i.e. builds from before it was added
+    pub fn identify_unsupported(&mut self, peer_id: &PeerId) {
+        if let Some(info) = self.peers.get_mut(peer_id) {
+            info.schema_version = Some(LEGACY_SCHEMA_VERSION);
+        }
+    }
+    pub fn schema_version(&self, peer_id: &PeerId) -> Option<u32> {
+        self.peers.get(peer_id)?.schema_version
+    }
+    /// False if we don't know yet, better safe than sending something they can't read
+    pub fn peer_supports(&self, peer_id: &PeerId, feature: Feature) -> bool {
+        self.schema_version(peer_id).is_some_and(|version| feature.supported_by(version))
+    }
+    /// Beacons can arrive out of order, keep the newest
+    pub fn located(&mut self, peer_id: &PeerId, share: LocationShare) {
+        if let Some(info) = self.peers.get_mut(peer_id)
+            && info.location.as_ref().is_none_or(|l| l.timestamp < share.timestamp)
+        {
+            info.location = Some(share);
+        }
+    }
+    pub fn subscribed(&mut self, peer_id: &PeerId, room_name: String) {
+        if let Some(info) = self.peers.get_mut(peer_id) {
+            info.rooms.insert(room_name);
+        }
+    }
+    pub fn unsubscribed(&mut self, peer_id: &PeerId, room_name: &str) {
+        if let Some(info) = self.peers.get_mut(peer_id) {
+            info.rooms.remove(room_name);
+        }
+    }
+    pub fn get_mut(&mut self, peer_id: &PeerId) -> Option<&mut PeerInfo> {
+        self.peers.get_mut(peer_id)
+    }
+    pub fn is_connected(&self, peer_id: &PeerId) -> bool {
+        self.peers.get(peer_id).is_some_and(|info| info.is_connected())
+    }
+    /// Connected peers in the room we know are on a build without the feature
+    pub fn too_old_for(&self, room_name: &str, feature: Feature) -> Vec<PeerId> {
+        self.peers
+            .iter()
+            .filter(|(_, info)| info.is_connected() && info.rooms.contains(room_name))
+            .filter(|(_, info)| info.schema_version.is_some_and(|version| !feature.supported_by(version)))
+            .map(|(peer_id, _)| *peer_id)
+            .collect()
+    }
+    /// Whether every connected peer in the room can handle the feature
+    pub fn room_supports(&self, room_name: &str, feature: Feature) -> bool {
+        self.peers
+            .iter()
+            .filter(|(_, info)| info.is_connected() && info.rooms.contains(room_name))
+            .all(|(peer_id, _)| self.peer_supports(peer_id, feature))
+    }
+    pub fn details(&self, whitelist: &Whitelist) -> Vec<PeerDetails> {
+        self.peers
+            .iter()
+            .map(|(peer_id, info)| {
+                let mut addresses: Vec<Multiaddr> = info.connections.values().map(|c| c.address.clone()).collect();
+                for address in &info.listen_addrs {
+                    if !addresses.contains(address) {
+                        addresses.push(address.clone());
+                    }
+                }
+                PeerDetails {
+                    peer_id: *peer_id,
+                    role: if whitelist.contains(peer_id) { Role::Wolf } else { Role::Sheep },
+                    connected: info.is_connected(),
+                    addresses,
+                    transports: info.transports(),
+                    connections: info.connections.values().cloned().collect(),
+                    first_seen: info.first_seen,
+                    last_seen: info.last_seen,
+                    rtt: info.rtt.clone(),
+                    agent_version: info.agent_version.clone(),
+                    protocol_version: info.protocol_version.clone(),
+                    schema_version: info.schema_version,
+                    rooms: info.rooms.iter().cloned().collect(),
+                    location: info.location.clone(),
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn disconnected(book: &mut PeerBook, last_seen: u64) -> PeerId {
+        let peer_id = PeerId::random();
+        let mut info = PeerInfo::new();
+        info.last_seen = last_seen;
+        book.peers.insert(peer_id, info);
+        peer_id
+    }
+
+    #[test]
+    fn old_disconnected_peers_are_forgotten() {
+        let now = 10 * DISCONNECTED_MAX_AGE_MS;
+        let mut book = PeerBook::new();
+        let stale = disconnected(&mut book, now - DISCONNECTED_MAX_AGE_MS);
+        let recent = disconnected(&mut book, now - 1000);
+        book.forget_disconnected(now);
+        assert!(!book.peers.contains_key(&stale));
+        assert!(book.peers.contains_key(&recent));
+    }
+
+    #[test]
+    fn least_recently_seen_go_first() {
+        let now = 10 * DISCONNECTED_MAX_AGE_MS;
+        let mut book = PeerBook::new();
+        let peers: Vec<PeerId> = (0..MAX_DISCONNECTED_PEERS as u64 + 2).map(|i| disconnected(&mut book, now - 1000 + i)).collect();
+        book.forget_disconnected(now);
+        assert_eq!(book.peers.len(), MAX_DISCONNECTED_PEERS);
+        assert!(!book.peers.contains_key(&peers[0]) && !book.peers.contains_key(&peers[1]));
+        assert!(peers[2..].iter().all(|peer_id| book.peers.contains_key(peer_id)));
+    }
+}
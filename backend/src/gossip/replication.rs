@@ -0,0 +1,41 @@
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+
+use crate::board::{BoardError, BoardOp, SignedOp};
+
+// Moving the shared board around, the CRDT itself is in board.rs:
+// - our own edits are signed, applied and sent to the BOARD_ROOM topic right away
+// - when we connect to a peer that has a board, both ask the other for the ops they are
+//   missing, which covers everything either side missed while apart
+//
+// A board can grow past what fits in one request_response message, so a sync starts with
+// the digests of the ops we have (see SignedOp::digest()) and the peer answers with what
+// we don't have, a page at a time. Every page says where the next one starts, we keep
+// asking until there is none.
+
+// Ops are at most a few KB (see board.rs), so a full page stays well below the codec's limit
+pub const BOARD_SYNC_PAGE: usize = 256;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BoardSyncRequest {
+    // Digests of every op we have
+    pub have: Vec<String>,
+    // Where the page starts, None for the first one
+    pub after: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BoardSyncResponse {
+    pub ops: Vec<SignedOp>,
+    // Where the next page starts, None if that was everything
+    pub next: Option<String>,
+}
+
+pub trait Replication {
+    /// Wolves only
+    fn edit_board(&mut self, ops: Vec<BoardOp>) -> Result<(), BoardError>;
+    /// Applies ops from gossip or a sync, returns the incidents that changed
+    fn board_ops_received(&mut self, from: PeerId, ops: Vec<SignedOp>) -> Vec<String>;
+    /// Asks for the page of ops we're missing that starts after `after`
+    fn request_board_sync(&mut self, peer_id: PeerId, after: Option<String>);
+}
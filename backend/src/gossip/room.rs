@@ -30,12 +30,6 @@ pub fn is_public(&self) -> bool {
             Room::DirectMessage(_) => false,
         }
     }
-    pub fn is_direct_message(&self) -> bool {
-        match self {
-            Room::PublicRoom(_) => false,
-            Room::DirectMessage(_) => true,
-        }
-    }
 }
 
 pub trait GossipRooms {
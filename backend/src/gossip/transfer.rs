@@ -0,0 +1,428 @@
+use libp2p::{PeerId, request_response::OutboundRequestId};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Display,
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use super::{GossipEvent, message::MessageData};
+use crate::config::TransferConfig;
+use crate::communication::VoiceMessage;
+
+// Files are too big for gossipsub, so only a manifest goes over gossip:
+// - the file is cut into CHUNK_SIZE chunks, each one hashed with SHA-256
+// - the root hash is the SHA-256 of all chunk hashes one after the other, and identifies the file
+// - whoever wants the file asks peers that have it for the chunks one by one ("/truman/chunk/1.0.0")
+//   and checks every chunk against the manifest, so it doesn't matter who sends them
+// - the announcer has the file, everyone else that has (parts of) it is found through the DHT
+
+pub const CHUNK_PROTOCOL: &str = "/truman/chunk/1.0.0";
+pub const CHUNK_SIZE: usize = 32 * 1024;
+const HASH_SIZE: usize = 32;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileManifest {
+    pub name: String,
+    pub size: u64,
+    pub mime: String,
+    // Hex, this is what the host app refers to the file by
+    pub root_hash: String,
+    pub chunk_size: u32,
+    // All chunk hashes in one go, a Vec<Vec<u8>> would be a lot bigger on the wire
+    #[serde(with = "serde_bytes")]
+    pub chunk_hashes: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChunkRequest {
+    pub root_hash: String,
+    pub index: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ChunkResponse {
+    Chunk(#[serde(with = "serde_bytes")] Vec<u8>),
+    NotFound,
+}
+
+#[derive(Debug)]
+pub enum TransferError {
+    UnknownFile(String),
+    TooLarge { size: u64, max: u64 },
+    BadManifest,
+    Corrupt,
+    Io(std::io::Error),
+}
+
+impl Display for TransferError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransferError::UnknownFile(root_hash) => write!(f, "No manifest for file {}", root_hash),
+            TransferError::TooLarge { size, max } => write!(f, "File is {} bytes, the limit is {}", size, max),
+            TransferError::BadManifest => write!(f, "Manifest doesn't add up"),
+            TransferError::Corrupt => write!(f, "File doesn't match its root hash"),
+            TransferError::Io(e) => write!(f, "IO error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TransferError {}
+
+impl From<std::io::Error> for TransferError {
+    fn from(err: std::io::Error) -> Self {
+        TransferError::Io(err)
+    }
+}
+
+fn sha256(data: &[u8]) -> Vec<u8> {
+    Sha256::digest(data).to_vec()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl FileManifest {
+    pub fn build(name: String, mime: String, data: &[u8]) -> Self {
+        let chunk_hashes: Vec<u8> = data.chunks(CHUNK_SIZE).flat_map(sha256).collect();
+        Self {
+            name,
+            size: data.len() as u64,
+            mime,
+            root_hash: to_hex(&sha256(&chunk_hashes)),
+            chunk_size: CHUNK_SIZE as u32,
+            chunk_hashes,
+        }
+    }
+    pub fn chunk_count(&self) -> usize {
+        self.chunk_hashes.len() / HASH_SIZE
+    }
+    pub fn chunk_hash(&self, index: usize) -> Option<&[u8]> {
+        self.chunk_hashes.get(index * HASH_SIZE..(index + 1) * HASH_SIZE)
+    }
+    /// Whoever sent the manifest could have made anything up, check it before fetching
+    pub fn is_consistent(&self) -> bool {
+        let chunk_size = self.chunk_size as u64;
+        chunk_size > 0
+            && self.chunk_hashes.len().is_multiple_of(HASH_SIZE)
+            && self.chunk_count() as u64 == self.size.div_ceil(chunk_size)
+            && to_hex(&sha256(&self.chunk_hashes)) == self.root_hash
+    }
+    fn chunk_len(&self, index: usize) -> usize {
+        let start = index as u64 * self.chunk_size as u64;
+        (self.size - start).min(self.chunk_size as u64) as usize
+    }
+}
+
+/// A file we have in full, either shared by us or downloaded
+pub struct SharedFile {
+    pub manifest: FileManifest,
+    pub path: PathBuf,
+}
+
+impl SharedFile {
+    pub fn read_chunk(&self, index: usize) -> Result<Vec<u8>, TransferError> {
+        if index >= self.manifest.chunk_count() {
+            return Err(TransferError::BadManifest);
+        }
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(index as u64 * self.manifest.chunk_size as u64))?;
+        let mut chunk = vec![0; self.manifest.chunk_len(index)];
+        file.read_exact(&mut chunk)?;
+        Ok(chunk)
+    }
+}
+
+pub struct Download {
+    pub manifest: FileManifest,
+    chunks: Vec<Option<Vec<u8>>>,
+    pub received: usize,
+    pub providers: Vec<PeerId>,
+    next_provider: usize,
+    in_flight: HashMap<OutboundRequestId, (usize, PeerId)>,
+}
+
+impl Download {
+    pub fn new(manifest: FileManifest, providers: Vec<PeerId>) -> Self {
+        Self {
+            chunks: vec![None; manifest.chunk_count()],
+            manifest,
+            received: 0,
+            providers,
+            next_provider: 0,
+            in_flight: HashMap::new(),
+        }
+    }
+    pub fn add_provider(&mut self, peer_id: PeerId) {
+        if !self.providers.contains(&peer_id) {
+            self.providers.push(peer_id);
+        }
+    }
+    pub fn is_complete(&self) -> bool {
+        self.received == self.chunks.len()
+    }
+    pub fn chunk(&self, index: usize) -> Option<&Vec<u8>> {
+        self.chunks.get(index)?.as_ref()
+    }
+    /// Missing chunks that aren't requested yet, spread over the providers
+    pub fn next_requests(&mut self, max_in_flight: usize) -> Vec<(usize, PeerId)> {
+        let mut requests = Vec::new();
+        if self.providers.is_empty() {
+            return requests;
+        }
+        for index in 0..self.chunks.len() {
+            if self.in_flight.len() + requests.len() >= max_in_flight {
+                break;
+            }
+            let requested = self.in_flight.values().any(|(i, _)| *i == index);
+            if self.chunks[index].is_some() || requested {
+                continue;
+            }
+            let provider = self.providers[self.next_provider % self.providers.len()];
+            self.next_provider += 1;
+            requests.push((index, provider));
+        }
+        requests
+    }
+    pub fn request_sent(&mut self, request_id: OutboundRequestId, index: usize, peer_id: PeerId) {
+        self.in_flight.insert(request_id, (index, peer_id));
+    }
+    /// Checks the chunk against the manifest, a peer sending garbage is dropped as a provider
+    pub fn chunk_received(&mut self, request_id: &OutboundRequestId, chunk: Vec<u8>) -> Result<(), TransferError> {
+        let Some((index, peer_id)) = self.in_flight.remove(request_id) else {
+            return Ok(());
+        };
+        if self.manifest.chunk_hash(index) != Some(sha256(&chunk).as_slice()) {
+            self.providers.retain(|p| *p != peer_id);
+            return Err(TransferError::Corrupt);
+        }
+        if self.chunks[index].is_none() {
+            self.chunks[index] = Some(chunk);
+            self.received += 1;
+        }
+        Ok(())
+    }
+    /// The peer couldn't give us the chunk, try someone else
+    pub fn request_failed(&mut self, request_id: &OutboundRequestId) {
+        if let Some((_, peer_id)) = self.in_flight.remove(request_id) {
+            self.providers.retain(|p| *p != peer_id);
+        }
+    }
+    pub fn is_stuck(&self) -> bool {
+        self.providers.is_empty() && self.in_flight.is_empty() && !self.is_complete()
+    }
+    /// Puts the chunks together and checks the whole thing once more
+    pub fn assemble(&self) -> Result<Vec<u8>, TransferError> {
+        let mut data = Vec::with_capacity(self.manifest.size as usize);
+        let mut chunk_hashes = Vec::with_capacity(self.manifest.chunk_hashes.len());
+        for chunk in &self.chunks {
+            let chunk = chunk.as_ref().ok_or(TransferError::Corrupt)?;
+            chunk_hashes.extend(sha256(chunk));
+            data.extend_from_slice(chunk);
+        }
+        if data.len() as u64 != self.manifest.size || to_hex(&sha256(&chunk_hashes)) != self.manifest.root_hash {
+            return Err(TransferError::Corrupt);
+        }
+        Ok(data)
+    }
+}
+
+/// Everything about files we have, were offered or are downloading
+#[derive(Default)]
+pub struct Transfers {
+    pub shared: HashMap<String, SharedFile>,
+    // Manifests we've seen, with who announced them
+    pub offers: HashMap<String, (FileManifest, PeerId)>,
+    pub downloads: HashMap<String, Download>,
+    // Which download a chunk request belongs to
+    pub requests: HashMap<OutboundRequestId, String>,
+    // Voice clips being downloaded, reported once they're complete
+    pub voice: HashMap<String, (MessageData, VoiceMessage)>,
+    pub voice_quota: VoiceQuota,
+}
+
+/// When recent voice clips came in, so nobody can fill up our disk with them
+#[derive(Default)]
+pub struct VoiceQuota {
+    total: VecDeque<u64>,
+    per_peer: HashMap<PeerId, VecDeque<u64>>,
+}
+
+impl VoiceQuota {
+    /// Counts the clip if the author and everyone together are still under their limits.
+    /// The total matters too, new peer ids don't cost anything.
+    pub fn allow(&mut self, author: PeerId, config: &TransferConfig, now_ms: u64) -> bool {
+        let window_ms = config.voice_window_secs * 1000;
+        let forget_old = |clips: &mut VecDeque<u64>| {
+            while clips.front().is_some_and(|received| received + window_ms <= now_ms) {
+                clips.pop_front();
+            }
+        };
+        forget_old(&mut self.total);
+        self.per_peer.retain(|_, clips| {
+            forget_old(clips);
+            !clips.is_empty()
+        });
+        let from_author = self.per_peer.get(&author).map_or(0, VecDeque::len);
+        if self.total.len() >= config.max_voice_clips || from_author >= config.max_voice_clips_per_peer {
+            return false;
+        }
+        self.total.push_back(now_ms);
+        self.per_peer.entry(author).or_default().push_back(now_ms);
+        true
+    }
+}
+
+impl Transfers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Only keeps the file name, so a manifest can't make us write outside the files directory
+    pub fn file_path(files_dir: &Path, manifest: &FileManifest) -> PathBuf {
+        let name = Path::new(&manifest.name)
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let prefix: String = manifest.root_hash.chars().take(16).collect();
+        files_dir.join(format!("{}_{}", prefix, name))
+    }
+}
+
+pub trait FileTransfer {
+    /// Puts the data into storage and makes it available to others, without announcing it
+    fn store_file(&mut self, name: String, mime: String, data: &[u8]) -> Result<FileManifest, TransferError>;
+    /// Copies the file into storage and announces it in the room
+    fn share_file(&mut self, path: &Path, mime: String, room_name: &str) -> Result<FileManifest, Box<dyn std::error::Error>>;
+    /// Remembers a manifest someone announced, so the host app can fetch it later
+    fn file_offered(&mut self, peer_id: PeerId, manifest: FileManifest);
+    fn fetch_file(&mut self, root_hash: &str) -> Result<(), TransferError>;
+    /// Sends out chunk requests for a download, up to the configured limit
+    fn request_chunks(&mut self, root_hash: &str);
+    /// Call after a chunk came in or a request failed, asks for more or finishes the download
+    fn download_progressed(&mut self, root_hash: &str) -> Option<GossipEvent>;
+    fn save_download(&mut self, download: Download) -> Result<GossipEvent, TransferError>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn manifest(data: &[u8]) -> FileManifest {
+        FileManifest::build("notes.txt".to_string(), "text/plain".to_string(), data)
+    }
+
+    /// A download with every chunk filled in straight from the data
+    fn filled_download(data: &[u8]) -> Download {
+        let mut download = Download::new(manifest(data), vec![PeerId::random()]);
+        for (index, chunk) in data.chunks(CHUNK_SIZE).enumerate() {
+            download.chunks[index] = Some(chunk.to_vec());
+            download.received += 1;
+        }
+        download
+    }
+
+    #[test]
+    fn data_is_cut_into_chunks() {
+        let data = data(2 * CHUNK_SIZE + 10);
+        let manifest = manifest(&data);
+        assert_eq!(manifest.size, data.len() as u64);
+        assert_eq!(manifest.chunk_count(), 3);
+        assert_eq!(manifest.chunk_len(0), CHUNK_SIZE);
+        assert_eq!(manifest.chunk_len(2), 10);
+        for (index, chunk) in data.chunks(CHUNK_SIZE).enumerate() {
+            assert_eq!(manifest.chunk_hash(index), Some(sha256(chunk).as_slice()));
+        }
+        assert_eq!(manifest.chunk_hash(3), None);
+
+        // Exactly one chunk's worth doesn't get an empty chunk after it
+        assert_eq!(self::manifest(&data[..CHUNK_SIZE]).chunk_count(), 1);
+    }
+
+    #[test]
+    fn root_hash_covers_every_chunk() {
+        let data = data(CHUNK_SIZE + 1);
+        let manifest = manifest(&data);
+        let mut chunk_hashes = sha256(&data[..CHUNK_SIZE]);
+        chunk_hashes.extend(sha256(&data[CHUNK_SIZE..]));
+        assert_eq!(manifest.root_hash, to_hex(&sha256(&chunk_hashes)));
+        assert_eq!(manifest.root_hash.len(), 2 * HASH_SIZE);
+
+        let mut changed = data.clone();
+        *changed.last_mut().unwrap() ^= 1;
+        assert_ne!(self::manifest(&changed).root_hash, manifest.root_hash);
+    }
+
+    #[test]
+    fn made_up_manifests_are_inconsistent() {
+        let good = manifest(&data(CHUNK_SIZE + 1));
+        assert!(good.is_consistent());
+        assert!(manifest(&[]).is_consistent());
+
+        let mut bad = good.clone();
+        bad.root_hash = to_hex(&sha256(b"something else"));
+        assert!(!bad.is_consistent());
+
+        let mut bad = good.clone();
+        bad.size = 3 * CHUNK_SIZE as u64;
+        assert!(!bad.is_consistent());
+
+        let mut bad = good.clone();
+        bad.chunk_size = 0;
+        assert!(!bad.is_consistent());
+
+        // Half a hash doesn't count, even with a root hash that matches it
+        let mut bad = good.clone();
+        bad.chunk_hashes.truncate(HASH_SIZE + HASH_SIZE / 2);
+        bad.root_hash = to_hex(&sha256(&bad.chunk_hashes));
+        assert!(!bad.is_consistent());
+    }
+
+    #[test]
+    fn assemble_gives_back_the_data() {
+        let data = data(2 * CHUNK_SIZE + 10);
+        let download = filled_download(&data);
+        assert!(download.is_complete());
+        assert_eq!(download.assemble().unwrap(), data);
+    }
+
+    #[test]
+    fn assemble_refuses_missing_or_wrong_chunks() {
+        let data = data(2 * CHUNK_SIZE + 10);
+
+        let mut download = filled_download(&data);
+        download.chunks[1] = None;
+        download.received -= 1;
+        assert!(!download.is_complete());
+        assert!(matches!(download.assemble(), Err(TransferError::Corrupt)));
+
+        let mut download = filled_download(&data);
+        download.chunks.swap(0, 1);
+        assert!(matches!(download.assemble(), Err(TransferError::Corrupt)));
+    }
+
+    #[test]
+    fn empty_file_is_complete_from_the_start() {
+        let mut download = Download::new(manifest(&[]), vec![PeerId::random()]);
+        assert!(download.is_complete());
+        assert!(!download.is_stuck());
+        assert!(download.next_requests(4).is_empty());
+        assert_eq!(download.assemble().unwrap(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn file_path_stays_in_the_files_directory() {
+        let mut manifest = manifest(b"hi");
+        manifest.name = "../../etc/passwd".to_string();
+        let path = Transfers::file_path(Path::new("/files"), &manifest);
+        assert_eq!(path.parent(), Some(Path::new("/files")));
+        assert!(path.file_name().unwrap().to_string_lossy().ends_with("_passwd"));
+    }
+}
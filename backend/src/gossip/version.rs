@@ -0,0 +1,105 @@
+use serde::{Deserialize, Serialize};
+
+use crate::communication::InteractionMessage;
+
+// Old and new builds live side by side in the field, so every node tells the others
+// which message schema it speaks through the identify protocol ("/truman/<schema>.0.0").
+//
+// Schema history:
+// 1 - JSON over gossipsub only, no identify (anything that doesn't identify is assumed to be this)
+// 2 - identify, RTT measured with the libp2p ping protocol
+// 3 - binary wire envelope with a CBOR body (see wire.rs)
+// 4 - deflate compressed bodies
+// 5 - file manifests and the chunk protocol (see transfer.rs)
+// 6 - voice messages
+// 7 - locations on messages and LocationShare beacons
+// 8 - geofenced alerts
+// 9 - CAP alerts (see cap.rs)
+// 10 - message ids, expiry and superseding
+// 11 - retractions
+// 12 - threaded replies
+// 13 - signed messages and history sync (see history.rs)
+// 14 - shared board (see board.rs)
+// 15 - roll calls and check-ins (see rollcall.rs)
+pub const SCHEMA_VERSION: u32 = 15;
+pub const LEGACY_SCHEMA_VERSION: u32 = 1;
+
+const PROTOCOL_PREFIX: &str = "/truman/";
+
+pub fn protocol_version() -> String {
+    format!("{}{}.0.0", PROTOCOL_PREFIX, SCHEMA_VERSION)
+}
+
+/// Gets the schema version out of an identify protocol version, None if it isn't a TruMAN node
+pub fn parse_protocol_version(protocol_version: &str) -> Option<u32> {
+    let version = protocol_version.strip_prefix(PROTOCOL_PREFIX)?;
+    version.split('.').next()?.parse().ok()
+}
+
+// New message kinds can't be downgraded, a roll call has no older form. They still go out to
+// public rooms with old peers in them, since everyone else there needs them: old builds fail
+// to decode the unknown variant and drop it (an UndecodableMessage event from schema 3 on,
+// a log line before that), nobody acts on half a message. New fields on existing kinds
+// (ids, expiry, replies, locations on messages) are skipped by old decoders, the message
+// just loses that part. Direct messages are only for one peer, those are refused instead if
+// we know the peer can't read them, see Feature::needed_for().
+
+/// Things a sender has to check the other side understands before using them
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum Feature {
+    // Otherwise we fall back to InteractionMessage::Ping over gossip
+    RttPing,
+    // Otherwise they can't read anything we send, unless we are built with json-wire (see wire.rs)
+    BinaryWire,
+    // Otherwise big messages go out uncompressed
+    Compression,
+    // Otherwise we don't ask for missed messages
+    History,
+    // Otherwise we don't fetch board ops on connect
+    Board,
+    // Otherwise they can't answer a roll call, the roster says so. Also roll calls and check-ins themselves.
+    RollCall,
+    // The message kinds below are dropped by builds that don't have them, see above
+    Files,
+    Voice,
+    LocationShare,
+    GeoAlert,
+    CapAlert,
+    Retract,
+}
+
+impl Feature {
+    pub fn min_schema_version(&self) -> u32 {
+        match self {
+            Feature::RttPing => 2,
+            Feature::BinaryWire => 3,
+            Feature::Compression => 4,
+            Feature::History => 13,
+            Feature::Board => 14,
+            Feature::RollCall => 15,
+            Feature::Files => 5,
+            Feature::Voice => 6,
+            Feature::LocationShare => 7,
+            Feature::GeoAlert => 8,
+            Feature::CapAlert => 9,
+            Feature::Retract => 11,
+        }
+    }
+    /// What the receiver needs to be able to decode the message at all
+    pub fn needed_for(message: &InteractionMessage) -> Option<Feature> {
+        match message {
+            InteractionMessage::FileManifest(_) => Some(Feature::Files),
+            InteractionMessage::VoiceMessage(_) => Some(Feature::Voice),
+            InteractionMessage::LocationShare(_) => Some(Feature::LocationShare),
+            InteractionMessage::GeoAlert(_) => Some(Feature::GeoAlert),
+            InteractionMessage::CapAlert(_) => Some(Feature::CapAlert),
+            InteractionMessage::Retract(_) => Some(Feature::Retract),
+            InteractionMessage::BoardOps(_) => Some(Feature::Board),
+            InteractionMessage::RollCall(_) | InteractionMessage::CheckIn(_) => Some(Feature::RollCall),
+            _ => None,
+        }
+    }
+    pub fn supported_by(&self, schema_version: u32) -> bool {
+        schema_version >= self.min_schema_version()
+    }
+}
@@ -0,0 +1,26 @@
+use crate::communication::VoiceMessage;
+
+use super::{message::MessageData, transfer::TransferError};
+
+// Push-to-talk clips for people who can't type. The host app records and encodes
+// (e.g. Opus, AAC or AMR, see is_valid_codec), we only move the bytes around:
+// - small clips go inline in the VoiceMessage
+// - bigger ones are stored like a shared file and the VoiceMessage carries the manifest,
+//   receivers fetch it through the chunk protocol straight away
+// Either way the host app gets a VoiceMessage event once the clip is on disk.
+// Anyone can send clips, so received ones count against a quota (see VoiceQuota) and are
+// named after their content, the same clip sent twice is only stored once.
+
+pub trait Voice {
+    fn send_voice_message(
+        &mut self,
+        room_name: &str,
+        codec: String,
+        duration_ms: u32,
+        clip: &[u8],
+    ) -> Result<(), Box<dyn std::error::Error>>;
+    /// Stores inline clips right away, fetches the others
+    fn voice_received(&mut self, data: MessageData, voice: VoiceMessage) -> Result<(), TransferError>;
+    /// Raises the VoiceMessage event if the file was a clip we were waiting for
+    fn voice_ready(&mut self, root_hash: &str, path: String);
+}
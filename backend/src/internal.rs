@@ -1,6 +1,9 @@
-use crate::gossip::{Gossip, MyBehaviourEvent, GossipEvent, room::GossipRooms};
-use crate::communication::{InteractionMessage};
-use crate::{log, FAIL, SUCCESS};
+use crate::gossip::{Gossip, MyBehaviourEvent, GossipEvent, checkin::CheckIns, replication::Replication, room::GossipRooms, transfer::FileTransfer, voice::Voice};
+use crate::board::BOARD_ROOM;
+use crate::cap::CapAlert;
+use crate::communication::{InteractionMessage, Tag};
+use crate::config::Config;
+use crate::log;
 use libp2p::PeerId;
 use libp2p::{swarm::SwarmEvent};
 use std::time::SystemTime;
@@ -8,38 +11,59 @@
 use tokio::sync::MutexGuard;
 use futures::future::FutureExt;
 
-pub fn gossip_init(guard: &mut MutexGuard<'_, Option<Gossip>>, whitelist: Vec<String>) -> i32 {
-    let failable_gossip = Gossip::new(&whitelist);
-    if let Err(e) = failable_gossip {
-        log!("Error initializing gossip: {:?}", e);
-        return FAIL;
-    }
-    let mut gossip = failable_gossip.unwrap();
+pub fn gossip_init(
+    guard: &mut MutexGuard<'_, Option<Gossip>>,
+    whitelist: Vec<String>,
+    config: Config,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut gossip = Gossip::new(&whitelist, config)?;
     
-    // Join general chat room
-    if let Err(e) = gossip.join_room("general") {
-        log!("Error joining general room: {:?}", e);
-        return FAIL;
+    // Join the default (general) chat room
+    let default_room = gossip.default_room().to_string();
+    if let Err(e) = gossip.join_room(&default_room) {
+        log!("Error joining {} room: {:?}", default_room, e);
+        return Err(e);
+    }
+
+    // Board edits have a topic of their own, see board.rs
+    if let Err(e) = gossip.join_room(BOARD_ROOM) {
+        log!("Error joining {} room: {:?}", BOARD_ROOM, e);
     }
     
     // Start listening for connections
     if let Err(e) = gossip.open_ears() {
         log!("Error opening ears: {:?}", e);
-        return FAIL;
+        return Err(e);
     }
 
     **guard = Some(gossip);
-    SUCCESS
+    Ok(())
 }
 
+// Cap so one busy loop iteration can't hold the lock forever
+const MAX_EVENTS_PER_LOOP: usize = 64;
+
 pub fn gossip_loop(gossip: &mut Gossip, events: &mut Vec<GossipEvent>) {
-    let Some(event) = gossip.swarm.select_next_some().now_or_never() else {
-        return;
-    };
-    
-    match handle_event(gossip, events, event) {
-        Ok(_) => {},
-        Err(e) => log!("Error handling event: {:?}", e),
+    gossip.tick();
+
+    // Drain everything that is ready instead of one event per loop iteration
+    for _ in 0..MAX_EVENTS_PER_LOOP {
+        let Some(event) = gossip.swarm.select_next_some().now_or_never() else {
+            return;
+        };
+        
+        match handle_event(gossip, events, event) {
+            Ok(_) => {},
+            Err(e) => log!("Error handling event: {:?}", e),
+        }
+        // Extra events get the same treatment, e.g. messages that came in through history sync
+        while !gossip.pending_events.is_empty() {
+            for action in std::mem::take(&mut gossip.pending_events) {
+                if let Err(e) = handle_action(gossip, events, action) {
+                    log!("Error handling event: {:?}", e);
+                }
+            }
+        }
     }
 }
 
@@ -49,24 +73,40 @@ fn handle_event(
     event: SwarmEvent<MyBehaviourEvent>
 ) -> Result<(), Box<dyn std::error::Error>> {
     // Safely handle the event and convert it to our GossipEvent type
-    let action = match gossip.handle_event(event) {
-        Some(action) => action,
-        None => return Ok(()),
-    };
-     
+    match gossip.handle_event(event) {
+        Some(action) => handle_action(gossip, events, action),
+        None => Ok(()),
+    }
+}
+
+fn handle_action(
+    gossip: &mut Gossip,
+    events: &mut Vec<GossipEvent>,
+    action: GossipEvent,
+) -> Result<(), Box<dyn std::error::Error>> {
     // Debug output for all events
     log!("Handling event: {:?}", action);
     
     // Store only one copy of the event
     match &action {
-        GossipEvent::NewConnection(peer_id) => {
-            log!("New connection detected to peer(s): {:?}", peer_id);
+        GossipEvent::Disconnected { peer, reason } => {
+            log!("Disconnected from {}: {:?}", peer, reason);
             events.push(action.clone());
         },
-        GossipEvent::Disconnection(peer_id) => {
-            log!("Disconnection from peer(s): {:?}", peer_id);
+        GossipEvent::DialFailure { address, error } => {
+            log!("Could not dial {}: {}", address, error);
             events.push(action.clone());
         },
+        GossipEvent::Message((_, InteractionMessage::VoiceMessage(_))) => {
+            // The clip bytes don't belong in the event list, the VoiceMessage event
+            // with the file path comes once it's stored
+        },
+        GossipEvent::Message((_, InteractionMessage::BoardOps(_))) => {
+            // The host app gets a BoardChanged event instead
+        },
+        GossipEvent::Message((_, InteractionMessage::CheckIn(_))) => {
+            // Wolves get a CheckedIn event instead, nobody else needs to know
+        },
         _ => {
             // For message events, we'll process them below
             events.push(action.clone());
@@ -125,9 +165,54 @@ fn handle_event(
             gossip.whitelist.add_peer(new_wolf.new_wolf_peer_id);
         },
         InteractionMessage::WolfVerify(_wolf_verify) => {
-            log!("Received wolf verification for: {}", data.peer);
-            gossip.whitelist.add_peer(data.peer);
+            log!("Received wolf verification for: {}", data.source);
+            gossip.whitelist.add_peer(data.source);
         }
+        InteractionMessage::FileManifest(manifest) => {
+            log!("Received file manifest for {} ({} bytes)", manifest.name, manifest.size);
+            // The host app decides whether to fetch it
+            gossip.file_offered(data.source, manifest);
+        },
+        InteractionMessage::VoiceMessage(voice) => {
+            log!("Received {}ms voice message from {}", voice.duration_ms, data.peer);
+            if let Err(e) = gossip.voice_received(data, voice) {
+                log!("Failed to receive voice message: {}", e);
+            }
+        },
+        InteractionMessage::GeoAlert(alert) => {
+            // Only gets here if we're inside the area
+            log!("Received geofenced alert: {}", alert.message.message);
+            gossip.cap_alerts.insert(CapAlert::from_geo_alert(&alert, &data.source));
+        },
+        InteractionMessage::CapAlert(alert) => {
+            log!("Received CAP alert {} ({:?})", alert.identifier, Tag::from(alert.severity()));
+            gossip.cap_alerts.insert(alert);
+        },
+        InteractionMessage::Retract(retract) => {
+            log!("Message {} retracted by {}: {}", retract.msg_id, data.source, retract.reason);
+            gossip.retract(retract.msg_id, retract.reason, data.source);
+        },
+        InteractionMessage::LocationShare(share) => {
+            log!("Received location beacon from {}", data.source);
+            gossip.peers.located(&data.source, share);
+        },
+        InteractionMessage::BoardOps(ops) => {
+            log!("Received {} board ops from {}", ops.len(), data.peer);
+            let incidents = gossip.board_ops_received(data.peer, ops);
+            if !incidents.is_empty() {
+                events.push(GossipEvent::BoardChanged { incidents });
+            }
+        },
+        InteractionMessage::RollCall(call) => {
+            // The host app asks the user and answers with check_in()
+            log!("Received roll call {} from {}", call.id, data.source);
+            gossip.roll_call_received(data.source, call);
+        },
+        InteractionMessage::CheckIn(check_in) => {
+            if let Some(event) = gossip.check_in_received(data.peer, check_in) {
+                events.push(event);
+            }
+        },
         InteractionMessage::Other => {
             log!("Received unknown message type, ignoring");
         },
@@ -146,7 +231,7 @@ pub fn peer_id_from_raw_parts(
         Ok(peer_id) => Some(peer_id),
         Err(_) => {
             log!("Invalid PeerId");
-            return None;
+            None
         }
     }
 }
\ No newline at end of file
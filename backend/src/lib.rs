@@ -1,20 +1,39 @@
+// Everything exported here is called from C with a pointer and its size, which the header
+// documents. Marking the functions unsafe wouldn't tell the C side anything, so the checks
+// stay inside the functions where they are.
+#![allow(clippy::not_unsafe_ptr_arg_deref)]
+
 mod gossip;
 mod communication;
+mod board;
+mod cap;
+mod config;
+mod geo;
 mod runtime;
 mod internal;
 mod log;
+mod rollcall;
+mod store;
+mod wire;
 pub mod ffi;
 
 use gossip::room::GossipRooms;
 use communication::{InteractionMessage};
+use std::collections::BTreeMap;
 use std::sync::Arc;
 use std::time::SystemTime;
 use tokio::runtime::Runtime;
 use tokio::sync::Mutex;
-use crate::communication::Message;
+use crate::communication::{GeoAlert, Message, Retract, Tag};
+use crate::board::BoardOp;
+use crate::cap::CapAlert;
+use crate::config::Config;
+use crate::geo::{Area, Location, Precision};
 use crate::ffi::FFIList;
+use crate::rollcall::CheckInStatus;
+use crate::store::SearchFilters;
 use crate::runtime::BackendRuntime;
-use crate::{communication::NewWolf, gossip::GenerateRoomName};
+use crate::{communication::NewWolf, gossip::{GenerateRoomName, Gossip, checkin::CheckIns, discovery::Discovery, transfer::FileTransfer, peers::now_ms, replication::Replication, version::Feature, voice::Voice}};
 use crate::internal::{gossip_init, gossip_loop, peer_id_from_raw_parts};
 
 lazy_static::lazy_static! {
@@ -39,10 +58,57 @@ pub extern "C" fn init(whitelist_ptr: *mut *mut u8, whitelist_sizes_ptr: *mut us
         
     BACKEND_RUNTIME.block_on(async {
         let mut guard = BACKEND_RUNTIME.gossip_instance.lock().await;
-        gossip_init(&mut guard, whitelist)
+        match gossip_init(&mut guard, whitelist, Config::default()) {
+            Ok(_) => SUCCESS,
+            Err(e) => {
+                log!("Error initializing gossip: {:?}", e);
+                FAIL
+            }
+        }
     })
 }
 
+/// Same as `init`, but takes a JSON or TOML config.
+/// Returns the list of problems with the config (or with starting up), an empty list means success.
+#[unsafe(no_mangle)]
+pub extern "C" fn init_with_config(
+    config: *const u8,
+    config_size: usize,
+    whitelist_ptr: *mut *mut u8,
+    whitelist_sizes_ptr: *mut usize,
+    whitelist_size: usize,
+) -> FFIList {
+    let config = if config.is_null() {
+        String::new()
+    } else {
+        let config_slice = unsafe { std::slice::from_raw_parts(config, config_size) };
+        String::from_utf8_lossy(config_slice).to_string()
+    };
+    let whitelist = FFIList::init(
+        whitelist_ptr,
+        whitelist_sizes_ptr,
+        whitelist_size
+    ).to_vec();
+
+    let errors: Vec<String> = match Config::load(&config) {
+        Ok(config) => BACKEND_RUNTIME.block_on(async {
+            let mut guard = BACKEND_RUNTIME.gossip_instance.lock().await;
+            match gossip_init(&mut guard, whitelist, config) {
+                Ok(_) => Vec::new(),
+                Err(e) => vec![e.to_string()],
+            }
+        }),
+        Err(errors) => errors.iter().map(|e| e.to_string()).collect(),
+    };
+    for e in &errors {
+        log!("Error initializing gossip: {}", e);
+    }
+
+    let output = FFIList::from_vec(&errors);
+    std::mem::forget(errors);
+    output
+}
+
 #[unsafe(no_mangle)]
 pub extern "C" fn start_gossip_loop() {
     BACKEND_RUNTIME.spawn(async {
@@ -102,43 +168,63 @@ pub extern "C" fn ping(target: *const u8, target_size: usize) -> i32 {
                 return FAIL;
             }
         };
-        // Join a room specific to the target peer
-        let room_name = target_peer_id.generate_room_name();
-        if let Err(e) = gossip.join_room(&room_name) {
-            log!("Error joining room: {e:?}");
-            return FAIL;
+        // They might not be on our subnet, ask the DHT how to reach them
+        gossip.find_peer(target_peer_id);
+
+        // Old builds don't have the ping protocol, fall back to the gossip ping they understand
+        if gossip.peers.schema_version(&target_peer_id).is_some()
+            && !gossip.peers.peer_supports(&target_peer_id, Feature::RttPing)
+        {
+            return legacy_ping(gossip, target_peer_id);
         }
-        
-        let room_name = match gossip.get_topic_from_name(&room_name) {
-            Some(name) => name,
-            None => {
-                log!("Error getting room name for {}", &room_name);
-                return FAIL;
-            }
-        };
-        
-        // Create a ping message with current timestamp
-        let message = InteractionMessage::Ping(
-            SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .expect("Time went backwards")
-                .as_millis() // Convert to milliseconds
-        );
-        
-        // Send the ping message
-        match gossip.gossip(&message, room_name) {
-            Ok(_) => {
-                log!("Ping sent successfully to {}", target_peer_id);
-                SUCCESS
-            },
-            Err(e) => {
-                log!("Error sending ping: {e:?}");
-                FAIL
-            }
+
+        // The ping protocol measures RTTs on every connection anyway,
+        // the result shows up as a PingResult event
+        if !gossip.request_ping(target_peer_id) {
+            log!("Not connected to {}, nothing to ping", target_peer_id);
+            return FAIL;
         }
+        log!("Ping requested for {}", target_peer_id);
+        SUCCESS
     })
 }
 
+fn legacy_ping(gossip: &mut Gossip, target_peer_id: libp2p::PeerId) -> i32 {
+    // Join a room specific to the target peer
+    let room_name = target_peer_id.generate_room_name();
+    if let Err(e) = gossip.join_room(&room_name) {
+        log!("Error joining room: {e:?}");
+        return FAIL;
+    }
+
+    let room_name = match gossip.get_topic_from_name(&room_name) {
+        Some(name) => name,
+        None => {
+            log!("Error getting room name for {}", &room_name);
+            return FAIL;
+        }
+    };
+
+    // The reply comes back as a PingReply message
+    let message = InteractionMessage::Ping(
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_millis() // Convert to milliseconds
+    );
+
+    match gossip.gossip(&message, room_name) {
+        Ok(_) => {
+            log!("Legacy ping sent successfully to {}", target_peer_id);
+            SUCCESS
+        },
+        Err(e) => {
+            log!("Error sending ping: {e:?}");
+            FAIL
+        }
+    }
+}
+
 #[cfg(debug_assertions)]
 pub extern "C" fn ping_test() {
     BACKEND_RUNTIME.block_on_gossip(|gossip| {
@@ -160,7 +246,7 @@ pub extern "C" fn ping_test() {
         }
         
         //TODO pinging in general is insane
-        let room_name = gossip.get_topic_from_name("general");
+        let room_name = gossip.get_topic_from_name(gossip.default_room());
         let Some(room_name) = room_name else {
             log!("Error getting room name");
             return;
@@ -173,26 +259,91 @@ pub extern "C" fn ping_test() {
         );
         if let Err(e) = gossip.gossip(&message, room_name) {
             log!("Error sending ping: {e:?}");
-            // Don't crash on InsufficientPeers - it's an expected condition
-            if let gossip::GossipSendError::PublishError(libp2p::gossipsub::PublishError::InsufficientPeers) = e {
-                log!("Not enough peers connected yet to propagate messages");
-            }
         }
     })
 }
 
+#[derive(serde::Deserialize)]
+struct GeoAlertRequest {
+    #[serde(flatten)]
+    message: BroadcastRequest,
+    area: Area,
+}
+
+#[derive(serde::Deserialize)]
+struct BroadcastRequest {
+    message: String,
+    #[serde(default)]
+    tag: String,
+    #[serde(default)]
+    location: Option<Location>,
+    #[serde(default)]
+    expires_at: Option<u64>,
+    #[serde(default)]
+    supersedes: Option<String>,
+    #[serde(default)]
+    reply_to: Option<String>,
+}
+
+impl BroadcastRequest {
+    fn into_message(self) -> Option<Message> {
+        let message = Message {
+            message: self.message,
+            tags: self.tag.into(),
+            timestamp: now_ms(),
+            location: self.location,
+            id: Message::new_id(),
+            expires_at: self.expires_at,
+            supersedes: self.supersedes,
+            reply_to: self.reply_to,
+        };
+        (message.is_valid() && !message.is_expired(message.timestamp)).then_some(message)
+    }
+}
+
+// Sends a Message or GeoAlert to the default room, returns the new message's id.
+// Every broadcast goes through here, so our own messages can be superseded, retracted and
+// replied to like anyone else's.
+fn broadcast(gossip: &mut Gossip, msg: InteractionMessage) -> Option<String> {
+    let Some(topic) = gossip.get_topic_from_name(gossip.default_room()) else {
+        log!("Error getting '{}' topic", gossip.default_room());
+        return None;
+    };
+    let message = msg.message()?.clone();
+    if publish_message(gossip, topic, &msg) != SUCCESS {
+        return None;
+    }
+    // Cached only after gossip() stored it, same order as accept(), so superseding can mark
+    // the stored row. Our own messages can be superseded too and the UI hears about it.
+    gossip.cache_message(gossip.peer_id(), &message);
+    Some(message.id)
+}
+
+fn broadcast_with_id(gossip: &mut Gossip, msg: InteractionMessage) -> FFIList {
+    let ids: Vec<String> = broadcast(gossip, msg).into_iter().collect();
+    let result = FFIList::from_vec(&ids);
+    std::mem::forget(ids);
+    result
+}
+
+// Shared by the broadcast_message variants. Nobody to send to isn't an error, the outbox
+// holds on to it until there is someone.
+fn publish_message(gossip: &mut Gossip, topic: libp2p::gossipsub::IdentTopic, msg: &InteractionMessage) -> i32 {
+    match gossip.gossip(msg, topic) {
+        Ok(_) => {
+            log!("Message queued for broadcast");
+            SUCCESS
+        },
+        Err(e) => {
+            log!("Error broadcasting message: {:?}", e);
+            FAIL
+        }
+    }
+}
+
 #[unsafe(no_mangle)]
 pub extern "C" fn broadcast_message(message: *mut u8, message_size: usize, tag: *const u8, tag_size: usize) -> i32 {
     BACKEND_RUNTIME.block_on_gossip(|gossip| {
-        // Get the general topic
-        let topic = match gossip.get_topic_from_name("general") {
-            Some(topic) => topic,
-            None => {
-                log!("Error getting 'general' topic");
-                return FAIL;
-            }
-        };
-        
         // Create the message from the provided data
         let msg = InteractionMessage::Message(Message{
             message: unsafe {
@@ -205,33 +356,304 @@ pub extern "C" fn broadcast_message(message: *mut u8, message_size: usize, tag:
                 .duration_since(SystemTime::UNIX_EPOCH)
                 .expect("Time went backwards")
                 .as_millis() as u64, // Convert to milliseconds
+            location: None,
+            id: Message::new_id(),
+            expires_at: None,
+            supersedes: None,
+            reply_to: None,
         });
         
-        // Send the message
-        match gossip.gossip(&msg, topic) {
-            Ok(_) => {
-                log!("Message broadcast successfully");
-                SUCCESS
-            },
+        match broadcast(gossip, msg) {
+            Some(_) => SUCCESS,
+            None => FAIL,
+        }
+    })
+}
+
+/// Same as broadcast_message, but takes
+/// `{"message": "...", "tag": "high", "location": {...}, "expires_at": ..., "supersedes": "<msg id>"}`
+/// so optional fields don't need yet another pointer pair. Returns the message's id.
+#[unsafe(no_mangle)]
+pub extern "C" fn broadcast_message_ex(json: *const u8, json_size: usize) -> FFIList {
+    BACKEND_RUNTIME.block_on_gossip(|gossip| {
+        let json = unsafe { std::slice::from_raw_parts(json, json_size) };
+        let request: BroadcastRequest = match serde_json::from_slice(json) {
+            Ok(request) => request,
+            Err(e) => {
+                log!("Invalid broadcast request: {}", e);
+                return FFIList::new();
+            }
+        };
+        let Some(message) = request.into_message() else {
+            log!("Invalid or already expired broadcast request");
+            return FFIList::new();
+        };
+        broadcast_with_id(gossip, InteractionMessage::Message(message))
+    })
+}
+
+/// Answers a message, same as broadcast_message_ex with reply_to set. The reply keeps the
+/// parent's tag, an answer to a critical request for help is just as urgent.
+#[unsafe(no_mangle)]
+pub extern "C" fn reply(msg_id: *const u8, msg_id_size: usize, text: *const u8, text_size: usize) -> FFIList {
+    BACKEND_RUNTIME.block_on_gossip(|gossip| {
+        let msg_id = unsafe {
+            String::from_utf8_lossy(std::slice::from_raw_parts(msg_id, msg_id_size)).to_string()
+        };
+        let text = unsafe {
+            String::from_utf8_lossy(std::slice::from_raw_parts(text, text_size)).to_string()
+        };
+
+        // Only wolves can send anything but Normal, see InteractionMessage::from_msg
+        let is_wolf = gossip.whitelist.contains(&gossip.peer_id());
+        let tags = gossip.messages.get(&msg_id).map(|cached| cached.message.tags).filter(|_| is_wolf);
+        let message = Message {
+            message: text,
+            tags: tags.unwrap_or(Tag::Normal),
+            timestamp: now_ms(),
+            location: None,
+            id: Message::new_id(),
+            expires_at: None,
+            supersedes: None,
+            reply_to: Some(msg_id),
+        };
+        if !message.is_valid() {
+            log!("Invalid message id to reply to");
+            return FFIList::new();
+        }
+        broadcast_with_id(gossip, InteractionMessage::Message(message))
+    })
+}
+
+/// Every message in the thread the given message is part of, oldest first.
+/// Each one is a JSON object with author, message and superseded_by.
+#[unsafe(no_mangle)]
+pub extern "C" fn get_thread(msg_id: *const u8, msg_id_size: usize) -> FFIList {
+    BACKEND_RUNTIME.block_on_gossip(|gossip| {
+        let msg_id = unsafe {
+            String::from_utf8_lossy(std::slice::from_raw_parts(msg_id, msg_id_size)).to_string()
+        };
+        let thread: Vec<String> = gossip
+            .messages
+            .thread(&msg_id)
+            .iter()
+            .filter_map(|cached| serde_json::to_string(cached).ok())
+            .collect();
+
+        let result = FFIList::from_vec(&thread);
+        std::mem::forget(thread);
+        result
+    })
+}
+
+/// Stored messages newer than `since` (unix ms), oldest first. An empty room means all rooms.
+/// Each one is a JSON object, see store::StoredMessage.
+#[unsafe(no_mangle)]
+pub extern "C" fn get_messages(room: *const u8, room_size: usize, since: u64, limit: u32) -> FFIList {
+    BACKEND_RUNTIME.block_on_gossip(|gossip| {
+        let room = unsafe { String::from_utf8_lossy(std::slice::from_raw_parts(room, room_size)).to_string() };
+        let Some(store) = &gossip.store else {
+            log!("No message store");
+            return FFIList::new();
+        };
+        let room = (!room.is_empty()).then_some(room.as_str());
+        let messages: Vec<String> = match store.messages(room, since, limit as usize) {
+            Ok(messages) => messages.iter().filter_map(|stored| serde_json::to_string(stored).ok()).collect(),
             Err(e) => {
-                // Log but don't treat InsufficientPeers as a fatal error for UI
-                if let gossip::GossipSendError::PublishError(libp2p::gossipsub::PublishError::InsufficientPeers) = e {
-                    log!("Message queued but not sent - not enough peers connected yet");
-                    // Return success to frontend so it doesn't show an error
-                    // This makes the UI nicer during demo setup
-                    // > Smart
-                    // >  - Comet
-
-                    return SUCCESS;
+                log!("Error reading messages: {}", e);
+                return FFIList::new();
+            }
+        };
+
+        let result = FFIList::from_vec(&messages);
+        std::mem::forget(messages);
+        result
+    })
+}
+
+/// Full text search over the stored messages, best matches first.
+/// Filters are JSON, see store::SearchFilters, empty means none.
+#[unsafe(no_mangle)]
+pub extern "C" fn search_messages(
+    query: *const u8,
+    query_size: usize,
+    filters: *const u8,
+    filters_size: usize,
+) -> FFIList {
+    BACKEND_RUNTIME.block_on_gossip(|gossip| {
+        let query = unsafe { String::from_utf8_lossy(std::slice::from_raw_parts(query, query_size)).to_string() };
+        let filters: SearchFilters = if filters_size == 0 {
+            SearchFilters::default()
+        } else {
+            let filters = unsafe { std::slice::from_raw_parts(filters, filters_size) };
+            match serde_json::from_slice(filters) {
+                Ok(filters) => filters,
+                Err(e) => {
+                    log!("Invalid search filters: {}", e);
+                    return FFIList::new();
                 }
-                
-                log!("Error broadcasting message: {:?}", e);
+            }
+        };
+        let Some(store) = &gossip.store else {
+            log!("No message store");
+            return FFIList::new();
+        };
+        let results: Vec<String> = match store.search(&query, &filters) {
+            Ok(results) => results.iter().filter_map(|result| serde_json::to_string(result).ok()).collect(),
+            Err(e) => {
+                log!("Error searching messages: {}", e);
+                return FFIList::new();
+            }
+        };
+
+        let result = FFIList::from_vec(&results);
+        std::mem::forget(results);
+        result
+    })
+}
+
+/// Wolves only. Takes the same JSON as broadcast_message_ex plus an area,
+/// e.g. `"area": {"circle": {"center": {"lat": 52.1, "lon": 5.2}, "radius_m": 500}}`
+#[unsafe(no_mangle)]
+pub extern "C" fn broadcast_geo_alert(json: *const u8, json_size: usize) -> FFIList {
+    BACKEND_RUNTIME.block_on_gossip(|gossip| {
+        let json = unsafe { std::slice::from_raw_parts(json, json_size) };
+        if !gossip.whitelist.contains(&gossip.peer_id()) {
+            log!("Only wolves can send geofenced alerts");
+            return FFIList::new();
+        }
+        let request: GeoAlertRequest = match serde_json::from_slice(json) {
+            Ok(request) => request,
+            Err(e) => {
+                log!("Invalid geo alert request: {}", e);
+                return FFIList::new();
+            }
+        };
+        if !request.area.is_valid() {
+            log!("Invalid area in geo alert request");
+            return FFIList::new();
+        }
+        let Some(message) = request.message.into_message() else {
+            log!("Invalid or already expired geo alert request");
+            return FFIList::new();
+        };
+        broadcast_with_id(gossip, InteractionMessage::GeoAlert(GeoAlert { message, area: request.area }))
+    })
+}
+
+/// Wolves only. Takes a message (or CAP alert) back everywhere in the mesh
+#[unsafe(no_mangle)]
+pub extern "C" fn retract_message(msg_id: *const u8, msg_id_size: usize, reason: *const u8, reason_size: usize) -> i32 {
+    BACKEND_RUNTIME.block_on_gossip(|gossip| {
+        let msg_id = unsafe {
+            String::from_utf8_lossy(std::slice::from_raw_parts(msg_id, msg_id_size)).to_string()
+        };
+        let reason = unsafe {
+            String::from_utf8_lossy(std::slice::from_raw_parts(reason, reason_size)).to_string()
+        };
+        if !gossip.whitelist.contains(&gossip.peer_id()) {
+            log!("Only wolves can retract messages");
+            return FAIL;
+        }
+
+        let topic = match gossip.get_topic_from_name(gossip.default_room()) {
+            Some(topic) => topic,
+            None => {
+                log!("Error getting '{}' topic", gossip.default_room());
+                return FAIL;
+            }
+        };
+        let message = InteractionMessage::Retract(Retract { msg_id: msg_id.clone(), reason: reason.clone() });
+        let result = publish_message(gossip, topic, &message);
+        // Our own copy goes too
+        gossip.retract(msg_id, reason, gossip.peer_id());
+        result
+    })
+}
+
+/// Wolves only. Takes a CAP 1.2 XML alert and broadcasts it
+#[unsafe(no_mangle)]
+pub extern "C" fn publish_cap_alert(xml: *const u8, xml_size: usize) -> i32 {
+    BACKEND_RUNTIME.block_on_gossip(|gossip| {
+        let xml = unsafe { String::from_utf8_lossy(std::slice::from_raw_parts(xml, xml_size)).to_string() };
+        if !gossip.whitelist.contains(&gossip.peer_id()) {
+            log!("Only wolves can publish CAP alerts");
+            return FAIL;
+        }
+        let alert = match CapAlert::from_xml(&xml) {
+            Ok(alert) => alert,
+            Err(e) => {
+                log!("Invalid CAP alert: {}", e);
+                return FAIL;
+            }
+        };
+
+        let topic = match gossip.get_topic_from_name(gossip.default_room()) {
+            Some(topic) => topic,
+            None => {
+                log!("Error getting '{}' topic", gossip.default_room());
+                return FAIL;
+            }
+        };
+        gossip.cap_alerts.insert(alert.clone());
+        publish_message(gossip, topic, &InteractionMessage::CapAlert(alert))
+    })
+}
+
+/// CAP 1.2 XML for an alert we sent or received, by identifier.
+/// Geofenced alerts are exported too, their identifier is "<peer id>.<timestamp>".
+#[unsafe(no_mangle)]
+pub extern "C" fn export_cap_alert(identifier: *const u8, identifier_size: usize) -> FFIList {
+    BACKEND_RUNTIME.block_on_gossip(|gossip| {
+        let identifier = unsafe {
+            String::from_utf8_lossy(std::slice::from_raw_parts(identifier, identifier_size)).to_string()
+        };
+        let xml = match gossip.cap_alerts.get(&identifier) {
+            Some(alert) => vec![alert.to_xml()],
+            None => {
+                log!("No CAP alert with identifier {}", identifier);
+                Vec::new()
+            }
+        };
+
+        let result = FFIList::from_vec(&xml);
+        std::mem::forget(xml);
+        result
+    })
+}
+
+/// Our current position, JSON like `{"lat": 52.1, "lon": 5.2, "accuracy_m": 10, "source": "gps"}`.
+/// Only sent out if location sharing is on.
+#[unsafe(no_mangle)]
+pub extern "C" fn update_location(json: *const u8, json_size: usize) -> i32 {
+    BACKEND_RUNTIME.block_on_gossip(|gossip| {
+        let json = unsafe { std::slice::from_raw_parts(json, json_size) };
+        match serde_json::from_slice::<Location>(json) {
+            Ok(location) if location.is_valid() => {
+                gossip.location.current = Some(location);
+                SUCCESS
+            }
+            Ok(_) => {
+                log!("Location out of range");
+                FAIL
+            }
+            Err(e) => {
+                log!("Invalid location: {}", e);
                 FAIL
             }
         }
     })
 }
 
+#[unsafe(no_mangle)]
+pub extern "C" fn set_location_sharing(enabled: i32, precise: i32) -> i32 {
+    BACKEND_RUNTIME.block_on_gossip(|gossip| {
+        let precision = if precise != 0 { Precision::Exact } else { Precision::Coarse };
+        gossip.location.set_enabled(enabled != 0, precision);
+        SUCCESS
+    })
+}
+
 #[unsafe(no_mangle)]
 pub extern "C" fn new_wolf(
     new_wolf_peer_id: *const u8,
@@ -243,20 +665,14 @@ pub extern "C" fn new_wolf(
         };
         
         // First, add to the local whitelist regardless of whether we can broadcast
-        gossip.whitelist.add_peer(new_wolf_peer_id.clone());
+        gossip.whitelist.add_peer(new_wolf_peer_id);
         log!("Added {} to local whitelist", new_wolf_peer_id);
         
-        // Check if we have peers to broadcast to
-        if gossip.peer_ids.is_empty() {
-            log!("No peers connected yet - new wolf added locally only");
-            // Return success even if we only added locally
-            return 1;
-        }
-        
+        // Goes out through the outbox once there's someone to tell
         let message = InteractionMessage::NewWolf(NewWolf {
-            new_wolf_peer_id: new_wolf_peer_id.clone()
+            new_wolf_peer_id
         });
-        let room_name = gossip.get_topic_from_name("general");
+        let room_name = gossip.get_topic_from_name(gossip.default_room());
         let Some(room_name) = room_name else {
             log!("Error getting room name");
             // Return success since we already added to local whitelist
@@ -264,26 +680,318 @@ pub extern "C" fn new_wolf(
         };
         
         match gossip.gossip(&message, room_name) {
-            Ok(_) => {
-                log!("Successfully announced new wolf to the network");
-                1
-            },
+            Ok(_) => log!("Queued new wolf announcement"),
+            // Return success since we already added to local whitelist
+            Err(e) => log!("Error broadcasting new wolf: {e:?}"),
+        }
+        1
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn dial(address: *const u8, address_size: usize) -> i32 {
+    BACKEND_RUNTIME.block_on_gossip(|gossip| {
+        let address_slice = unsafe {
+            std::slice::from_raw_parts(address, address_size)
+        };
+        let address = match String::from_utf8_lossy(address_slice).parse::<libp2p::Multiaddr>() {
+            Ok(address) => address,
             Err(e) => {
-                log!("Error broadcasting new wolf: {e:?}");
-                if let gossip::GossipSendError::PublishError(libp2p::gossipsub::PublishError::InsufficientPeers) = e {
-                    log!("Not enough peers connected yet to announce new wolf");
-                    // Return success since we already added to local whitelist
-                    1
-                } else {
-                    // Return success since we already added to local whitelist
-                    // This makes demo smoother even if network broadcast fails
-                    1
-                }
+                log!("Invalid multiaddr provided for dial: {e:?}");
+                return FAIL;
+            }
+        };
+
+        // Whether it actually worked is reported later as a DialSuccess/DialFailure event
+        match gossip.dial(address) {
+            Ok(_) => SUCCESS,
+            Err(e) => {
+                log!("Error dialing: {e:?}");
+                FAIL
+            }
+        }
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn share_file(path: *const u8, path_size: usize, mime: *const u8, mime_size: usize) -> FFIList {
+    BACKEND_RUNTIME.block_on_gossip(|gossip| {
+        let path = unsafe {
+            String::from_utf8_lossy(std::slice::from_raw_parts(path, path_size)).to_string()
+        };
+        let mime = unsafe {
+            String::from_utf8_lossy(std::slice::from_raw_parts(mime, mime_size)).to_string()
+        };
+
+        // Everyone in the default room gets the manifest, the file itself is only sent on request
+        let room_name = gossip.default_room().to_string();
+        let root_hashes = match gossip.share_file(std::path::Path::new(&path), mime, &room_name) {
+            Ok(manifest) => vec![manifest.root_hash],
+            Err(e) => {
+                log!("Error sharing file {}: {:?}", path, e);
+                Vec::new()
+            }
+        };
+
+        let result = FFIList::from_vec(&root_hashes);
+        std::mem::forget(root_hashes);
+        result
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn fetch_file(root_hash: *const u8, root_hash_size: usize) -> i32 {
+    BACKEND_RUNTIME.block_on_gossip(|gossip| {
+        let root_hash = unsafe {
+            String::from_utf8_lossy(std::slice::from_raw_parts(root_hash, root_hash_size)).to_string()
+        };
+
+        // Progress is reported through FileProgress/FileComplete/FileFailed events
+        match gossip.fetch_file(&root_hash) {
+            Ok(_) => SUCCESS,
+            Err(e) => {
+                log!("Error fetching file {}: {}", root_hash, e);
+                FAIL
+            }
+        }
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn send_voice_message(
+    codec: *const u8,
+    codec_size: usize,
+    duration_ms: u32,
+    data: *const u8,
+    data_size: usize,
+) -> i32 {
+    BACKEND_RUNTIME.block_on_gossip(|gossip| {
+        let codec = unsafe {
+            String::from_utf8_lossy(std::slice::from_raw_parts(codec, codec_size)).to_string()
+        };
+        let clip = unsafe { std::slice::from_raw_parts(data, data_size) };
+
+        let room_name = gossip.default_room().to_string();
+        match gossip.send_voice_message(&room_name, codec, duration_ms, clip) {
+            Ok(_) => SUCCESS,
+            Err(e) => {
+                log!("Error sending voice message: {:?}", e);
+                FAIL
             }
         }
     })
 }
 
+/// One JSON object, see gossip::Diagnostics
+#[unsafe(no_mangle)]
+pub extern "C" fn get_diagnostics() -> FFIList {
+    BACKEND_RUNTIME.block_on_gossip(|gossip| {
+        let diagnostics = match serde_json::to_string(&gossip.diagnostics()) {
+            Ok(json) => vec![json],
+            Err(e) => {
+                log!("Error serializing diagnostics: {}", e);
+                Vec::new()
+            }
+        };
+
+        let result = FFIList::from_vec(&diagnostics);
+        std::mem::forget(diagnostics);
+        result
+    })
+}
+
+/// Wolves only. Takes the incident's fields as a JSON object of strings,
+/// e.g. `{"title": "Flooded basement", "assignee": "team 2"}`. Status defaults to open.
+#[unsafe(no_mangle)]
+pub extern "C" fn board_add_incident(json: *const u8, json_size: usize) -> FFIList {
+    BACKEND_RUNTIME.block_on_gossip(|gossip| {
+        let json = unsafe { std::slice::from_raw_parts(json, json_size) };
+        let mut fields: BTreeMap<String, String> = match serde_json::from_slice(json) {
+            Ok(fields) => fields,
+            Err(e) => {
+                log!("Invalid incident: {}", e);
+                return FFIList::new();
+            }
+        };
+        fields.entry("status".to_string()).or_insert_with(|| "open".to_string());
+
+        let incident = BoardOp::new_tag();
+        let timestamp = now_ms();
+        let mut ops = vec![BoardOp::Add { incident: incident.clone(), tag: BoardOp::new_tag() }];
+        ops.extend(fields.into_iter().map(|(field, value)| BoardOp::Set {
+            incident: incident.clone(),
+            field,
+            value,
+            timestamp,
+        }));
+        if let Err(e) = gossip.edit_board(ops) {
+            log!("Error adding incident: {}", e);
+            return FFIList::new();
+        }
+
+        let ids = vec![incident];
+        let result = FFIList::from_vec(&ids);
+        std::mem::forget(ids);
+        result
+    })
+}
+
+/// Wolves only. Status has to be open, assigned or resolved, other fields are free.
+#[unsafe(no_mangle)]
+pub extern "C" fn board_set_field(
+    incident: *const u8,
+    incident_size: usize,
+    field: *const u8,
+    field_size: usize,
+    value: *const u8,
+    value_size: usize,
+) -> i32 {
+    BACKEND_RUNTIME.block_on_gossip(|gossip| {
+        let (incident, field, value) = unsafe {
+            (
+                String::from_utf8_lossy(std::slice::from_raw_parts(incident, incident_size)).to_string(),
+                String::from_utf8_lossy(std::slice::from_raw_parts(field, field_size)).to_string(),
+                String::from_utf8_lossy(std::slice::from_raw_parts(value, value_size)).to_string(),
+            )
+        };
+        if !gossip.board.contains(&incident) {
+            log!("No incident {} on the board", incident);
+            return FAIL;
+        }
+        match gossip.edit_board(vec![BoardOp::Set { incident, field, value, timestamp: now_ms() }]) {
+            Ok(_) => SUCCESS,
+            Err(e) => {
+                log!("Error editing incident: {}", e);
+                FAIL
+            }
+        }
+    })
+}
+
+/// Wolves only
+#[unsafe(no_mangle)]
+pub extern "C" fn board_remove_incident(incident: *const u8, incident_size: usize) -> i32 {
+    BACKEND_RUNTIME.block_on_gossip(|gossip| {
+        let incident = unsafe {
+            String::from_utf8_lossy(std::slice::from_raw_parts(incident, incident_size)).to_string()
+        };
+        let tags = gossip.board.tags(&incident);
+        if tags.is_empty() {
+            log!("No incident {} on the board", incident);
+            return FAIL;
+        }
+        match gossip.edit_board(vec![BoardOp::Remove { incident, tags }]) {
+            Ok(_) => SUCCESS,
+            Err(e) => {
+                log!("Error removing incident: {}", e);
+                FAIL
+            }
+        }
+    })
+}
+
+/// One JSON object per incident, see board::Incident
+#[unsafe(no_mangle)]
+pub extern "C" fn get_board() -> FFIList {
+    BACKEND_RUNTIME.block_on_gossip(|gossip| {
+        let incidents: Vec<String> = gossip
+            .board
+            .incidents()
+            .iter()
+            .filter_map(|incident| serde_json::to_string(incident).ok())
+            .collect();
+
+        let result = FFIList::from_vec(&incidents);
+        std::mem::forget(incidents);
+        result
+    })
+}
+
+/// Wolves only. Everyone in the default room is asked to check in within duration_secs.
+#[unsafe(no_mangle)]
+pub extern "C" fn start_roll_call(duration_secs: u64) -> FFIList {
+    BACKEND_RUNTIME.block_on_gossip(|gossip| {
+        let ids = match gossip.start_roll_call(duration_secs.saturating_mul(1000)) {
+            Ok(id) => vec![id],
+            Err(e) => {
+                log!("Error starting roll call: {}", e);
+                Vec::new()
+            }
+        };
+
+        let result = FFIList::from_vec(&ids);
+        std::mem::forget(ids);
+        result
+    })
+}
+
+/// Answers a roll call. Status is ok, need_help or injured, the location is the last one
+/// passed to update_location (exact, whatever the sharing setting says).
+#[unsafe(no_mangle)]
+pub extern "C" fn check_in(
+    roll_call: *const u8,
+    roll_call_size: usize,
+    status: *const u8,
+    status_size: usize,
+    include_location: i32,
+) -> i32 {
+    BACKEND_RUNTIME.block_on_gossip(|gossip| {
+        let (roll_call, status) = unsafe {
+            (
+                String::from_utf8_lossy(std::slice::from_raw_parts(roll_call, roll_call_size)).to_string(),
+                String::from_utf8_lossy(std::slice::from_raw_parts(status, status_size)).to_string(),
+            )
+        };
+        let Ok(status) = CheckInStatus::try_from(status.as_str()) else {
+            log!("Unknown check-in status {}", status);
+            return FAIL;
+        };
+        let location = if include_location != 0 { gossip.location.current.clone() } else { None };
+
+        match gossip.check_in(&roll_call, status, location) {
+            Ok(_) => SUCCESS,
+            Err(e) => {
+                log!("Error checking in for roll call {}: {}", roll_call, e);
+                FAIL
+            }
+        }
+    })
+}
+
+/// Wolves only. One JSON object, see rollcall::Roster. An empty id means the latest roll call.
+#[unsafe(no_mangle)]
+pub extern "C" fn get_roster(roll_call: *const u8, roll_call_size: usize) -> FFIList {
+    BACKEND_RUNTIME.block_on_gossip(|gossip| {
+        let roll_call = unsafe {
+            String::from_utf8_lossy(std::slice::from_raw_parts(roll_call, roll_call_size)).to_string()
+        };
+        if !gossip.whitelist.contains(&gossip.peer_id()) {
+            log!("Only wolves keep a roster");
+            return FFIList::new();
+        }
+        let roll_call = match roll_call.is_empty() {
+            true => gossip.roll_calls.latest().map(str::to_string),
+            false => Some(roll_call),
+        };
+        let roster = roll_call.and_then(|id| gossip.roll_calls.roster(&id, &gossip.peers, now_ms()));
+        let rosters = match roster.map(|roster| serde_json::to_string(&roster)) {
+            Some(Ok(json)) => vec![json],
+            Some(Err(e)) => {
+                log!("Error serializing roster: {}", e);
+                Vec::new()
+            }
+            None => {
+                log!("No such roll call");
+                Vec::new()
+            }
+        };
+
+        let result = FFIList::from_vec(&rosters);
+        std::mem::forget(rosters);
+        result
+    })
+}
+
 #[unsafe(no_mangle)]
 pub extern "C" fn get_local_peer_id() -> FFIList {
     BACKEND_RUNTIME.block_on_gossip(|gossip| {
@@ -314,6 +1022,8 @@ pub extern "C" fn cleanup() {
     });
 }
 
+/// Bare peer ids, so they can be passed straight to ping() or new_wolf().
+/// RTT stats and everything else we know about a peer are in get_peer_details().
 #[unsafe(no_mangle)]
 pub extern "C" fn get_peers() -> FFIList {
     BACKEND_RUNTIME.block_on_gossip(|gossip| {
@@ -330,4 +1040,22 @@ pub extern "C" fn get_peers() -> FFIList {
         
         result
     })
-}
\ No newline at end of file
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn get_peer_details() -> FFIList {
+    BACKEND_RUNTIME.block_on_gossip(|gossip| {
+        // One JSON object per peer, recently disconnected ones included (connected: false)
+        let mut details = Vec::new();
+        for peer in gossip.peers.details(&gossip.whitelist) {
+            match serde_json::to_string(&peer) {
+                Ok(peer_str) => details.push(peer_str),
+                Err(e) => log!("Error serializing peer details: {:?}", e),
+            }
+        }
+
+        let result = FFIList::from_vec(&details);
+        std::mem::forget(details);
+        result
+    })
+}
@@ -27,9 +27,28 @@ fn print_peers(peers: FFIList) {
         return;
     }
     
+    // RTTs aren't in get_peers(), they come with the details
+    let rtts: std::collections::HashMap<String, serde_json::Value> = get_peer_details()
+        .to_vec()
+        .iter()
+        .filter_map(|details| serde_json::from_str::<serde_json::Value>(details).ok())
+        .filter_map(|details| Some((details["peer_id"].as_str()?.to_string(), details["rtt"].clone())))
+        .collect();
+
     println!("\n👥 CONNECTED PEERS ({}):", peers_vec.len());
     for (i, peer) in peers_vec.iter().enumerate() {
-        println!("  [{}] {}", i+1, peer);
+        match rtts.get(peer) {
+            Some(rtt) if !rtt["last_ms"].is_null() => println!(
+                "  [{}] {} (rtt {:.1}ms, avg {:.1}ms, jitter {:.1}ms, loss {:.0}%)",
+                i+1,
+                peer,
+                rtt["last_ms"].as_f64().unwrap_or_default(),
+                rtt["avg_ms"].as_f64().unwrap_or_default(),
+                rtt["jitter_ms"].as_f64().unwrap_or_default(),
+                rtt["loss"].as_f64().unwrap_or_default() * 100.0
+            ),
+            _ => println!("  [{}] {}", i+1, peer),
+        }
     }
     println!();
 }
@@ -52,11 +71,30 @@ fn main() {
     let whitelist = FFIList::from_vec(&whitelist).spread();
     
     println!("🚀 Initializing P2P network...");
-    let init_result = init(whitelist.0, whitelist.1, whitelist.2);
-    
-    if init_result == 0 {
-        println!("❌ Failed to initialize P2P network");
-        return;
+    // Optional config file (JSON or TOML) as the first argument
+    if let Some(config_path) = std::env::args().nth(1) {
+        let config = match std::fs::read_to_string(&config_path) {
+            Ok(config) => config,
+            Err(e) => {
+                println!("❌ Could not read {}: {}", config_path, e);
+                return;
+            }
+        };
+        let errors = init_with_config(config.as_ptr(), config.len(), whitelist.0, whitelist.1, whitelist.2).to_vec();
+        if !errors.is_empty() {
+            println!("❌ Failed to initialize P2P network:");
+            for error in errors {
+                println!("  {}", error);
+            }
+            return;
+        }
+    } else {
+        let init_result = init(whitelist.0, whitelist.1, whitelist.2);
+        
+        if init_result == 0 {
+            println!("❌ Failed to initialize P2P network");
+            return;
+        }
     }
     
     println!("✅ P2P network initialized successfully");
@@ -118,7 +156,7 @@ fn main() {
                 let result = broadcast_message(
                     content.as_bytes().as_ptr() as *mut u8, 
                     content.len(), 
-                    tag.as_bytes().as_ptr() as *const u8, 
+                    tag.as_bytes().as_ptr(), 
                     tag.len()
                 );
                 
@@ -0,0 +1,416 @@
+use libp2p::{PeerId, identity::Keypair};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::Display;
+
+use crate::communication::LocationShare;
+use crate::geo::Location;
+use crate::gossip::{history::verify_signature, peers::PeerBook, version::Feature};
+
+// Accounting for people after an event. A wolf starts a roll call with a deadline, every
+// node's host app asks its user how they are and answers with a check-in (ok, need help,
+// injured, optionally where they are). Every wolf keeps the answers it hears and turns them
+// into a roster: who answered, and who didn't together with when and where we last saw them.
+//
+// Check-ins are signed by their author like board ops (see board.rs), so one that is passed
+// on still says who it is from. Rosters only live in memory, a roll call is over long before
+// anyone restarts the app.
+
+const MAX_ID_LEN: usize = 64;
+// A roll call that takes longer than this isn't a roll call anymore
+pub const MAX_DURATION_MS: u64 = 24 * 60 * 60 * 1000;
+// How long a roster is kept after the deadline, late check-ins still count
+const KEEP_AFTER_DEADLINE_MS: u64 = 24 * 60 * 60 * 1000;
+const MAX_ROLL_CALLS: usize = 32;
+
+#[derive(Debug)]
+pub enum RollCallError {
+    Unauthorized,
+    UnknownRollCall,
+    BadSignature,
+    Invalid,
+    Signing,
+    Send(String),
+}
+
+impl Display for RollCallError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RollCallError::Unauthorized => write!(f, "Only wolves can start roll calls"),
+            RollCallError::UnknownRollCall => write!(f, "No such roll call"),
+            RollCallError::BadSignature => write!(f, "Signature doesn't match the author"),
+            RollCallError::Invalid => write!(f, "Invalid roll call or check-in"),
+            RollCallError::Signing => write!(f, "Could not sign check-in"),
+            RollCallError::Send(e) => write!(f, "Could not send: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RollCallError {}
+
+fn id_ok(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_ID_LEN
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RollCall {
+    pub id: String,
+    // Unix time in milliseconds, check-ins after it are marked late
+    pub deadline: u64,
+}
+
+impl RollCall {
+    pub fn new_id() -> String {
+        format!("{:016x}", rand::random::<u64>())
+    }
+    pub fn is_valid(&self, now_ms: u64) -> bool {
+        id_ok(&self.id) && self.deadline <= now_ms + MAX_DURATION_MS
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckInStatus {
+    Ok,
+    NeedHelp,
+    Injured,
+}
+
+impl TryFrom<&str> for CheckInStatus {
+    type Error = RollCallError;
+
+    fn try_from(status: &str) -> Result<Self, Self::Error> {
+        match status.to_lowercase().as_str() {
+            "ok" => Ok(CheckInStatus::Ok),
+            "need_help" => Ok(CheckInStatus::NeedHelp),
+            "injured" => Ok(CheckInStatus::Injured),
+            _ => Err(RollCallError::Invalid),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CheckIn {
+    pub roll_call: String,
+    pub status: CheckInStatus,
+    pub location: Option<Location>,
+    pub timestamp: u64,
+}
+
+impl CheckIn {
+    pub fn is_valid(&self) -> bool {
+        id_ok(&self.roll_call) && self.location.as_ref().is_none_or(Location::is_valid)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SignedCheckIn {
+    pub author: PeerId,
+    pub check_in: CheckIn,
+    #[serde(with = "serde_bytes")]
+    pub signature: Vec<u8>,
+}
+
+fn signing_bytes(check_in: &CheckIn) -> Option<Vec<u8>> {
+    let mut bytes = b"truman-check-in".to_vec();
+    ciborium::into_writer(check_in, &mut bytes).ok()?;
+    Some(bytes)
+}
+
+impl SignedCheckIn {
+    pub fn sign(keypair: &Keypair, check_in: CheckIn) -> Result<Self, RollCallError> {
+        let bytes = signing_bytes(&check_in).ok_or(RollCallError::Signing)?;
+        let signature = keypair.sign(&bytes).map_err(|_| RollCallError::Signing)?;
+        Ok(Self { author: keypair.public().to_peer_id(), check_in, signature })
+    }
+    pub fn verify(&self) -> bool {
+        signing_bytes(&self.check_in).is_some_and(|bytes| verify_signature(&self.author, &bytes, &self.signature))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Responded {
+    pub peer: PeerId,
+    pub status: CheckInStatus,
+    pub location: Option<Location>,
+    pub checked_in_at: u64,
+    // Came in after the deadline
+    pub late: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Unresponsive {
+    pub peer: PeerId,
+    pub connected: bool,
+    pub last_seen: u64,
+    // Last location beacon, if they share their location
+    pub location: Option<LocationShare>,
+    // Builds from before roll calls can't answer at all
+    pub can_check_in: bool,
+}
+
+/// What the host app gets to see about a roll call, wolves only
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Roster {
+    pub id: String,
+    pub started_by: PeerId,
+    pub started_at: u64,
+    pub deadline: u64,
+    pub open: bool,
+    pub responded: Vec<Responded>,
+    // Most recently seen first
+    pub unresponsive: Vec<Unresponsive>,
+}
+
+struct RollCallState {
+    call: RollCall,
+    started_by: PeerId,
+    // When we heard about it, the roll call itself doesn't say
+    started_at: u64,
+    // Newest check-in per peer
+    check_ins: HashMap<PeerId, SignedCheckIn>,
+}
+
+#[derive(Default)]
+pub struct RollCalls {
+    calls: HashMap<String, RollCallState>,
+}
+
+impl RollCalls {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns false if we already knew about it
+    pub fn started(&mut self, call: RollCall, started_by: PeerId, now_ms: u64) -> bool {
+        if self.calls.contains_key(&call.id) {
+            return false;
+        }
+        if self.calls.len() >= MAX_ROLL_CALLS
+            && let Some(oldest) = self.calls.values().min_by_key(|state| state.started_at).map(|state| state.call.id.clone())
+        {
+            self.calls.remove(&oldest);
+        }
+        let state = RollCallState { call: call.clone(), started_by, started_at: now_ms, check_ins: HashMap::new() };
+        self.calls.insert(call.id, state);
+        true
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.calls.contains_key(id)
+    }
+
+    /// The roll call we heard about last
+    pub fn latest(&self) -> Option<&str> {
+        self.calls.values().max_by_key(|state| state.started_at).map(|state| state.call.id.as_str())
+    }
+
+    /// Checks and records a check-in. Returns false if we already had the same or a newer one.
+    pub fn checked_in(&mut self, check_in: SignedCheckIn) -> Result<bool, RollCallError> {
+        if !check_in.check_in.is_valid() {
+            return Err(RollCallError::Invalid);
+        }
+        let state = self.calls.get_mut(&check_in.check_in.roll_call).ok_or(RollCallError::UnknownRollCall)?;
+        if !check_in.verify() {
+            return Err(RollCallError::BadSignature);
+        }
+        // People can change their answer, e.g. from ok to need help
+        match state.check_ins.get(&check_in.author) {
+            Some(current) if current.check_in.timestamp >= check_in.check_in.timestamp => Ok(false),
+            _ => {
+                state.check_ins.insert(check_in.author, check_in);
+                Ok(true)
+            }
+        }
+    }
+
+    pub fn prune(&mut self, now_ms: u64) {
+        self.calls.retain(|_, state| state.call.deadline + KEEP_AFTER_DEADLINE_MS > now_ms);
+    }
+
+    /// Everyone we know of that didn't answer counts as unresponsive, we can't tell who is
+    /// out there beyond that
+    pub fn roster(&self, id: &str, peers: &PeerBook, now_ms: u64) -> Option<Roster> {
+        let state = self.calls.get(id)?;
+        let mut responded: Vec<Responded> = state
+            .check_ins
+            .values()
+            .map(|signed| Responded {
+                peer: signed.author,
+                status: signed.check_in.status,
+                location: signed.check_in.location.clone(),
+                checked_in_at: signed.check_in.timestamp,
+                late: signed.check_in.timestamp > state.call.deadline,
+            })
+            .collect();
+        responded.sort_by_key(|entry| entry.checked_in_at);
+
+        let mut unresponsive: Vec<Unresponsive> = peers
+            .peers
+            .iter()
+            // Peers without a schema version aren't TruMAN nodes, nobody to account for
+            .filter(|(peer_id, info)| info.schema_version.is_some() && !state.check_ins.contains_key(peer_id))
+            .map(|(peer_id, info)| Unresponsive {
+                peer: *peer_id,
+                connected: info.is_connected(),
+                last_seen: info.last_seen,
+                location: info.location.clone(),
+                can_check_in: peers.peer_supports(peer_id, Feature::RollCall),
+            })
+            .collect();
+        unresponsive.sort_by_key(|entry| std::cmp::Reverse(entry.last_seen));
+
+        Some(Roster {
+            id: state.call.id.clone(),
+            started_by: state.started_by,
+            started_at: state.started_at,
+            deadline: state.call.deadline,
+            open: now_ms <= state.call.deadline,
+            responded,
+            unresponsive,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gossip::{peers::PeerInfo, version::SCHEMA_VERSION};
+
+    const NOW: u64 = 1_716_594_540_000;
+
+    fn call(id: &str, deadline: u64) -> RollCall {
+        RollCall { id: id.to_string(), deadline }
+    }
+
+    fn check_in(keypair: &Keypair, roll_call: &str, status: CheckInStatus, timestamp: u64) -> SignedCheckIn {
+        let check_in = CheckIn { roll_call: roll_call.to_string(), status, location: None, timestamp };
+        SignedCheckIn::sign(keypair, check_in).unwrap()
+    }
+
+    /// A peer we know of, on a build that speaks `schema_version`
+    fn known_peer(book: &mut PeerBook, peer_id: PeerId, schema_version: Option<u32>, last_seen: u64) -> PeerId {
+        let mut info = PeerInfo::new();
+        info.schema_version = schema_version;
+        info.last_seen = last_seen;
+        book.peers.insert(peer_id, info);
+        peer_id
+    }
+
+    #[test]
+    fn check_ins_have_to_be_signed_by_their_author() {
+        let mut calls = RollCalls::new();
+        calls.started(call("call", NOW + 1000), PeerId::random(), NOW);
+        let keypair = Keypair::generate_ed25519();
+
+        let mut forged = check_in(&keypair, "call", CheckInStatus::Ok, NOW);
+        forged.author = PeerId::random();
+        assert!(matches!(calls.checked_in(forged), Err(RollCallError::BadSignature)));
+
+        let mut changed = check_in(&keypair, "call", CheckInStatus::Ok, NOW);
+        changed.check_in.status = CheckInStatus::Injured;
+        assert!(matches!(calls.checked_in(changed), Err(RollCallError::BadSignature)));
+
+        assert!(calls.checked_in(check_in(&keypair, "call", CheckInStatus::Ok, NOW)).unwrap());
+    }
+
+    #[test]
+    fn check_ins_for_unknown_roll_calls_are_refused() {
+        let mut calls = RollCalls::new();
+        let keypair = Keypair::generate_ed25519();
+        assert!(matches!(
+            calls.checked_in(check_in(&keypair, "nope", CheckInStatus::Ok, NOW)),
+            Err(RollCallError::UnknownRollCall)
+        ));
+        assert!(matches!(calls.checked_in(check_in(&keypair, "", CheckInStatus::Ok, NOW)), Err(RollCallError::Invalid)));
+    }
+
+    #[test]
+    fn only_a_newer_check_in_replaces_the_old_one() {
+        let mut calls = RollCalls::new();
+        calls.started(call("call", NOW + 1000), PeerId::random(), NOW);
+        let keypair = Keypair::generate_ed25519();
+        let status = |calls: &RollCalls| calls.roster("call", &PeerBook::new(), NOW).unwrap().responded[0].status;
+
+        assert!(calls.checked_in(check_in(&keypair, "call", CheckInStatus::Ok, NOW)).unwrap());
+        assert!(calls.checked_in(check_in(&keypair, "call", CheckInStatus::NeedHelp, NOW + 10)).unwrap());
+        assert_eq!(status(&calls), CheckInStatus::NeedHelp);
+
+        // Older or the same again, e.g. relayed late or through history sync
+        assert!(!calls.checked_in(check_in(&keypair, "call", CheckInStatus::Ok, NOW + 5)).unwrap());
+        assert!(!calls.checked_in(check_in(&keypair, "call", CheckInStatus::Ok, NOW + 10)).unwrap());
+        assert_eq!(status(&calls), CheckInStatus::NeedHelp);
+    }
+
+    #[test]
+    fn roster_splits_who_answered_from_who_didnt() {
+        let mut calls = RollCalls::new();
+        let wolf = PeerId::random();
+        let deadline = NOW + 1000;
+        calls.started(call("call", deadline), wolf, NOW);
+
+        let mut book = PeerBook::new();
+        let (on_time, late) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        for keypair in [&on_time, &late] {
+            known_peer(&mut book, keypair.public().to_peer_id(), Some(SCHEMA_VERSION), NOW);
+        }
+        let quiet = known_peer(&mut book, PeerId::random(), Some(SCHEMA_VERSION), NOW - 10);
+        // From before roll calls
+        let quieter = known_peer(&mut book, PeerId::random(), Some(1), NOW - 20);
+        // Not a TruMAN node, nobody to account for
+        known_peer(&mut book, PeerId::random(), None, NOW);
+
+        calls.checked_in(check_in(&late, "call", CheckInStatus::Injured, deadline + 1)).unwrap();
+        calls.checked_in(check_in(&on_time, "call", CheckInStatus::Ok, deadline)).unwrap();
+
+        let roster = calls.roster("call", &book, NOW).unwrap();
+        assert_eq!((roster.started_by, roster.started_at, roster.deadline), (wolf, NOW, deadline));
+        assert!(roster.open);
+        let responded: Vec<(PeerId, bool)> = roster.responded.iter().map(|r| (r.peer, r.late)).collect();
+        assert_eq!(responded, [(on_time.public().to_peer_id(), false), (late.public().to_peer_id(), true)]);
+
+        // Most recently seen first, and whether they could have answered at all
+        let unresponsive: Vec<(PeerId, bool)> = roster.unresponsive.iter().map(|u| (u.peer, u.can_check_in)).collect();
+        assert_eq!(unresponsive, [(quiet, true), (quieter, false)]);
+
+        assert!(!calls.roster("call", &book, deadline + 1).unwrap().open);
+        assert!(calls.roster("other", &book, NOW).is_none());
+    }
+
+    #[test]
+    fn roll_calls_are_kept_for_a_day_after_the_deadline() {
+        let mut calls = RollCalls::new();
+        calls.started(call("old", NOW), PeerId::random(), NOW - 1000);
+        calls.started(call("new", NOW + 1000), PeerId::random(), NOW);
+
+        calls.prune(NOW + KEEP_AFTER_DEADLINE_MS - 1);
+        assert!(calls.contains("old") && calls.contains("new"));
+        calls.prune(NOW + KEEP_AFTER_DEADLINE_MS);
+        assert!(!calls.contains("old"));
+        assert!(calls.contains("new"));
+    }
+
+    #[test]
+    fn oldest_roll_call_makes_room() {
+        let mut calls = RollCalls::new();
+        for i in 0..MAX_ROLL_CALLS as u64 {
+            assert!(calls.started(call(&i.to_string(), NOW + 1000), PeerId::random(), NOW + i));
+        }
+        // Hearing about one again doesn't count
+        assert!(!calls.started(call("0", NOW + 1000), PeerId::random(), NOW + 100));
+        assert!(calls.contains("0"));
+
+        assert!(calls.started(call("new", NOW + 1000), PeerId::random(), NOW + 100));
+        assert!(!calls.contains("0"));
+        assert!(calls.contains("1") && calls.contains("new"));
+        assert_eq!(calls.latest(), Some("new"));
+    }
+
+    #[test]
+    fn roll_calls_can_only_run_for_a_day() {
+        assert!(call("call", NOW + MAX_DURATION_MS).is_valid(NOW));
+        assert!(!call("call", NOW + MAX_DURATION_MS + 1).is_valid(NOW));
+        assert!(!call("", NOW).is_valid(NOW));
+        assert!(!call(&"x".repeat(MAX_ID_LEN + 1), NOW).is_valid(NOW));
+    }
+}
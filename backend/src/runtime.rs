@@ -69,11 +69,4 @@ pub fn block_on_event<F, R: Default>(&self, f: F) -> R
     {
         self.block_on(async { self.with_event(f).await })
     }
-
-    pub fn block_on_gossip_and_event<F, R: Default>(&self, f: F) -> R
-    where
-        F: FnOnce(&mut Gossip, &mut Vec<GossipEvent>) -> R,
-    {
-        self.block_on(async { self.with_gossip_and_event(f).await })
-    }
 }
\ No newline at end of file
@@ -0,0 +1,868 @@
+use libp2p::PeerId;
+use rusqlite::{Connection, params};
+use serde::{Deserialize, Deserializer, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    fmt::Display,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use crate::board::SignedOp;
+use crate::cap::parse_datetime;
+use crate::communication::{InteractionMessage, Tag};
+use crate::gossip::peers::now_ms;
+use crate::wire::{self, EncodeOptions, Encoding, WireError};
+
+// Every message we send or receive ends up in here (storage_path/messages.db), so nothing is
+// lost if the app dies between two collect_events() calls and the host app doesn't need
+// its own copy. Only things a user would want to read back are kept: broadcasts, alerts,
+// voice messages and file manifests, not pings or beacons.
+//
+// The message itself is stored as a wire envelope (see wire.rs), so the columns are just
+// for finding it. Expired and retracted messages are deleted. The author's signature is
+// kept next to it, messages that have one can be handed out to peers catching up (see
+// gossip/history.rs). Retracts are kept too, only for handing out, and the ids they
+// retracted go in their own table so the message can't come back through history sync.
+//
+// Text is indexed with FTS5 for search(), rows in messages_fts share their rowid with the
+// message and go when it goes (trigger).
+//
+// Schema history (PRAGMA user_version), open() migrates old databases:
+// 1 - messages
+// 2 - signatures
+// 3 - full text index
+// 4 - board ops (see board.rs)
+// 5 - retracted ids
+
+// Expired messages don't need to go the moment they expire
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+pub const MAX_QUERY_LIMIT: usize = 1000;
+const DEFAULT_SEARCH_LIMIT: usize = 50;
+
+#[derive(Debug)]
+pub enum StoreError {
+    Sqlite(rusqlite::Error),
+    Wire(WireError),
+    Io(std::io::Error),
+}
+
+impl Display for StoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StoreError::Sqlite(e) => write!(f, "SQLite error: {}", e),
+            StoreError::Wire(e) => write!(f, "Could not encode/decode stored message: {}", e),
+            StoreError::Io(e) => write!(f, "IO error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<rusqlite::Error> for StoreError {
+    fn from(err: rusqlite::Error) -> Self {
+        StoreError::Sqlite(err)
+    }
+}
+
+impl From<WireError> for StoreError {
+    fn from(err: WireError) -> Self {
+        StoreError::Wire(err)
+    }
+}
+
+impl From<std::io::Error> for StoreError {
+    fn from(err: std::io::Error) -> Self {
+        StoreError::Io(err)
+    }
+}
+
+/// What get_messages() hands to the host app
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StoredMessage {
+    pub id: String,
+    pub room: String,
+    pub author: String,
+    pub tag: Tag,
+    // Sender's time if the message has one, otherwise when we got it
+    pub timestamp: u64,
+    // Sent by us
+    pub outgoing: bool,
+    pub superseded_by: Option<String>,
+    pub message: InteractionMessage,
+}
+
+/// All optional, times are unix ms and inclusive
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct SearchFilters {
+    pub room: Option<String>,
+    pub author: Option<String>,
+    // "critical", "high" or "normal", in any case
+    #[serde(deserialize_with = "tag_filter")]
+    pub tag: Option<Tag>,
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    pub limit: Option<usize>,
+}
+
+// Anything that isn't a tag fails the whole filter, rather than searching Normal messages
+fn tag_filter<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Tag>, D::Error> {
+    let Some(tag) = Option::<String>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    tag.parse().map(Some).map_err(serde::de::Error::custom)
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SearchResult {
+    #[serde(flatten)]
+    pub message: StoredMessage,
+    // bm25, lower is a better match. Results are sorted by it.
+    pub rank: f64,
+    // The matching bit of the text, matches are between [ and ]
+    pub snippet: String,
+}
+
+pub struct MessageStore {
+    conn: Connection,
+    options: EncodeOptions,
+    max_decompressed_size: usize,
+    last_prune: Instant,
+}
+
+fn tag_name(tag: Tag) -> &'static str {
+    match tag {
+        Tag::Critical => "critical",
+        Tag::High => "high",
+        Tag::Normal => "normal",
+    }
+}
+
+/// For messages without an id of their own, the same on every node so a message
+/// that comes in twice (e.g. through history sync) is only stored once
+fn content_id(message: &InteractionMessage) -> Option<String> {
+    let hash = Sha256::digest(wire::to_cbor(message).ok()?);
+    Some(hash[..8].iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// None for the kinds we don't keep
+pub fn stored_id(message: &InteractionMessage) -> Option<String> {
+    match message {
+        InteractionMessage::Message(_) | InteractionMessage::GeoAlert(_) => {
+            let id = &message.message()?.id;
+            // Older builds don't send ids
+            if id.is_empty() { content_id(message) } else { Some(id.clone()) }
+        }
+        InteractionMessage::CapAlert(alert) => Some(alert.identifier.clone()),
+        InteractionMessage::FileManifest(manifest) => Some(manifest.root_hash.clone()),
+        InteractionMessage::VoiceMessage(_) | InteractionMessage::Retract(_) => content_id(message),
+        _ => None,
+    }
+}
+
+/// What search() looks through, None for messages without text (voice)
+fn searchable_text(message: &InteractionMessage) -> Option<String> {
+    match message {
+        InteractionMessage::CapAlert(alert) => {
+            let mut text = Vec::new();
+            for info in &alert.info {
+                text.push(info.event.as_str());
+                for field in [&info.sender_name, &info.headline, &info.description, &info.instruction] {
+                    text.extend(field.as_deref());
+                }
+                text.extend(info.areas.iter().map(|area| area.area_desc.as_str()));
+            }
+            Some(text.join("\n"))
+        }
+        InteractionMessage::FileManifest(manifest) => Some(manifest.name.clone()),
+        _ => Some(message.message()?.message.clone()),
+    }
+}
+
+/// Every word has to match, as a prefix so "ins" finds "insulin". Quoted, so whatever
+/// the user typed can't break the FTS5 query syntax.
+fn match_query(query: &str) -> Option<String> {
+    let terms: Vec<String> = query
+        .split_whitespace()
+        .map(|term| format!("\"{}\"*", term.replace('"', "\"\"")))
+        .collect();
+    (!terms.is_empty()).then(|| terms.join(" "))
+}
+
+fn timestamp(message: &InteractionMessage) -> Option<u64> {
+    match message {
+        InteractionMessage::CapAlert(alert) => parse_datetime(&alert.sent),
+        InteractionMessage::VoiceMessage(voice) => Some(voice.timestamp),
+        _ => Some(message.message()?.timestamp),
+    }
+}
+
+fn expires_at(message: &InteractionMessage) -> Option<u64> {
+    match message {
+        // Gone once the last info block expired, and never if one of them doesn't
+        InteractionMessage::CapAlert(alert) => alert
+            .info
+            .iter()
+            .map(|info| info.expires.as_deref().and_then(parse_datetime))
+            .collect::<Option<Vec<u64>>>()?
+            .into_iter()
+            .max(),
+        _ => message.message()?.expires_at,
+    }
+}
+
+impl MessageStore {
+    pub fn open(path: &Path, compression_threshold: usize, max_decompressed_size: usize) -> Result<Self, StoreError> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let conn = Connection::open(path)?;
+        let version: i32 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if version < 1 {
+            conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS messages (
+                    id TEXT PRIMARY KEY NOT NULL,
+                    room TEXT NOT NULL,
+                    author TEXT NOT NULL,
+                    tag TEXT NOT NULL,
+                    timestamp INTEGER NOT NULL,
+                    received_at INTEGER NOT NULL,
+                    kind INTEGER NOT NULL,
+                    outgoing INTEGER NOT NULL,
+                    expires_at INTEGER,
+                    superseded_by TEXT,
+                    envelope BLOB NOT NULL
+                );
+                CREATE INDEX IF NOT EXISTS messages_room_time ON messages (room, timestamp);
+                CREATE INDEX IF NOT EXISTS messages_author ON messages (author);
+                CREATE INDEX IF NOT EXISTS messages_tag ON messages (tag);
+                CREATE INDEX IF NOT EXISTS messages_time ON messages (timestamp);
+                CREATE INDEX IF NOT EXISTS messages_expires_at ON messages (expires_at);
+                PRAGMA user_version = 1;",
+            )?;
+        }
+        if version < 2 {
+            conn.execute_batch(
+                "ALTER TABLE messages ADD COLUMN signature BLOB;
+                PRAGMA user_version = 2;",
+            )?;
+        }
+        let store = Self {
+            conn,
+            options: EncodeOptions { encoding: Encoding::Binary, compression_threshold: Some(compression_threshold) },
+            max_decompressed_size,
+            last_prune: Instant::now(),
+        };
+        if version < 3 {
+            store.conn.execute_batch(
+                "CREATE VIRTUAL TABLE IF NOT EXISTS messages_fts USING fts5 (
+                    text,
+                    tokenize = 'unicode61 remove_diacritics 2'
+                );
+                CREATE TRIGGER IF NOT EXISTS messages_fts_delete AFTER DELETE ON messages BEGIN
+                    DELETE FROM messages_fts WHERE rowid = old.rowid;
+                END;",
+            )?;
+            store.index_existing()?;
+            store.conn.execute_batch("PRAGMA user_version = 3;")?;
+        }
+        if version < 4 {
+            store.conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS board_ops (
+                    key TEXT PRIMARY KEY NOT NULL,
+                    op BLOB NOT NULL
+                );
+                PRAGMA user_version = 4;",
+            )?;
+        }
+        if version < 5 {
+            store.conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS retracted (
+                    id TEXT PRIMARY KEY NOT NULL,
+                    retracted_at INTEGER NOT NULL
+                );
+                PRAGMA user_version = 5;",
+            )?;
+        }
+        Ok(store)
+    }
+
+    /// Puts messages from before the full text index in it
+    fn index_existing(&self) -> Result<(), StoreError> {
+        let mut statement = self.conn.prepare("SELECT rowid, envelope FROM messages")?;
+        let rows = statement
+            .query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, Vec<u8>>(1)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        for (rowid, envelope) in rows {
+            let message = wire::decode(&envelope, self.max_decompressed_size)?;
+            if let Some(text) = searchable_text(&message) {
+                self.conn.execute("INSERT INTO messages_fts (rowid, text) VALUES (?1, ?2)", params![rowid, text])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Does nothing for messages we don't keep, or already have.
+    /// The signature has to be checked already.
+    pub fn insert(
+        &mut self,
+        author: &PeerId,
+        room: &str,
+        message: &InteractionMessage,
+        outgoing: bool,
+        signature: Option<&[u8]>,
+    ) -> Result<(), StoreError> {
+        let Some(id) = stored_id(message) else {
+            return Ok(());
+        };
+        let received_at = now_ms();
+        let envelope = wire::encode(message, self.options)?;
+        let inserted = self.conn.execute(
+            "INSERT OR IGNORE INTO messages
+                (id, room, author, tag, timestamp, received_at, kind, outgoing, expires_at, envelope, signature)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            params![
+                id,
+                room,
+                author.to_string(),
+                tag_name(message.priority()),
+                timestamp(message).unwrap_or(received_at) as i64,
+                received_at as i64,
+                wire::kind_of(message),
+                outgoing,
+                expires_at(message).map(|e| e as i64),
+                envelope,
+                signature,
+            ],
+        )?;
+        if inserted > 0
+            && let Some(text) = searchable_text(message)
+        {
+            let rowid = self.conn.last_insert_rowid();
+            self.conn.execute("INSERT INTO messages_fts (rowid, text) VALUES (?1, ?2)", params![rowid, text])?;
+        }
+        Ok(())
+    }
+
+    pub fn superseded(&mut self, msg_id: &str, by: &str) -> Result<(), StoreError> {
+        self.conn
+            .execute("UPDATE messages SET superseded_by = ?2 WHERE id = ?1", params![msg_id, by])?;
+        Ok(())
+    }
+
+    pub fn delete(&mut self, msg_id: &str) -> Result<(), StoreError> {
+        self.conn.execute("DELETE FROM messages WHERE id = ?1", params![msg_id])?;
+        Ok(())
+    }
+
+    /// Deletes the message and remembers it's gone for good, it might not even be here yet
+    pub fn retract(&mut self, msg_id: &str) -> Result<(), StoreError> {
+        self.delete(msg_id)?;
+        self.conn.execute(
+            "INSERT OR IGNORE INTO retracted (id, retracted_at) VALUES (?1, ?2)",
+            params![msg_id, now_ms() as i64],
+        )?;
+        Ok(())
+    }
+
+    pub fn is_retracted(&self, msg_id: &str) -> Result<bool, StoreError> {
+        Ok(self.conn.query_row("SELECT EXISTS (SELECT 1 FROM retracted WHERE id = ?1)", params![msg_id], |row| row.get(0))?)
+    }
+
+    /// Deletes expired messages, at most once a minute. Returns how many went.
+    pub fn prune(&mut self, now_ms: u64) -> Result<usize, StoreError> {
+        if self.last_prune.elapsed() < PRUNE_INTERVAL {
+            return Ok(0);
+        }
+        self.last_prune = Instant::now();
+        Ok(self
+            .conn
+            .execute("DELETE FROM messages WHERE expires_at IS NOT NULL AND expires_at <= ?1", params![now_ms as i64])?)
+    }
+
+    pub fn contains(&self, msg_id: &str) -> Result<bool, StoreError> {
+        Ok(self.conn.query_row("SELECT EXISTS (SELECT 1 FROM messages WHERE id = ?1)", params![msg_id], |row| row.get(0))?)
+    }
+
+    /// When we last got something in the room, by our own clock. The sender's timestamp
+    /// could be anything.
+    pub fn last_received(&self, room: &str) -> Result<Option<u64>, StoreError> {
+        let last: Option<i64> =
+            self.conn.query_row("SELECT MAX(received_at) FROM messages WHERE room = ?1", params![room], |row| row.get(0))?;
+        Ok(last.map(|last| last as u64))
+    }
+
+    /// The `limit` broadcasts (Message and GeoAlert) we got last that haven't expired, oldest first.
+    /// For filling the message cache on startup.
+    pub fn recent_broadcasts(&self, limit: usize, now_ms: u64) -> Result<Vec<StoredMessage>, StoreError> {
+        let mut statement = self.conn.prepare(
+            "SELECT id, room, author, tag, timestamp, outgoing, superseded_by, envelope FROM messages
+             WHERE kind IN (?1, ?2) AND (expires_at IS NULL OR expires_at > ?3)
+             ORDER BY rowid DESC LIMIT ?4",
+        )?;
+        // Message and GeoAlert, see wire::kind_of
+        let rows = statement
+            .query_map(params![6, 10, now_ms as i64, limit as i64], Self::row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        rows.into_iter().rev().map(|row| self.decode(row)).collect()
+    }
+
+    /// Signed messages we got after `since` (our clock), in the order we got them, with their
+    /// signature and when we got them. For history sync.
+    pub fn signed_messages(
+        &self,
+        room: &str,
+        since: u64,
+        limit: usize,
+    ) -> Result<Vec<(StoredMessage, Vec<u8>, u64)>, StoreError> {
+        let mut statement = self.conn.prepare(
+            "SELECT id, room, author, tag, timestamp, outgoing, superseded_by, envelope, signature, received_at
+             FROM messages
+             WHERE room = ?1 AND received_at > ?2 AND signature IS NOT NULL
+             ORDER BY received_at ASC, rowid ASC LIMIT ?3",
+        )?;
+        let rows = statement
+            .query_map(params![room, since as i64, limit.min(MAX_QUERY_LIMIT) as i64], |row| {
+                Ok((Self::row(row)?, row.get::<_, Vec<u8>>(8)?, row.get::<_, i64>(9)? as u64))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        rows.into_iter()
+            .map(|(row, signature, received_at)| Ok((self.decode(row)?, signature, received_at)))
+            .collect()
+    }
+
+    /// Keeps a board op that changed the board, replacing the one with the same key
+    pub fn save_board_op(&mut self, op: &SignedOp) -> Result<(), StoreError> {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(op, &mut bytes).map_err(|e| WireError::Cbor(e.to_string()))?;
+        self.conn.execute(
+            "INSERT OR REPLACE INTO board_ops (key, op) VALUES (?1, ?2)",
+            params![op.key(), bytes],
+        )?;
+        Ok(())
+    }
+
+    pub fn board_ops(&self) -> Result<Vec<SignedOp>, StoreError> {
+        let mut statement = self.conn.prepare("SELECT op FROM board_ops")?;
+        let rows = statement.query_map([], |row| row.get::<_, Vec<u8>>(0))?.collect::<Result<Vec<_>, _>>()?;
+        rows.iter()
+            .map(|bytes| ciborium::from_reader(bytes.as_slice()).map_err(|e| WireError::Cbor(e.to_string()).into()))
+            .collect()
+    }
+
+    /// Full text search, best matches first
+    pub fn search(&self, query: &str, filters: &SearchFilters) -> Result<Vec<SearchResult>, StoreError> {
+        let Some(query) = match_query(query) else {
+            return Ok(Vec::new());
+        };
+        let mut statement = self.conn.prepare(
+            "SELECT m.id, m.room, m.author, m.tag, m.timestamp, m.outgoing, m.superseded_by, m.envelope,
+                    bm25(messages_fts), snippet(messages_fts, 0, '[', ']', '…', 16)
+             FROM messages_fts JOIN messages m ON m.rowid = messages_fts.rowid
+             WHERE messages_fts MATCH ?1
+                AND (?2 IS NULL OR m.room = ?2)
+                AND (?3 IS NULL OR m.author = ?3)
+                AND (?4 IS NULL OR m.tag = ?4)
+                AND (?5 IS NULL OR m.timestamp >= ?5)
+                AND (?6 IS NULL OR m.timestamp <= ?6)
+             ORDER BY bm25(messages_fts) LIMIT ?7",
+        )?;
+        let tag = filters.tag.map(tag_name);
+        let limit = filters.limit.unwrap_or(DEFAULT_SEARCH_LIMIT).min(MAX_QUERY_LIMIT);
+        let rows = statement
+            .query_map(
+                params![
+                    query,
+                    filters.room,
+                    filters.author,
+                    tag,
+                    filters.from.map(|from| from as i64),
+                    filters.to.map(|to| to as i64),
+                    limit as i64,
+                ],
+                |row| Ok((Self::row(row)?, row.get::<_, f64>(8)?, row.get::<_, String>(9)?)),
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+        rows.into_iter()
+            .map(|(row, rank, snippet)| Ok(SearchResult { message: self.decode(row)?, rank, snippet }))
+            .collect()
+    }
+
+    /// Messages newer than `since` (unix ms), oldest first. No room means all rooms.
+    /// Retracts aren't included, the host app got a Retracted event for those.
+    pub fn messages(&self, room: Option<&str>, since: u64, limit: usize) -> Result<Vec<StoredMessage>, StoreError> {
+        let mut statement = self.conn.prepare(
+            "SELECT id, room, author, tag, timestamp, outgoing, superseded_by, envelope FROM messages
+             WHERE (?1 IS NULL OR room = ?1) AND timestamp > ?2 AND kind != ?4
+             ORDER BY timestamp ASC LIMIT ?3",
+        )?;
+        // Retract, see wire::kind_of
+        let rows = statement
+            .query_map(params![room, since as i64, limit.min(MAX_QUERY_LIMIT) as i64, 12], Self::row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        rows.into_iter().map(|row| self.decode(row)).collect()
+    }
+
+    fn row(row: &rusqlite::Row) -> rusqlite::Result<(StoredMessage, Vec<u8>)> {
+        let tag: String = row.get(3)?;
+        let stored = StoredMessage {
+            id: row.get(0)?,
+            room: row.get(1)?,
+            author: row.get(2)?,
+            tag: tag.into(),
+            timestamp: row.get::<_, i64>(4)? as u64,
+            outgoing: row.get(5)?,
+            superseded_by: row.get(6)?,
+            // Filled in by decode()
+            message: InteractionMessage::Other,
+        };
+        Ok((stored, row.get(7)?))
+    }
+
+    fn decode(&self, (mut stored, envelope): (StoredMessage, Vec<u8>)) -> Result<StoredMessage, StoreError> {
+        stored.message = wire::decode(&envelope, self.max_decompressed_size)?;
+        Ok(stored)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::communication::Message;
+
+    /// A database file of its own, gone again after the test
+    struct TempDb(std::path::PathBuf);
+
+    impl TempDb {
+        fn new() -> Self {
+            Self(std::env::temp_dir().join(format!("truman-store-{:016x}.db", rand::random::<u64>())))
+        }
+        fn open(&self) -> MessageStore {
+            MessageStore::open(&self.0, 1024, 1024 * 1024).unwrap()
+        }
+    }
+
+    impl Drop for TempDb {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn message(id: &str, text: &str, timestamp: u64) -> InteractionMessage {
+        InteractionMessage::Message(Message {
+            message: text.to_string(),
+            tags: Tag::Normal,
+            timestamp,
+            location: None,
+            id: id.to_string(),
+            expires_at: None,
+            supersedes: None,
+            reply_to: None,
+        })
+    }
+
+    fn expiring(id: &str, expires_at: u64) -> InteractionMessage {
+        let mut message = message(id, "Bridge closed", 1_000);
+        if let InteractionMessage::Message(inner) = &mut message {
+            inner.expires_at = Some(expires_at);
+        }
+        message
+    }
+
+    fn ids(messages: &[StoredMessage]) -> Vec<&str> {
+        messages.iter().map(|stored| stored.id.as_str()).collect()
+    }
+
+    fn user_version(store: &MessageStore) -> i32 {
+        store.conn.query_row("PRAGMA user_version", [], |row| row.get(0)).unwrap()
+    }
+
+    fn fts_rows(store: &MessageStore) -> i64 {
+        store.conn.query_row("SELECT COUNT(*) FROM messages_fts", [], |row| row.get(0)).unwrap()
+    }
+
+    #[test]
+    fn version_1_databases_are_migrated() {
+        let db = TempDb::new();
+        let old = message("0123456789abcdef", "Shelter open at the school", 1_000);
+        {
+            // What the first build left behind
+            let conn = Connection::open(&db.0).unwrap();
+            conn.execute_batch(
+                "CREATE TABLE messages (
+                    id TEXT PRIMARY KEY NOT NULL,
+                    room TEXT NOT NULL,
+                    author TEXT NOT NULL,
+                    tag TEXT NOT NULL,
+                    timestamp INTEGER NOT NULL,
+                    received_at INTEGER NOT NULL,
+                    kind INTEGER NOT NULL,
+                    outgoing INTEGER NOT NULL,
+                    expires_at INTEGER,
+                    superseded_by TEXT,
+                    envelope BLOB NOT NULL
+                );
+                PRAGMA user_version = 1;",
+            )
+            .unwrap();
+            let options = EncodeOptions { encoding: Encoding::Binary, compression_threshold: None };
+            let envelope = wire::encode(&old, options).unwrap();
+            conn.execute(
+                "INSERT INTO messages (id, room, author, tag, timestamp, received_at, kind, outgoing, envelope)
+                 VALUES ('0123456789abcdef', 'public', ?1, 'normal', 1000, 1000, 6, 0, ?2)",
+                params![PeerId::random().to_string(), envelope],
+            )
+            .unwrap();
+        }
+
+        let mut store = db.open();
+        assert_eq!(user_version(&store), 5);
+        // The old message made it into the search index
+        let results = store.search("shelter", &SearchFilters::default()).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].message.id, "0123456789abcdef");
+        // And the newer columns and tables are there
+        store.insert(&PeerId::random(), "public", &message("fedcba9876543210", "Hi", 2_000), false, Some(b"sig")).unwrap();
+        assert_eq!(store.signed_messages("public", 0, 10).unwrap().len(), 1);
+        assert!(store.board_ops().unwrap().is_empty());
+        assert!(!store.is_retracted("0123456789abcdef").unwrap());
+
+        // Opening it again doesn't migrate anything twice
+        drop(store);
+        let store = db.open();
+        assert_eq!(user_version(&store), 5);
+        assert_eq!(fts_rows(&store), 2);
+    }
+
+    #[test]
+    fn messages_are_stored_once() {
+        let db = TempDb::new();
+        let mut store = db.open();
+        let author = PeerId::random();
+        let message = message("0123456789abcdef", "Water at the school", 1_000);
+        store.insert(&author, "public", &message, false, None).unwrap();
+        // Again, e.g. through history sync
+        store.insert(&author, "public", &message, false, None).unwrap();
+        assert_eq!(ids(&store.messages(None, 0, 10).unwrap()), ["0123456789abcdef"]);
+        assert_eq!(fts_rows(&store), 1);
+
+        // Kinds nobody reads back aren't kept at all
+        store.insert(&author, "public", &InteractionMessage::Name, false, None).unwrap();
+        assert_eq!(store.messages(None, 0, 10).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn prune_deletes_expired_messages() {
+        let db = TempDb::new();
+        let mut store = db.open();
+        let author = PeerId::random();
+        store.insert(&author, "public", &expiring("expired", 5_000), false, None).unwrap();
+        store.insert(&author, "public", &expiring("expiring", 50_000), false, None).unwrap();
+        store.insert(&author, "public", &message("lasting", "Shelter open", 1_000), false, None).unwrap();
+
+        // Not more than once a minute
+        assert_eq!(store.prune(10_000).unwrap(), 0);
+        store.last_prune -= PRUNE_INTERVAL;
+        assert_eq!(store.prune(10_000).unwrap(), 1);
+        assert_eq!(store.prune(100_000).unwrap(), 0);
+
+        assert!(!store.contains("expired").unwrap());
+        assert!(store.contains("expiring").unwrap() && store.contains("lasting").unwrap());
+    }
+
+    #[test]
+    fn delete_takes_the_message_out_of_search() {
+        let db = TempDb::new();
+        let mut store = db.open();
+        store.insert(&PeerId::random(), "public", &message("gone", "Bridge closed", 1_000), false, None).unwrap();
+        assert_eq!(store.search("bridge", &SearchFilters::default()).unwrap().len(), 1);
+
+        store.delete("gone").unwrap();
+        assert!(!store.contains("gone").unwrap());
+        assert!(store.search("bridge", &SearchFilters::default()).unwrap().is_empty());
+        assert_eq!(fts_rows(&store), 0);
+    }
+
+    #[test]
+    fn messages_filters() {
+        let db = TempDb::new();
+        let mut store = db.open();
+        let author = PeerId::random();
+        // Not inserted in order, gossip doesn't keep it either
+        for (id, room, timestamp) in [("c", "public", 3_000), ("a", "public", 1_000), ("b", "dm", 2_000), ("d", "public", 4_000)] {
+            store.insert(&author, room, &message(id, "Hi", timestamp), false, None).unwrap();
+        }
+
+        assert_eq!(ids(&store.messages(None, 0, 10).unwrap()), ["a", "b", "c", "d"]);
+        assert_eq!(ids(&store.messages(Some("public"), 0, 10).unwrap()), ["a", "c", "d"]);
+        assert_eq!(ids(&store.messages(Some("dm"), 0, 10).unwrap()), ["b"]);
+        assert!(store.messages(Some("nowhere"), 0, 10).unwrap().is_empty());
+        // Newer than, not from
+        assert_eq!(ids(&store.messages(None, 2_000, 10).unwrap()), ["c", "d"]);
+        assert_eq!(ids(&store.messages(Some("public"), 0, 2).unwrap()), ["a", "c"]);
+    }
+
+    #[test]
+    fn recent_broadcasts_are_the_newest_that_still_count() {
+        let db = TempDb::new();
+        let mut store = db.open();
+        let author = PeerId::random();
+        for (id, timestamp) in [("a", 1_000), ("b", 2_000), ("c", 3_000)] {
+            store.insert(&author, "public", &message(id, "Hi", timestamp), false, None).unwrap();
+        }
+        store.insert(&author, "public", &expiring("expired", 5_000), false, None).unwrap();
+        store.superseded("a", "c").unwrap();
+
+        let recent = store.recent_broadcasts(10, 10_000).unwrap();
+        assert_eq!(ids(&recent), ["a", "b", "c"]);
+        assert_eq!(recent[0].superseded_by.as_deref(), Some("c"));
+        assert_eq!(recent[0].author, author.to_string());
+        assert_eq!(ids(&store.recent_broadcasts(2, 10_000).unwrap()), ["b", "c"]);
+    }
+
+    #[test]
+    fn history_goes_by_when_we_got_it() {
+        let db = TempDb::new();
+        let mut store = db.open();
+        let author = PeerId::random();
+        let before = now_ms();
+        // Someone's clock is a year ahead, another one's is off by a year the other way
+        let year = 365 * 24 * 60 * 60 * 1000;
+        store.insert(&author, "public", &message("ahead", "Hi", before + year), false, Some(b"sig")).unwrap();
+        store.insert(&author, "public", &message("behind", "Hi", before - year), false, Some(b"sig")).unwrap();
+        store.insert(&author, "public", &message("unsigned", "Hi", before), false, None).unwrap();
+
+        let last = store.last_received("public").unwrap().unwrap();
+        assert!(last >= before && last <= now_ms());
+        assert_eq!(store.last_received("dm").unwrap(), None);
+
+        let signed = store.signed_messages("public", before - 1, 10).unwrap();
+        let signed_ids: Vec<&str> = signed.iter().map(|(stored, _, _)| stored.id.as_str()).collect();
+        assert_eq!(signed_ids, ["ahead", "behind"]);
+        assert!(signed.iter().all(|(_, signature, received_at)| signature == b"sig" && *received_at >= before));
+        assert!(store.signed_messages("public", last, 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn retracted_ids_are_remembered() {
+        let db = TempDb::new();
+        let mut store = db.open();
+        let author = PeerId::random();
+        store.insert(&author, "public", &message("wrong", "Shelter at the mall", 1_000), false, None).unwrap();
+        store.retract("wrong").unwrap();
+        // Retracted before we even got it
+        store.retract("later").unwrap();
+        assert!(!store.contains("wrong").unwrap());
+        assert!(store.is_retracted("wrong").unwrap() && store.is_retracted("later").unwrap());
+        assert!(!store.is_retracted("other").unwrap());
+
+        // The Retract itself is kept for history sync, but isn't something to read back
+        let retract = InteractionMessage::Retract(crate::communication::Retract {
+            msg_id: "wrong".to_string(),
+            reason: "Mall is flooded".to_string(),
+        });
+        let id = stored_id(&retract).unwrap();
+        store.insert(&author, "public", &retract, false, Some(b"sig")).unwrap();
+        assert!(store.contains(&id).unwrap());
+        assert!(store.messages(None, 0, 10).unwrap().is_empty());
+        assert_eq!(store.signed_messages("public", 0, 10).unwrap().len(), 1);
+
+        drop(store);
+        assert!(db.open().is_retracted("later").unwrap());
+    }
+
+    #[test]
+    fn match_query_quotes_every_word() {
+        assert_eq!(match_query("water  school").as_deref(), Some("\"water\"* \"school\"*"));
+        // Quotes are doubled, so they can't end the term early
+        assert_eq!(match_query("say \"hi").as_deref(), Some("\"say\"* \"\"\"hi\"*"));
+        // FTS5 operators are just words
+        assert_eq!(match_query("NOT OR").as_deref(), Some("\"NOT\"* \"OR\"*"));
+        assert_eq!(match_query("   "), None);
+    }
+
+    /// Search results by id
+    fn found(store: &MessageStore, query: &str, filters: &SearchFilters) -> Vec<String> {
+        store.search(query, filters).unwrap().into_iter().map(|result| result.message.id).collect()
+    }
+
+    fn tagged(id: &str, text: &str, tags: Tag, timestamp: u64) -> InteractionMessage {
+        let mut message = message(id, text, timestamp);
+        if let InteractionMessage::Message(inner) = &mut message {
+            inner.tags = tags;
+        }
+        message
+    }
+
+    #[test]
+    fn search_matches_prefixes_and_odd_input() {
+        let db = TempDb::new();
+        let mut store = db.open();
+        let author = PeerId::random();
+        for (id, text) in [("insulin", "Need insulin at the Café"), ("quote", "He said \"run\" NOT walk")] {
+            store.insert(&author, "public", &message(id, text, 1_000), false, None).unwrap();
+        }
+
+        assert_eq!(found(&store, "ins", &SearchFilters::default()), ["insulin"]);
+        // Every word has to match, accents and case don't matter
+        assert_eq!(found(&store, "INSULIN cafe", &SearchFilters::default()), ["insulin"]);
+        assert!(found(&store, "insulin walk", &SearchFilters::default()).is_empty());
+        // Nothing the user types breaks the query
+        assert_eq!(found(&store, "\"run\" NOT", &SearchFilters::default()), ["quote"]);
+        assert!(store.search("\" * ( ) : ^", &SearchFilters::default()).is_ok());
+        assert!(found(&store, "", &SearchFilters::default()).is_empty());
+    }
+
+    #[test]
+    fn search_filters() {
+        let db = TempDb::new();
+        let mut store = db.open();
+        let (wolf, sheep) = (PeerId::random(), PeerId::random());
+        store.insert(&wolf, "public", &tagged("a", "Water at the school", Tag::Critical, 1_000), false, None).unwrap();
+        store.insert(&sheep, "public", &tagged("b", "Water at the station", Tag::Normal, 2_000), false, None).unwrap();
+        store.insert(&sheep, "dm", &tagged("c", "Water at home", Tag::High, 3_000), false, None).unwrap();
+
+        let search = |filters: SearchFilters| {
+            let mut ids = found(&store, "water", &filters);
+            ids.sort();
+            ids
+        };
+        assert_eq!(search(SearchFilters::default()), ["a", "b", "c"]);
+        assert_eq!(search(SearchFilters { room: Some("dm".to_string()), ..Default::default() }), ["c"]);
+        assert_eq!(search(SearchFilters { author: Some(sheep.to_string()), ..Default::default() }), ["b", "c"]);
+        assert_eq!(search(SearchFilters { tag: Some(Tag::Critical), ..Default::default() }), ["a"]);
+        // Both ends are included
+        assert_eq!(search(SearchFilters { from: Some(2_000), ..Default::default() }), ["b", "c"]);
+        assert_eq!(search(SearchFilters { to: Some(2_000), ..Default::default() }), ["a", "b"]);
+        assert_eq!(search(SearchFilters { limit: Some(1), ..Default::default() }).len(), 1);
+    }
+
+    #[test]
+    fn tag_filter_is_strict_but_not_about_case() {
+        let filters: SearchFilters = serde_json::from_str(r#"{"tag": "Critical"}"#).unwrap();
+        assert_eq!(filters.tag, Some(Tag::Critical));
+        let filters: SearchFilters = serde_json::from_str(r#"{"tag": "HIGH", "limit": 5}"#).unwrap();
+        assert_eq!(filters.tag, Some(Tag::High));
+        let filters: SearchFilters = serde_json::from_str(r#"{"tag": null}"#).unwrap();
+        assert_eq!(filters.tag, None);
+        assert!(serde_json::from_str::<SearchFilters>(r#"{"tag": "urgent"}"#).is_err());
+        assert!(serde_json::from_str::<SearchFilters>(r#"{"tag": ""}"#).is_err());
+    }
+
+    #[test]
+    fn better_matches_come_first() {
+        let db = TempDb::new();
+        let mut store = db.open();
+        let author = PeerId::random();
+        let once = message("once", "Water is running low at the north end of town", 1_000);
+        store.insert(&author, "public", &once, false, None).unwrap();
+        store.insert(&author, "public", &message("often", "Water water water", 2_000), false, None).unwrap();
+
+        let results = store.search("water", &SearchFilters::default()).unwrap();
+        let ids: Vec<&str> = results.iter().map(|result| result.message.id.as_str()).collect();
+        assert_eq!(ids, ["often", "once"]);
+        assert!(results[0].rank < results[1].rank);
+        assert!(results[0].snippet.contains("[Water]"));
+    }
+}
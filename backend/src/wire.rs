@@ -0,0 +1,473 @@
+use std::{
+    fmt::Display,
+    io::{Read, Write},
+};
+
+use flate2::{Compression, read::DeflateDecoder, write::DeflateEncoder};
+
+use crate::communication::InteractionMessage;
+
+// Everything that goes over gossip is wrapped in this envelope:
+//
+//   magic (1) | version (1) | kind (1) | flags (1) | extensions length (2, BE) | extensions | body
+//
+// - kind says what InteractionMessage variant the body holds, so it can be logged/filtered
+//   without decoding the body
+// - extensions are TLVs (tag (1) | length (2, BE) | value), unknown tags are skipped so newer
+//   builds can add fields without breaking older ones
+// - body is the CBOR encoded InteractionMessage, deflated if the COMPRESSED flag is set
+//   (the codec is in the EXT_CODEC extension)
+//
+// Builds from before the envelope (schema 1) send the message as plain JSON, which is still
+// accepted. With the `json-wire` feature we send plain JSON too, handy when debugging with
+// a packet dump.
+
+pub const MAGIC: u8 = 0xA7;
+pub const WIRE_VERSION: u8 = 1;
+const HEADER_SIZE: usize = 6;
+
+pub mod flags {
+    pub const COMPRESSED: u8 = 0b0000_0001;
+    // Reserved, nothing encrypts bodies yet
+    pub const ENCRYPTED: u8 = 0b0000_0010;
+}
+
+// Extension tags, append only
+pub const EXT_CODEC: u8 = 1;
+// The author's signature, so the message can be passed on later (see gossip/history.rs)
+pub const EXT_SIGNATURE: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Deflate = 1,
+}
+
+impl TryFrom<u8> for Codec {
+    type Error = WireError;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(Codec::Deflate),
+            other => Err(WireError::UnsupportedCodec(other)),
+        }
+    }
+}
+
+/// How to encode outgoing messages
+#[derive(Debug, Clone, Copy)]
+pub struct EncodeOptions {
+    pub encoding: Encoding,
+    // None if the receivers can't decompress
+    pub compression_threshold: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Binary,
+    Json,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Extension {
+    pub tag: u8,
+    pub value: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Envelope {
+    pub version: u8,
+    pub kind: u8,
+    pub flags: u8,
+    pub extensions: Vec<Extension>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub enum WireError {
+    Empty,
+    Truncated,
+    UnsupportedVersion(u8),
+    UnsupportedFlags(u8),
+    UnsupportedCodec(u8),
+    TooLarge(usize),
+    Compression(std::io::Error),
+    Cbor(String),
+    Json(serde_json::Error),
+}
+
+impl Display for WireError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WireError::Empty => write!(f, "Empty message"),
+            WireError::Truncated => write!(f, "Message is truncated"),
+            WireError::UnsupportedVersion(v) => write!(f, "Unsupported wire version {}", v),
+            WireError::UnsupportedFlags(flags) => write!(f, "Unsupported flags {:#010b}", flags),
+            WireError::UnsupportedCodec(codec) => write!(f, "Unsupported compression codec {}", codec),
+            WireError::TooLarge(max) => write!(f, "Message decompresses to more than {} bytes", max),
+            WireError::Compression(e) => write!(f, "Compression error: {}", e),
+            WireError::Cbor(e) => write!(f, "Could not decode CBOR body: {}", e),
+            WireError::Json(e) => write!(f, "Could not decode JSON message: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for WireError {}
+
+impl From<serde_json::Error> for WireError {
+    fn from(err: serde_json::Error) -> Self {
+        WireError::Json(err)
+    }
+}
+
+/// The envelope's message type byte, append only
+pub fn kind_of(message: &InteractionMessage) -> u8 {
+    match message {
+        InteractionMessage::Ping(_) => 0,
+        InteractionMessage::PingReply(_) => 1,
+        InteractionMessage::Name => 2,
+        InteractionMessage::NameReply(_) => 3,
+        InteractionMessage::NewWolf(_) => 4,
+        InteractionMessage::WolfVerify(_) => 5,
+        InteractionMessage::Message(_) => 6,
+        InteractionMessage::FileManifest(_) => 7,
+        InteractionMessage::VoiceMessage(_) => 8,
+        InteractionMessage::LocationShare(_) => 9,
+        InteractionMessage::GeoAlert(_) => 10,
+        InteractionMessage::CapAlert(_) => 11,
+        InteractionMessage::Retract(_) => 12,
+        InteractionMessage::BoardOps(_) => 13,
+        InteractionMessage::RollCall(_) => 14,
+        InteractionMessage::CheckIn(_) => 15,
+        InteractionMessage::Other => 255,
+    }
+}
+
+impl Envelope {
+    pub fn new(kind: u8, body: Vec<u8>) -> Self {
+        Self {
+            version: WIRE_VERSION,
+            kind,
+            flags: 0,
+            extensions: Vec::new(),
+            body,
+        }
+    }
+
+    pub fn extension(&self, tag: u8) -> Option<&[u8]> {
+        self.extensions.iter().find(|e| e.tag == tag).map(|e| e.value.as_slice())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let extensions_size: usize = self.extensions.iter().map(|e| 3 + e.value.len()).sum();
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + extensions_size + self.body.len());
+        bytes.extend_from_slice(&[MAGIC, self.version, self.kind, self.flags]);
+        bytes.extend_from_slice(&(extensions_size as u16).to_be_bytes());
+        for extension in &self.extensions {
+            bytes.push(extension.tag);
+            bytes.extend_from_slice(&(extension.value.len() as u16).to_be_bytes());
+            bytes.extend_from_slice(&extension.value);
+        }
+        bytes.extend_from_slice(&self.body);
+        bytes
+    }
+
+    /// Expects data starting with MAGIC, see is_envelope()
+    pub fn from_bytes(data: &[u8]) -> Result<Self, WireError> {
+        if data.len() < HEADER_SIZE {
+            return Err(WireError::Truncated);
+        }
+        let version = data[1];
+        if version != WIRE_VERSION {
+            return Err(WireError::UnsupportedVersion(version));
+        }
+        let extensions_size = u16::from_be_bytes([data[4], data[5]]) as usize;
+        let body_start = HEADER_SIZE + extensions_size;
+        if data.len() < body_start {
+            return Err(WireError::Truncated);
+        }
+
+        let mut extensions = Vec::new();
+        let mut rest = &data[HEADER_SIZE..body_start];
+        while !rest.is_empty() {
+            if rest.len() < 3 {
+                return Err(WireError::Truncated);
+            }
+            let size = u16::from_be_bytes([rest[1], rest[2]]) as usize;
+            let value = rest.get(3..3 + size).ok_or(WireError::Truncated)?;
+            extensions.push(Extension { tag: rest[0], value: value.to_vec() });
+            rest = &rest[3 + size..];
+        }
+
+        Ok(Self {
+            version,
+            kind: data[2],
+            flags: data[3],
+            extensions,
+            body: data[body_start..].to_vec(),
+        })
+    }
+}
+
+pub fn is_envelope(data: &[u8]) -> bool {
+    data.first() == Some(&MAGIC)
+}
+
+pub fn encode(message: &InteractionMessage, options: EncodeOptions) -> Result<Vec<u8>, WireError> {
+    encode_signed(message, options, None)
+}
+
+/// Plain JSON has nowhere to put the signature, so it's left out then
+pub fn encode_signed(
+    message: &InteractionMessage,
+    options: EncodeOptions,
+    signature: Option<&[u8]>,
+) -> Result<Vec<u8>, WireError> {
+    if cfg!(feature = "json-wire") || options.encoding == Encoding::Json {
+        return Ok(serde_json::to_vec(message)?);
+    }
+    let mut envelope = Envelope::new(kind_of(message), to_cbor(message)?);
+    if let Some(signature) = signature {
+        envelope.extensions.push(Extension { tag: EXT_SIGNATURE, value: signature.to_vec() });
+    }
+
+    if let Some(threshold) = options.compression_threshold
+        && envelope.body.len() > threshold
+    {
+        let compressed = deflate(&envelope.body)?;
+        // Already compressed stuff (e.g. audio) only gets bigger
+        if compressed.len() < envelope.body.len() {
+            envelope.body = compressed;
+            envelope.flags |= flags::COMPRESSED;
+            envelope.extensions.push(Extension { tag: EXT_CODEC, value: vec![Codec::Deflate as u8] });
+        }
+    }
+    Ok(envelope.to_bytes())
+}
+
+/// The uncompressed envelope body
+pub fn to_cbor(message: &InteractionMessage) -> Result<Vec<u8>, WireError> {
+    let mut body = Vec::new();
+    ciborium::into_writer(message, &mut body).map_err(|e| WireError::Cbor(e.to_string()))?;
+    Ok(body)
+}
+
+/// The author's signature, if the message came in an envelope that has one
+pub fn signature(data: &[u8]) -> Option<Vec<u8>> {
+    if !is_envelope(data) {
+        return None;
+    }
+    let envelope = Envelope::from_bytes(data).ok()?;
+    envelope.extension(EXT_SIGNATURE).map(|signature| signature.to_vec())
+}
+
+pub fn decode(data: &[u8], max_decompressed_size: usize) -> Result<InteractionMessage, WireError> {
+    if data.is_empty() {
+        return Err(WireError::Empty);
+    }
+    if !is_envelope(data) {
+        // Plain JSON, from a schema 1 build or a json-wire one
+        return Ok(serde_json::from_slice(data)?);
+    }
+    let mut envelope = Envelope::from_bytes(data)?;
+    // Unlike extensions, flags change how the body has to be read, so unknown ones are fatal.
+    // Nothing can decrypt yet either.
+    let known_flags = flags::COMPRESSED | flags::ENCRYPTED;
+    if envelope.flags & !known_flags != 0 || envelope.flags & flags::ENCRYPTED != 0 {
+        return Err(WireError::UnsupportedFlags(envelope.flags));
+    }
+    if envelope.flags & flags::COMPRESSED != 0 {
+        // Deflate is the only codec so far, so it's the default if the extension is missing
+        let codec = match envelope.extension(EXT_CODEC) {
+            Some([codec]) => Codec::try_from(*codec)?,
+            Some(_) => return Err(WireError::Truncated),
+            None => Codec::Deflate,
+        };
+        envelope.body = match codec {
+            Codec::Deflate => inflate(&envelope.body, max_decompressed_size)?,
+        };
+    }
+    ciborium::from_reader(envelope.body.as_slice()).map_err(|e| WireError::Cbor(e.to_string()))
+}
+
+fn deflate(data: &[u8]) -> Result<Vec<u8>, WireError> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data).map_err(WireError::Compression)?;
+    encoder.finish().map_err(WireError::Compression)
+}
+
+fn inflate(data: &[u8], max_size: usize) -> Result<Vec<u8>, WireError> {
+    let mut inflated = Vec::new();
+    // Read one byte past the limit to tell "exactly max_size" and "too big" apart
+    DeflateDecoder::new(data)
+        .take(max_size as u64 + 1)
+        .read_to_end(&mut inflated)
+        .map_err(WireError::Compression)?;
+    if inflated.len() > max_size {
+        return Err(WireError::TooLarge(max_size));
+    }
+    Ok(inflated)
+}
+
+// The envelope tests make no sense when everything goes out as plain JSON
+#[cfg(all(test, not(feature = "json-wire")))]
+mod tests {
+    use super::*;
+    use crate::communication::{Message, Tag};
+
+    fn message(text: &str) -> InteractionMessage {
+        InteractionMessage::Message(Message {
+            message: text.to_string(),
+            tags: Tag::High,
+            timestamp: 1_716_594_540_000,
+            location: None,
+            id: "0123456789abcdef".to_string(),
+            expires_at: None,
+            supersedes: None,
+            reply_to: None,
+        })
+    }
+
+    fn text_of(message: &InteractionMessage) -> &str {
+        match message {
+            InteractionMessage::Message(message) => &message.message,
+            other => panic!("Expected a Message, got {:?}", other),
+        }
+    }
+
+    fn binary(compression_threshold: Option<usize>) -> EncodeOptions {
+        EncodeOptions { encoding: Encoding::Binary, compression_threshold }
+    }
+
+    // Compresses well, so encode() actually uses the compressed body
+    fn long_text() -> String {
+        "Shelter at the school gym is full, go to the town hall. ".repeat(40)
+    }
+
+    #[test]
+    fn round_trip_without_compression() {
+        let bytes = encode(&message("hello"), binary(None)).unwrap();
+        assert!(is_envelope(&bytes));
+        let envelope = Envelope::from_bytes(&bytes).unwrap();
+        assert_eq!(envelope.kind, kind_of(&message("hello")));
+        assert_eq!(envelope.flags, 0);
+        assert!(envelope.extensions.is_empty());
+        assert_eq!(text_of(&decode(&bytes, 1024).unwrap()), "hello");
+    }
+
+    #[test]
+    fn round_trip_with_compression() {
+        let text = long_text();
+        let bytes = encode(&message(&text), binary(Some(64))).unwrap();
+        let envelope = Envelope::from_bytes(&bytes).unwrap();
+        assert_eq!(envelope.flags, flags::COMPRESSED);
+        assert_eq!(envelope.extension(EXT_CODEC), Some(&[Codec::Deflate as u8][..]));
+        assert!(bytes.len() < text.len());
+        assert_eq!(text_of(&decode(&bytes, 64 * 1024).unwrap()), text);
+    }
+
+    #[test]
+    fn small_messages_are_not_compressed() {
+        let bytes = encode(&message("hello"), binary(Some(1024))).unwrap();
+        assert_eq!(Envelope::from_bytes(&bytes).unwrap().flags, 0);
+    }
+
+    #[test]
+    fn signature_survives_the_round_trip() {
+        let bytes = encode_signed(&message(&long_text()), binary(Some(64)), Some(&[7; 64])).unwrap();
+        assert_eq!(signature(&bytes), Some(vec![7; 64]));
+        assert_eq!(text_of(&decode(&bytes, 64 * 1024).unwrap()), long_text());
+    }
+
+    #[test]
+    fn decompressed_size_limit() {
+        let msg = message(&long_text());
+        let size = to_cbor(&msg).unwrap().len();
+        let bytes = encode(&msg, binary(Some(64))).unwrap();
+        // Exactly max_size is fine, one byte more isn't
+        assert!(decode(&bytes, size).is_ok());
+        assert!(matches!(decode(&bytes, size - 1), Err(WireError::TooLarge(max)) if max == size - 1));
+    }
+
+    #[test]
+    fn truncated_extensions_are_rejected() {
+        let body = to_cbor(&message("hello")).unwrap();
+        let header = |extensions_size: u16| {
+            let mut bytes = vec![MAGIC, WIRE_VERSION, 6, 0];
+            bytes.extend_from_slice(&extensions_size.to_be_bytes());
+            bytes
+        };
+
+        // Header cut short
+        assert!(matches!(decode(&[MAGIC, WIRE_VERSION, 6], 1024), Err(WireError::Truncated)));
+
+        // Extensions say they are longer than the whole message
+        let mut bytes = header(100);
+        bytes.extend_from_slice(&[EXT_SIGNATURE, 0, 1, 7]);
+        assert!(matches!(decode(&bytes, 1024), Err(WireError::Truncated)));
+
+        // Not even room for tag and length
+        let mut bytes = header(2);
+        bytes.extend_from_slice(&[EXT_SIGNATURE, 0]);
+        bytes.extend_from_slice(&body);
+        assert!(matches!(decode(&bytes, 1024), Err(WireError::Truncated)));
+
+        // Value is shorter than its length says
+        let mut bytes = header(5);
+        bytes.extend_from_slice(&[EXT_SIGNATURE, 0, 10, 7, 7]);
+        bytes.extend_from_slice(&body);
+        assert!(matches!(decode(&bytes, 1024), Err(WireError::Truncated)));
+
+        // A codec extension that isn't one byte
+        let mut envelope = Envelope::new(6, deflate(&body).unwrap());
+        envelope.flags = flags::COMPRESSED;
+        envelope.extensions.push(Extension { tag: EXT_CODEC, value: vec![1, 1] });
+        assert!(matches!(decode(&envelope.to_bytes(), 1024), Err(WireError::Truncated)));
+    }
+
+    #[test]
+    fn unknown_flags_are_rejected() {
+        let bytes = encode(&message("hello"), binary(None)).unwrap();
+        for flag in [flags::ENCRYPTED, 0b1000_0000, 0b0000_0100] {
+            let mut tampered = bytes.clone();
+            tampered[3] |= flag;
+            assert!(matches!(decode(&tampered, 1024), Err(WireError::UnsupportedFlags(f)) if f == flag));
+        }
+    }
+
+    #[test]
+    fn unknown_codecs_and_versions_are_rejected() {
+        let body = deflate(&to_cbor(&message("hello")).unwrap()).unwrap();
+        let mut envelope = Envelope::new(6, body);
+        envelope.flags = flags::COMPRESSED;
+        envelope.extensions.push(Extension { tag: EXT_CODEC, value: vec![9] });
+        assert!(matches!(decode(&envelope.to_bytes(), 1024), Err(WireError::UnsupportedCodec(9))));
+
+        let mut bytes = encode(&message("hello"), binary(None)).unwrap();
+        bytes[1] = WIRE_VERSION + 1;
+        assert!(matches!(decode(&bytes, 1024), Err(WireError::UnsupportedVersion(v)) if v == WIRE_VERSION + 1));
+    }
+
+    #[test]
+    fn unknown_extensions_are_skipped() {
+        let mut envelope = Envelope::new(6, to_cbor(&message("hello")).unwrap());
+        envelope.extensions.push(Extension { tag: 200, value: vec![1, 2, 3] });
+        envelope.extensions.push(Extension { tag: 201, value: Vec::new() });
+        let bytes = envelope.to_bytes();
+        assert_eq!(Envelope::from_bytes(&bytes).unwrap().extensions, envelope.extensions);
+        assert_eq!(text_of(&decode(&bytes, 1024).unwrap()), "hello");
+    }
+
+    #[test]
+    fn plain_json_is_still_accepted() {
+        // What schema 1 builds send
+        let legacy = serde_json::to_vec(&message("hello")).unwrap();
+        assert!(!is_envelope(&legacy));
+        assert_eq!(text_of(&decode(&legacy, 1024).unwrap()), "hello");
+        assert_eq!(signature(&legacy), None);
+
+        let json = encode(&message("hello"), EncodeOptions { encoding: Encoding::Json, compression_threshold: Some(0) }).unwrap();
+        assert_eq!(json, legacy);
+
+        assert!(matches!(decode(&[], 1024), Err(WireError::Empty)));
+        assert!(matches!(decode(b"{not json", 1024), Err(WireError::Json(_))));
+    }
+}